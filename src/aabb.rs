@@ -0,0 +1,137 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use vector::{Vector, Float};
+use matrix::Matrix;
+use ray::Ray;
+use packet::{RayPacket, Lanes};
+
+// Axis aligned bounding box. An empty box has min above max, so growing
+// it by anything gives that thing's bounds.
+#[derive(Debug,Copy,Clone)]
+pub struct Aabb {
+   pub min: Vector,
+   pub max: Vector,
+}
+
+impl Aabb {
+
+   // Constructors
+   pub fn new(min: Vector, max: Vector) -> Aabb {
+      Aabb { min, max }
+   }
+
+   pub fn empty() -> Aabb {
+      Aabb::new(Vector::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+                Vector::new(Float::NEG_INFINITY, Float::NEG_INFINITY, Float::NEG_INFINITY))
+   }
+
+   // Bounds of geometry with no finite extent, such as a plane.
+   pub fn infinite() -> Aabb {
+      Aabb::new(Vector::new(Float::NEG_INFINITY, Float::NEG_INFINITY, Float::NEG_INFINITY),
+                Vector::new(Float::INFINITY, Float::INFINITY, Float::INFINITY))
+   }
+
+   pub fn from_points(points: &[Vector]) -> Aabb {
+      points.iter().fold(Aabb::empty(), |b, &p| b.grow(p))
+   }
+
+   pub fn is_finite(&self) -> bool {
+      self.min.x.is_finite() && self.min.y.is_finite() && self.min.z.is_finite() &&
+      self.max.x.is_finite() && self.max.y.is_finite() && self.max.z.is_finite()
+   }
+
+   pub fn grow(&self, p: Vector) -> Aabb {
+      Aabb::new(Vector::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z)),
+                Vector::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z)))
+   }
+
+   pub fn union(&self, b: &Aabb) -> Aabb {
+      self.grow(b.min).grow(b.max)
+   }
+
+   pub fn intersection(&self, b: &Aabb) -> Aabb {
+      Aabb::new(Vector::new(self.min.x.max(b.min.x), self.min.y.max(b.min.y), self.min.z.max(b.min.z)),
+                Vector::new(self.max.x.min(b.max.x), self.max.y.min(b.max.y), self.max.z.min(b.max.z)))
+   }
+
+   pub fn centroid(&self) -> Vector {
+      (self.min + self.max) * 0.5
+   }
+
+   pub fn surface_area(&self) -> Float {
+      let d = self.max - self.min;
+      if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+         return 0.0;
+      }
+      2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+   }
+
+   // Bounds of the box after a transform, from its eight corners.
+   pub fn transform(&self, m: &Matrix) -> Aabb {
+      if !self.is_finite() {
+         return Aabb::infinite();
+      }
+      let mut b = Aabb::empty();
+      for i in 0 .. 8 {
+         let p = Vector::new(if i & 1 == 0 { self.min.x } else { self.max.x },
+                             if i & 2 == 0 { self.min.y } else { self.max.y },
+                             if i & 4 == 0 { self.min.z } else { self.max.z });
+         b = b.grow(m.transform_point(p));
+      }
+      b
+   }
+
+   // Slab test, returning the part of [tmin, tmax] inside the box.
+   pub fn hit(&self, r: &Ray, inv_dir: Vector, tmin: Float, tmax: Float) -> Option<(Float, Float)> {
+      let mut t0 = tmin;
+      let mut t1 = tmax;
+      let o = [r.origin.x, r.origin.y, r.origin.z];
+      let inv = [inv_dir.x, inv_dir.y, inv_dir.z];
+      let lo = [self.min.x, self.min.y, self.min.z];
+      let hi = [self.max.x, self.max.y, self.max.z];
+      for i in 0 .. 3 {
+         let (mut a, mut b) = ((lo[i] - o[i]) * inv[i], (hi[i] - o[i]) * inv[i]);
+         if a > b {
+            std::mem::swap(&mut a, &mut b);
+         }
+         // written so a NaN from 0 * inf leaves the interval unchanged
+         t0 = if a > t0 { a } else { t0 };
+         t1 = if b < t1 { b } else { t1 };
+         if t0 > t1 {
+            return None;
+         }
+      }
+      Some((t0, t1))
+   }
+
+   // The slab test for each ray of a packet, giving the distance it enters
+   // the box at, or infinity where it misses. min and max treat NaNs just
+   // as the single ray test does.
+   pub fn hit_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &Lanes) -> Lanes {
+      let mut t0 = *tmin;
+      let mut t1 = *tmax;
+      let axes = [(self.min.x, self.max.x, r.origin.x, r.inv_dir.x),
+                  (self.min.y, self.max.y, r.origin.y, r.inv_dir.y),
+                  (self.min.z, self.max.z, r.origin.z, r.inv_dir.z)];
+      for &(lo, hi, o, inv) in axes.iter() {
+         let (a, b) = ((Lanes::splat(lo) - o) * inv, (Lanes::splat(hi) - o) * inv);
+         let (a, b) = (b.min(a), a.max(b));
+         t0 = a.max(t0);
+         t1 = b.min(t1);
+      }
+      t0.gt(t1).select(Lanes::splat(Float::INFINITY), t0)
+   }
+}
@@ -0,0 +1,186 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Acceleration structures only know primitives by their index into the
+// list of bounds they were built from, so the caller tests them through a
+// closure and the same structure works for scene objects and mesh faces
+// alike. Primitives without finite bounds can't be placed in a structure
+// and are tested on every query.
+
+use vector::{Vector, Float};
+use aabb::Aabb;
+use ray::Ray;
+use packet::{RayPacket, Lanes, LANES};
+use bvh::Bvh;
+use grid::Grid;
+use kdtree::KdTree;
+use toml::Value;
+
+pub trait Accelerator {
+   // Bounds of everything in the structure, infinite if anything is unbounded.
+   fn bounds(&self) -> Aabb;
+
+   // Nearest hit query. test(i, tmax) intersects primitive i and returns
+   // the distance to any hit closer than tmax, which then shortens the ray.
+   fn closest(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize, Float) -> Option<Float>);
+
+   // Occlusion query, stopping at the first primitive for which test(i)
+   // reports a hit between tmin and tmax.
+   fn any(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize) -> bool) -> bool;
+
+   // Nearest hit query for a packet, each lane with its own tmax and those
+   // with a tmax below tmin inactive. test(i, tmax) intersects primitive i
+   // with the packet and shortens tmax in the lanes it hits. By default the
+   // rays are followed one at a time.
+   fn closest_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &mut Lanes, test: &mut dyn FnMut(usize, &mut Lanes)) {
+      for k in 0 .. LANES {
+         if tmax[k].is_nan() || tmax[k] < tmin[k] {
+            continue;
+         }
+         // only lane k is active while its ray is followed
+         let mut lane = Lanes::splat(Float::NEG_INFINITY);
+         lane[k] = tmax[k];
+         self.closest(&r.ray(k), tmin[k], tmax[k], &mut |i, t| {
+            lane[k] = t;
+            test(i, &mut lane);
+            if lane[k] < t { Some(lane[k]) } else { None }
+         });
+         tmax[k] = lane[k];
+      }
+   }
+}
+
+// Structure named in an [acceleration] table, used for the objects of the
+// scene and for the faces and curves inside each mesh and curve set.
+#[derive(Debug,Copy,Clone)]
+pub enum Structure {
+   Bvh,
+   // cells per primitive and number of levels
+   Grid(Float, usize),
+   KdTree,
+}
+
+// Reads an [acceleration] table, a BVH by default.
+pub fn import(obj: Option<&Value>) -> Result<Structure, String> {
+   let t = match obj.and_then(|o| o.lookup("type")) {
+      Some(v) => v.as_str().ok_or("Invalid string.")?,
+      None => "bvh",
+   };
+
+   match t {
+      "bvh" => Ok(Structure::Bvh),
+      "grid" => {
+         let mut density = 4.0;
+         let mut levels = 1;
+         if let Some(v) = obj.and_then(|o| o.lookup("density")) {
+            density = v.as_float().ok_or("Invalid float.")? as Float;
+         }
+         if let Some(v) = obj.and_then(|o| o.lookup("levels")) {
+            levels = (v.as_integer().ok_or("Invalid integer.")? as usize).max(1);
+         }
+         Ok(Structure::Grid(density, levels))
+      }
+      "kdtree" => Ok(Structure::KdTree),
+      _ => Err(format!("Unknown acceleration type {}.", t)),
+   }
+}
+
+pub fn build(structure: Structure, bounds: &[Aabb]) -> Box<dyn Accelerator> {
+   match structure {
+      Structure::Bvh => Box::new(Bvh::new(bounds)),
+      Structure::Grid(density, levels) => Box::new(Grid::new(bounds, density, levels)),
+      Structure::KdTree => Box::new(KdTree::new(bounds)),
+   }
+}
+
+pub fn component(v: Vector, axis: usize) -> Float {
+   match axis {
+      0 => v.x,
+      1 => v.y,
+      _ => v.z,
+   }
+}
+
+pub fn inverse(d: Vector) -> Vector {
+   Vector::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Structure, import, build, inverse};
+   use aabb::Aabb;
+   use ray::Ray;
+   use vector::{Vector, Float};
+   use toml::{Parser, Value};
+
+   fn parse(s: &str) -> Value {
+      Value::Table(Parser::new(s).parse().unwrap())
+   }
+
+   #[test]
+   fn imports_structures() {
+      match import(None) {
+         Ok(Structure::Bvh) => {},
+         s => panic!("{:?}", s),
+      }
+      match import(Some(&parse("type = \"grid\"\ndensity = 2.0\nlevels = 2"))) {
+         Ok(Structure::Grid(d, l)) => assert_eq!((d, l), (2.0, 2)),
+         s => panic!("{:?}", s),
+      }
+      assert!(import(Some(&parse("type = \"octree\""))).is_err());
+   }
+
+   // Every structure finds the same nearest box for each ray.
+   #[test]
+   fn structures_agree() {
+      let mut seed = 12345u32;
+      let mut next = || {
+         seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+         (seed >> 8) as Float / (1 << 24) as Float
+      };
+      let mut boxes = Vec::new();
+      for _ in 0 .. 200 {
+         let c = Vector::new(next() * 20.0 - 10.0, next() * 20.0 - 10.0, next() * 20.0 - 10.0);
+         let e = Vector::new(next(), next(), next()) * 0.5;
+         boxes.push(Aabb::new(c - e, c + e));
+      }
+      boxes.push(Aabb::infinite());
+
+      let structures = [Structure::Bvh, Structure::Grid(4.0, 1), Structure::Grid(2.0, 2), Structure::KdTree];
+      let accelerators: Vec<_> = structures.iter().map(|&s| build(s, &boxes)).collect();
+      for _ in 0 .. 200 {
+         let o = Vector::new(0.0, 0.0, -30.0);
+         // aimed at the middle of a box, so every ray hits one
+         let d = (boxes[(next() * 200.0) as usize].centroid() - o).normalize();
+         let r = Ray { origin: o, direction: d, time: 0.0 };
+         let nearest: Vec<Option<usize>> = accelerators.iter().map(|a| {
+            let mut found = None;
+            a.closest(&r, 0.0, Float::INFINITY, &mut |i, tmax| {
+               // the unbounded box stands for geometry far away
+               let t = if i == boxes.len() - 1 { 1000.0 } else {
+                  match boxes[i].hit(&r, inverse(d), 0.0, tmax) {
+                     Some((t0, _)) => t0,
+                     None => return None,
+                  }
+               };
+               if t <= tmax { found = Some(i); Some(t) } else { None }
+            });
+            found
+         }).collect();
+         assert!(nearest.iter().all(|&n| n == nearest[0]), "{:?}", nearest);
+         assert!(nearest[0] != Some(boxes.len() - 1));
+      }
+   }
+}
@@ -0,0 +1,271 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use vector::float::consts;
+use std::path::Path;
+use std::rc::Rc;
+use vector::{Vector, dot, Float};
+use color::Color;
+use ray::Ray;
+use environment::EnvironmentMap;
+use image::Image;
+use toml::Value;
+
+// Color seen by rays that miss every object in the scene.
+pub trait Background {
+   fn color(&self, r: &Ray) -> Color;
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct Solid {
+   color: Color,
+}
+
+// Vertical gradient from straight down to straight up.
+#[derive(Debug,Copy,Clone)]
+pub struct Gradient {
+   bottom: Color,
+   top: Color,
+}
+
+// Preetham, Shirley and Smits analytic daylight model.
+// "A Practical Analytic Model for Daylight", SIGGRAPH 1999.
+#[derive(Debug,Copy,Clone)]
+pub struct Sky {
+   sun: Vector,
+   theta_s: Float,
+   zenith: (Float, Float, Float), // Yxy at the zenith
+   perez: [[Float; 5]; 3],    // Perez coefficients for Y, x and y
+   intensity: Float,
+}
+
+pub struct Environment {
+   map: Rc<EnvironmentMap>,
+}
+
+impl Solid {
+   pub fn new(color: Color) -> Solid {
+      Solid { color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Solid {
+      Solid { color: Color::new(v[0], v[1], v[2]) }
+   }
+
+   pub fn import(obj: &Value) -> Result<Solid, String> {
+      let elements = vec!["color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      Ok(Solid::from_vec(values))
+   }
+}
+
+impl Gradient {
+   pub fn from_vec(v: Vec<Float>) -> Gradient {
+      Gradient { bottom: Color::new(v[0], v[1], v[2]), top: Color::new(v[3], v[4], v[5]) }
+   }
+
+   pub fn import(obj: &Value) -> Result<Gradient, String> {
+      let elements = vec!["bottom.0", "bottom.1", "bottom.2", "top.0", "top.1", "top.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      Ok(Gradient::from_vec(values))
+   }
+}
+
+impl Sky {
+   pub fn new(sun: Vector, turbidity: Float, intensity: Float) -> Sky {
+      let t = turbidity;
+      let sun = sun.normalize();
+      let theta_s = sun.y.clamp(-1.0, 1.0).acos().min(consts::FRAC_PI_2);
+
+      let chi = (4.0 / 9.0 - t / 120.0) * (consts::PI - 2.0 * theta_s);
+      let y_z = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+      let (s, s2, s3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+      let x_z = t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+         + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+         + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+      let yy_z = t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+         + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+         + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+      let perez = [
+         [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251,
+          0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+         [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125,
+          -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+         [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102,
+          -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+      ];
+
+      Sky {
+         sun,
+         theta_s,
+         zenith: (y_z, x_z, yy_z),
+         perez,
+         intensity,
+      }
+   }
+
+   pub fn import(obj: &Value) -> Result<Sky, String> {
+      let elements = vec!["sun.0", "sun.1", "sun.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let turbidity = match obj.lookup("turbidity") {
+         Some(v) => v.as_float().ok_or("Invalid float.")? as Float,
+         None => 3.0,
+      };
+      let intensity = match obj.lookup("intensity") {
+         Some(v) => v.as_float().ok_or("Invalid float.")? as Float,
+         None => 1.0,
+      };
+
+      if turbidity < 1.0 {
+         return Err("Turbidity must be at least 1.".to_string());
+      }
+
+      Ok(Sky::new(Vector::new(values[0], values[1], values[2]), turbidity, intensity))
+   }
+
+   fn perez(c: &[Float; 5], theta: Float, gamma: Float) -> Float {
+      (1.0 + c[0] * (c[1] / theta.cos()).exp())
+         * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+   }
+
+   fn channel(&self, i: usize, zenith: Float, theta: Float, gamma: Float) -> Float {
+      let c = &self.perez[i];
+      zenith * Sky::perez(c, theta, gamma) / Sky::perez(c, 0.0, self.theta_s)
+   }
+}
+
+impl Environment {
+   pub fn new(map: Rc<EnvironmentMap>) -> Environment {
+      Environment { map }
+   }
+
+   pub fn import(obj: &Value, dir: &Path) -> Result<Environment, String> {
+      let file = obj.lookup("file").and_then(|v| v.as_str()).ok_or("Missing file.")?;
+      let image = Image::load(&dir.join(file))?;
+      Ok(Environment::new(Rc::new(EnvironmentMap::new(image))))
+   }
+}
+
+impl Background for Solid {
+   fn color(&self, _r: &Ray) -> Color {
+      self.color
+   }
+}
+
+impl Background for Gradient {
+   fn color(&self, r: &Ray) -> Color {
+      let t = 0.5 * (r.direction.normalize().y + 1.0);
+      self.bottom * (1.0 - t) + self.top * t
+   }
+}
+
+impl Background for Sky {
+   fn color(&self, r: &Ray) -> Color {
+      let d = r.direction.normalize();
+      // the model is only defined above the horizon
+      let theta = d.y.clamp(0.001, 1.0).acos();
+      let gamma = dot(d, self.sun).clamp(-1.0, 1.0).acos();
+
+      let (y_z, x_z, yy_z) = self.zenith;
+      // zenith luminance is in kcd/m^2, scale to roughly unit brightness
+      let lum = self.channel(0, y_z, theta, gamma) * self.intensity / 20.0;
+      let x = self.channel(1, x_z, theta, gamma);
+      let y = self.channel(2, yy_z, theta, gamma);
+
+      let cx = x / y * lum;
+      let cz = (1.0 - x - y) / y * lum;
+
+      Color::new((3.2406 * cx - 1.5372 * lum - 0.4986 * cz).max(0.0),
+                 (-0.9689 * cx + 1.8758 * lum + 0.0415 * cz).max(0.0),
+                 (0.0557 * cx - 0.2040 * lum + 1.0570 * cz).max(0.0))
+   }
+}
+
+impl Background for Environment {
+   fn color(&self, r: &Ray) -> Color {
+      self.map.lookup(r.direction)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Background, Gradient, Sky};
+   use color::Color;
+   use ray::Ray;
+   use vector::{Vector, Float};
+
+   fn look(b: &dyn Background, x: Float, y: Float, z: Float) -> Color {
+      b.color(&Ray { origin: Vector::zero(), direction: Vector::new(x, y, z), time: 0.0 })
+   }
+
+   fn luminance(c: Color) -> Float {
+      0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+   }
+
+   #[test]
+   fn gradient_runs_from_bottom_to_top() {
+      let g = Gradient::from_vec(vec![1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+      assert_eq!(look(&g, 0.0, 3.0, 0.0).b, 1.0);
+      assert_eq!(look(&g, 0.0, -0.5, 0.0).r, 1.0);
+      let c = look(&g, 2.0, 0.0, 0.0);
+      assert!((c.r - 0.5).abs() < 1e-6 && (c.b - 0.5).abs() < 1e-6);
+   }
+
+   #[test]
+   fn daylight() {
+      // sun 45 degrees up to the east
+      let sky = Sky::new(Vector::new(1.0, 1.0, 0.0), 3.0, 1.0);
+      let zenith = look(&sky, 0.0, 1.0, 0.0);
+      // straight up the model gives its zenith luminance
+      assert!((luminance(zenith) - sky.zenith.0 / 20.0).abs() < 0.01 * sky.zenith.0 / 20.0);
+
+      // the zenith is a deeper blue than the horizon
+      let away = look(&sky, -1.0, 0.05, 0.0);
+      assert!(zenith.b / zenith.r > 2.0 * away.b / away.r);
+      // which is brightest towards the sun
+      let towards = look(&sky, 1.0, 0.05, 0.0);
+      assert!(luminance(towards) > 1.5 * luminance(away));
+      assert!(luminance(look(&sky, 1.0, 1.0, 0.0)) > luminance(towards));
+
+      // below the horizon it's held at the horizon, here both 135 degrees
+      // from the sun
+      let below = look(&sky, 0.0, -1.0, 0.0);
+      let horizon = look(&sky, -1.0, 0.0, 0.0);
+      assert!((luminance(below) - luminance(horizon)).abs() < 1e-6 && luminance(below) > 0.0);
+
+      let bright = Sky::new(Vector::new(1.0, 1.0, 0.0), 3.0, 2.0);
+      assert!((luminance(look(&bright, 0.0, 1.0, 0.0)) - 2.0 * luminance(zenith)).abs() < 1e-5);
+   }
+}
@@ -0,0 +1,291 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Bicubic Bezier patches, tessellated into a triangle mesh with normals
+// and uvs taken from the surface itself. Patch files are read in either
+// of two common layouts:
+//
+// * indexed, as used for the Utah teapot: the patch count, a line of 16
+//   one based control point indices per patch, the point count and then
+//   the points, with values separated by commas or spaces.
+// * bpt: the patch count, then for each patch a line with its degrees,
+//   which must be "3 3", followed by its 16 control points.
+
+use std::path::Path;
+use std::rc::Rc;
+use vector::{Vector, cross, Float};
+use color::Color;
+use mesh::{TriangleMesh, Mesh, read_file, import_color};
+use accelerator::Structure;
+use toml::Value;
+
+// Control points in rows of four, u running along a row and v down them.
+#[derive(Debug,Copy,Clone)]
+pub struct Patch {
+   pub points: [Vector; 16],
+}
+
+impl Patch {
+   // Position and partial derivatives at u, v.
+   pub fn eval(&self, u: Float, v: Float) -> (Vector, Vector, Vector) {
+      let (bu, du) = (bernstein(u), bernstein_derivative(u));
+      let (bv, dv) = (bernstein(v), bernstein_derivative(v));
+
+      let mut p = Vector::zero();
+      let mut dpdu = Vector::zero();
+      let mut dpdv = Vector::zero();
+      for row in 0 .. 4 {
+         for col in 0 .. 4 {
+            let c = self.points[row * 4 + col];
+            p = p + c * (bu[col] * bv[row]);
+            dpdu = dpdu + c * (du[col] * bv[row]);
+            dpdv = dpdv + c * (bu[col] * dv[row]);
+         }
+      }
+      (p, dpdu, dpdv)
+   }
+
+   // The teapot and files following it order their control points so the
+   // outside is on the left of u, looking along v.
+   pub fn normal(&self, u: Float, v: Float) -> Vector {
+      let (_, dpdu, dpdv) = self.eval(u, v);
+      let n = cross(dpdv, dpdu);
+      if n.magnitude_sq() > 1e-12 {
+         return n.normalize();
+      }
+      // collapsed edges, like the top of the teapot lid, have a vanishing
+      // derivative, so look just inside the patch instead
+      let (_, dpdu, dpdv) = self.eval(u + (0.5 - u) * 1e-3, v + (0.5 - v) * 1e-3);
+      cross(dpdv, dpdu).normalize()
+   }
+}
+
+fn bernstein(t: Float) -> [Float; 4] {
+   let s = 1.0 - t;
+   [s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t]
+}
+
+fn bernstein_derivative(t: Float) -> [Float; 4] {
+   let s = 1.0 - t;
+   [-3.0 * s * s, 3.0 * s * s - 6.0 * t * s, 6.0 * t * s - 3.0 * t * t, 3.0 * t * t]
+}
+
+pub fn load(path: &Path) -> Result<Vec<Patch>, String> {
+   let data = read_file(path)?;
+   let lines: Vec<Vec<&str>> = data.lines()
+      .map(|l| l.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()).collect())
+      .filter(|t: &Vec<&str>| !t.is_empty())
+      .collect();
+
+   let number = |t: &str| t.parse::<Float>().map_err(|_| format!("Invalid number {}.", t));
+   let count = lines.first().and_then(|l| l[0].parse::<usize>().ok()).ok_or("Missing patch count.")?;
+
+   let mut patches = Vec::new();
+   if lines.get(1).is_some_and(|l| l.len() == 2) {
+      for p in 0 .. count {
+         let start = 1 + p * 17;
+         let degree = lines.get(start).ok_or("Truncated file.")?;
+         if degree[0] != "3" || degree[1] != "3" {
+            return Err("Only bicubic patches are supported.".to_string());
+         }
+         let mut points = [Vector::zero(); 16];
+         for (i, point) in points.iter_mut().enumerate() {
+            let l = lines.get(start + 1 + i).ok_or("Truncated file.")?;
+            if l.len() < 3 {
+               return Err("Too few values.".to_string());
+            }
+            *point = Vector::new(number(l[0])?, number(l[1])?, number(l[2])?);
+         }
+         patches.push(Patch { points });
+      }
+   } else {
+      let nv = lines.get(1 + count).and_then(|l| l[0].parse::<usize>().ok()).ok_or("Missing vertex count.")?;
+      let mut vertices = Vec::new();
+      for i in 0 .. nv {
+         let l = lines.get(2 + count + i).ok_or("Truncated file.")?;
+         if l.len() < 3 {
+            return Err("Too few values.".to_string());
+         }
+         vertices.push(Vector::new(number(l[0])?, number(l[1])?, number(l[2])?));
+      }
+
+      for p in 0 .. count {
+         let l = &lines[1 + p];
+         if l.len() < 16 {
+            return Err("Patch with fewer than 16 indices.".to_string());
+         }
+         let mut points = [Vector::zero(); 16];
+         for i in 0 .. 16 {
+            let index = l[i].parse::<usize>().map_err(|_| format!("Invalid index {}.", l[i]))?;
+            points[i] = *vertices.get(index.wrapping_sub(1)).ok_or(format!("Index {} out of range.", index))?;
+         }
+         patches.push(Patch { points });
+      }
+   }
+
+   Ok(patches)
+}
+
+// Each patch becomes a grid of divisions x divisions quads.
+pub fn tessellate(patches: &[Patch], divisions: usize, color: Color) -> TriangleMesh {
+   let mut mesh = TriangleMesh::new(color);
+   let n = divisions.max(1);
+
+   for patch in patches.iter() {
+      let base = mesh.positions.len() as u32;
+      for j in 0 .. n + 1 {
+         for i in 0 .. n + 1 {
+            let (u, v) = (i as Float / n as Float, j as Float / n as Float);
+            let (p, _, _) = patch.eval(u, v);
+            mesh.positions.push(p);
+            mesh.normals.push(patch.normal(u, v));
+            mesh.uvs.push((u, v));
+         }
+      }
+
+      let row = (n + 1) as u32;
+      for j in 0 .. n as u32 {
+         for i in 0 .. n as u32 {
+            let a = base + j * row + i;
+            let (b, c, d) = (a + 1, a + row, a + row + 1);
+            mesh.push_face([a, d, b], None, None, 0);
+            mesh.push_face([a, c, d], None, None, 0);
+         }
+      }
+   }
+
+   mesh
+}
+
+// Scene geometry from a patch file, optionally giving the number of
+// divisions along each side of a patch.
+pub fn import(obj: &Value, dir: &Path, structure: Structure) -> Result<Mesh, String> {
+   let file = obj.lookup("file").and_then(|v| v.as_str()).ok_or("Missing file.")?;
+   let color = import_color(obj)?;
+   let divisions = match obj.lookup("divisions") {
+      Some(v) => v.as_integer().ok_or("Invalid integer.")? as usize,
+      None => 8,
+   };
+
+   let patches = load(&dir.join(file))?;
+   Ok(Mesh::new(Rc::new(tessellate(&patches, divisions, color)), structure))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Patch, load, tessellate};
+   use color::Color;
+   use vector::{Vector, Float, cross, dot};
+   use testfile::write;
+
+   fn load_str(name: &str, data: &str) -> Result<Vec<Patch>, String> {
+      load(&write(name, data.as_bytes()))
+   }
+
+   fn near(a: Vector, b: Vector) -> bool {
+      (a - b).magnitude() < 1e-4
+   }
+
+   // Control points evenly spaced on the unit square, lifted by z.
+   fn patch(z: &dyn Fn(usize, usize) -> Float) -> Patch {
+      let mut points = [Vector::zero(); 16];
+      for row in 0 .. 4 {
+         for col in 0 .. 4 {
+            points[row * 4 + col] = Vector::new(col as Float / 3.0, row as Float / 3.0, z(row, col));
+         }
+      }
+      Patch { points }
+   }
+
+   #[test]
+   fn evenly_spaced_points_give_the_plane() {
+      let p = patch(&|_, _| 0.0);
+      let (q, dpdu, dpdv) = p.eval(0.25, 0.75);
+      assert!(near(q, Vector::new(0.25, 0.75, 0.0)));
+      assert!(near(dpdu, Vector::new(1.0, 0.0, 0.0)));
+      assert!(near(dpdv, Vector::new(0.0, 1.0, 0.0)));
+      assert!(near(p.normal(0.5, 0.5), Vector::new(0.0, 0.0, -1.0)));
+   }
+
+   #[test]
+   fn corners_and_derivatives() {
+      let p = patch(&|row, col| ((row * 7 + col * 3) % 5) as Float * 0.2);
+      assert!(near(p.eval(0.0, 0.0).0, p.points[0]));
+      assert!(near(p.eval(1.0, 0.0).0, p.points[3]));
+      assert!(near(p.eval(0.0, 1.0).0, p.points[12]));
+      assert!(near(p.eval(1.0, 1.0).0, p.points[15]));
+
+      // the derivatives match central differences
+      let (u, v, h) = (0.3, 0.6, 1e-3);
+      let (_, dpdu, dpdv) = p.eval(u, v);
+      assert!(near(dpdu, (p.eval(u + h, v).0 - p.eval(u - h, v).0) * (0.5 / h)));
+      assert!(near(dpdv, (p.eval(u, v + h).0 - p.eval(u, v - h).0) * (0.5 / h)));
+      let n = p.normal(u, v);
+      assert!((n.magnitude() - 1.0).abs() < 1e-6 && dot(n, dpdu).abs() < 1e-6 && dot(n, dpdv).abs() < 1e-6);
+   }
+
+   #[test]
+   fn collapsed_edge_has_a_normal() {
+      let mut p = patch(&|_, _| 0.0);
+      for col in 0 .. 4 {
+         p.points[col] = Vector::new(0.5, 0.0, 0.0);
+      }
+      let n = p.normal(0.5, 0.0);
+      assert!(n.z < -0.99);
+   }
+
+   #[test]
+   fn loads_both_layouts() {
+      let mut points = String::new();
+      for row in 0 .. 4 {
+         for col in 0 .. 4 {
+            points.push_str(&format!("{}, {}, {}\n", col, row, row * col));
+         }
+      }
+      let indices = (1 .. 17).map(|i| i.to_string()).collect::<Vec<String>>().join(",");
+      let indexed = load_str("indexed.bpt", &format!("1\n{}\n16\n{}", indices, points)).unwrap();
+      let bpt = load_str("bpt.bpt", &format!("1\n3 3\n{}", points.replace(",", " "))).unwrap();
+      assert_eq!(indexed.len(), 1);
+      assert_eq!(bpt.len(), 1);
+      for i in 0 .. 16 {
+         let (row, col) = ((i / 4) as Float, (i % 4) as Float);
+         assert!(near(indexed[0].points[i], Vector::new(col, row, row * col)));
+         assert!(near(bpt[0].points[i], indexed[0].points[i]));
+      }
+
+      let out_of_range = indices.replace("16", "17");
+      assert!(load_str("range.bpt", &format!("1\n{}\n16\n{}", out_of_range, points)).is_err());
+      assert!(load_str("degree.bpt", &format!("1\n2 3\n{}", points)).is_err());
+      assert!(load_str("truncated.bpt", "2\n3 3\n0 0 0\n").is_err());
+   }
+
+   #[test]
+   fn tessellates_grids_facing_the_normals() {
+      let p = patch(&|row, col| if row == 1 && col == 2 { 1.0 } else { 0.0 });
+      let mesh = tessellate(&[p, p], 3, Color::new(1.0, 1.0, 1.0));
+      assert_eq!(mesh.positions.len(), 2 * 16);
+      assert_eq!(mesh.len(), 2 * 18);
+      assert_eq!(mesh.uvs[5], (1.0 / 3.0, 1.0 / 3.0));
+      assert!(near(mesh.positions[15], p.points[15]));
+      assert!(near(mesh.positions[16], p.points[0]));
+
+      // windings agree with the vertex normals
+      for face in 0 .. mesh.len() {
+         let (v0, v1, v2) = mesh.vertices(face);
+         let n = cross(v1 - v0, v2 - v0);
+         assert!(dot(n, mesh.normal(face, 1.0 / 3.0, 1.0 / 3.0)) > 0.0);
+      }
+   }
+}
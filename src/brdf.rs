@@ -13,30 +13,41 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use vector::Vector;
+use vector::{Vector, dot, Float};
 use color::Color;
 use geometry::ShadeRec;
 use toml::Value;
-use std::f32::consts;
+use vector::float::consts;
 
-pub trait BRDF {
+pub trait Brdf {
     fn f(&self, sr: &ShadeRec, wi: &Vector, wo: &Vector) -> Color;
-    fn rho(&self, sr: &ShadeRec, wo: &Vector) -> Color;
 }
 
 #[derive(Debug,Copy,Clone)]
 pub struct Lambertian {
-    kd: f32,
+    kd: Float,
     cd: Color,
 }
 
+// Kajiya-Kay model for hair and other thin fibres, lit along the fibre's
+// tangent, taken as dpdu. The diffuse part takes its color from the
+// surface, the specular highlight lies on the cone of mirror directions
+// around the tangent.
+#[derive(Debug,Copy,Clone)]
+pub struct Hair {
+    kd: Float,
+    ks: Float,
+    exponent: Float,
+    cs: Color,
+}
+
 impl Lambertian {
-    pub fn new(kd: f32, cd: Color) -> Lambertian {
-        Lambertian { kd: kd, cd: cd }
+    pub fn new(kd: Float, cd: Color) -> Lambertian {
+        Lambertian { kd, cd }
     }
 
-    pub fn from_vec(v: Vec<f32>) -> Lambertian {
-        Lambertian { kd: v[0], cd: Color::new(v[1], v[2], v[3]) }
+    pub fn from_vec(v: Vec<Float>) -> Lambertian {
+        Lambertian::new(v[0], Color::new(v[1], v[2], v[3]))
     }
 
     pub fn import(obj: &Value) -> Result<Lambertian, String> {
@@ -44,20 +55,139 @@ impl Lambertian {
         let mut values = Vec::new();
 
         for e in elements {
-            let value = try!(obj.lookup(e).ok_or("Missing element."));
-            values.push(try!(value.as_float().ok_or("Invalid float.")) as f32);
+            let value = obj.lookup(e).ok_or("Missing element.")?;
+            values.push(value.as_float().ok_or("Invalid float.")? as Float);
         }
 
         Ok(Lambertian::from_vec(values))
     }
 }
 
-impl BRDF for Lambertian {
-    fn f(&self, sr: &ShadeRec, wi: &Vector, wo: &Vector) -> Color {
+impl Brdf for Lambertian {
+    fn f(&self, _sr: &ShadeRec, _wi: &Vector, _wo: &Vector) -> Color {
         self.cd * self.kd * consts::FRAC_1_PI
     }
+}
+
+impl Hair {
+    pub fn new(kd: Float, ks: Float, exponent: Float, cs: Color) -> Hair {
+        Hair { kd, ks, exponent, cs }
+    }
+
+    pub fn from_vec(v: Vec<Float>) -> Hair {
+        Hair::new(v[0], v[1], v[2], Color::new(v[3], v[4], v[5]))
+    }
+
+    pub fn import(obj: &Value) -> Result<Hair, String> {
+        let elements = vec!["kd", "ks", "exponent", "cs.0", "cs.1", "cs.2"];
+        let mut values = Vec::new();
+
+        for e in elements {
+            let value = obj.lookup(e).ok_or("Missing element.")?;
+            values.push(value.as_float().ok_or("Invalid float.")? as Float);
+        }
+
+        Ok(Hair::from_vec(values))
+    }
+}
+
+impl Brdf for Hair {
+    fn f(&self, sr: &ShadeRec, wi: &Vector, wo: &Vector) -> Color {
+        let diffuse = sr.color * self.kd * consts::FRAC_1_PI;
+        if sr.dpdu.magnitude_sq() == 0.0 {
+            return diffuse;
+        }
+        let t = sr.dpdu.normalize();
+
+        // angle between the viewer and the cone the light is reflected into
+        let (cos_i, cos_o) = (dot(t, *wi), dot(t, *wo));
+        let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+        let cos_cone = sin_i * sin_o - cos_i * cos_o;
+        if cos_cone <= 0.0 {
+            return diffuse;
+        }
+        diffuse + self.cs * (self.ks * cos_cone.powf(self.exponent))
+    }
+}
+
+// Reads a BRDF table, its type one of lambertian or hair.
+pub fn import(obj: &Value) -> Result<Box<dyn Brdf>, String> {
+    match obj.lookup("type").and_then(|v| v.as_str()).ok_or("Missing type.")? {
+        "lambertian" => Ok(Box::new(Lambertian::import(obj)?)),
+        "hair" => Ok(Box::new(Hair::import(obj)?)),
+        t => Err(format!("Unknown brdf {}.", t)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Brdf, Hair, import};
+    use vector::{Vector, Float};
+    use vector::float::consts;
+    use color::Color;
+    use ray::Ray;
+    use geometry::ShadeRec;
+    use toml::{Parser, Value};
+
+    // A hit on a fibre running along x.
+    fn fibre(tangent: Vector) -> ShadeRec {
+        let r = Ray { origin: Vector::new(0.0, 0.0, 1.0), direction: Vector::new(0.0, 0.0, -1.0), time: 0.0 };
+        let mut sr = ShadeRec::new(r, 1.0, Vector::new(0.0, 0.0, 1.0), Color::new(0.5, 0.4, 0.3));
+        sr.set_surface((0.0, 0.0), tangent, Vector::new(0.0, 1.0, 0.0));
+        sr
+    }
+
+    fn hair() -> Hair {
+        Hair::new(0.8, 0.5, 20.0, Color::new(1.0, 1.0, 1.0))
+    }
+
+    fn near(a: Color, b: Color) -> bool {
+        (a.r - b.r).abs() < 1e-5 && (a.g - b.g).abs() < 1e-5 && (a.b - b.b).abs() < 1e-5
+    }
+
+    #[test]
+    fn diffuse_takes_the_surface_color() {
+        let sr = fibre(Vector::new(2.0, 0.0, 0.0));
+        let diffuse = Color::new(0.5, 0.4, 0.3) * (0.8 * consts::FRAC_1_PI);
+        // lit and seen from the same side, away from the highlight cone
+        let w = Vector::new(0.8, 0.0, 0.6);
+        assert!(near(hair().f(&sr, &w, &w), diffuse));
+        // without a tangent there is no highlight at all
+        let flat = fibre(Vector::zero());
+        let wo = Vector::new(-0.8, 0.0, 0.6);
+        assert!(near(hair().f(&flat, &w, &wo), diffuse));
+    }
+
+    // The highlight peaks on the cone of mirror directions around the
+    // fibre, whichever way round the fibre is lit and seen.
+    #[test]
+    fn highlight_lies_on_the_mirror_cone() {
+        let sr = fibre(Vector::new(1.0, 0.0, 0.0));
+        let diffuse = Color::new(0.5, 0.4, 0.3) * (0.8 * consts::FRAC_1_PI);
+        let (c, s) = ((0.3 as Float).cos(), (0.3 as Float).sin());
+        let wi = Vector::new(c, 0.0, s);
+        // anywhere around the fibre at the mirrored angle
+        for &phi in [0.0, 1.0, 2.5].iter() {
+            let phi = phi as Float;
+            let wo = Vector::new(-c, s * phi.sin(), s * phi.cos());
+            assert!(near(hair().f(&sr, &wi, &wo), diffuse + Color::new(0.5, 0.5, 0.5)));
+        }
+        let off = Vector::new(-(0.4 as Float).cos(), 0.0, (0.4 as Float).sin());
+        let f = hair().f(&sr, &wi, &off);
+        let expected = diffuse + Color::new(1.0, 1.0, 1.0) * (0.5 * (0.1 as Float).cos().powf(20.0));
+        assert!(near(f, expected), "{:?} != {:?}", f, expected);
+        assert!(near(hair().f(&sr, &off, &wi), f));
+    }
 
-    fn rho(&self, sr: &ShadeRec, wo: &Vector) -> Color {
-        self.cd * self.kd
+    #[test]
+    fn imports_by_type() {
+        let parse = |s: &str| Value::Table(Parser::new(s).parse().unwrap());
+        let sr = fibre(Vector::new(1.0, 0.0, 0.0));
+        let w = Vector::new(0.8, 0.0, 0.6);
+        let b = import(&parse("type = \"hair\"\nkd = 0.8\nks = 0.5\nexponent = 20.0\ncs = [1.0, 1.0, 1.0]")).unwrap();
+        assert!(near(b.f(&sr, &w, &w), Color::new(0.4, 0.32, 0.24) * consts::FRAC_1_PI));
+        assert!(import(&parse("type = \"hair\"\nkd = 0.8")).is_err());
+        assert!(import(&parse("type = \"velvet\"")).is_err());
     }
 }
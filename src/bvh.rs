@@ -0,0 +1,293 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Bounding volume hierarchy built with the surface area heuristic over
+// binned centroids.
+
+use vector::{Vector, Float};
+use aabb::Aabb;
+use ray::Ray;
+use packet::{RayPacket, Lanes};
+use accelerator::{Accelerator, component, inverse};
+
+const BINS: usize = 16;
+const MAX_LEAF: usize = 8;
+// cost of visiting a node relative to testing one primitive
+const TRAVERSAL_COST: Float = 1.0;
+
+struct Node {
+   bounds: Aabb,
+   // a leaf holds count primitives from start in the index list, an
+   // interior node has count 0 and its children at start and start + 1
+   start: usize,
+   count: usize,
+}
+
+pub struct Bvh {
+   nodes: Vec<Node>,
+   indices: Vec<usize>,
+   unbounded: Vec<usize>,
+}
+
+impl Bvh {
+   pub fn new(bounds: &[Aabb]) -> Bvh {
+      let mut bvh = Bvh { nodes: Vec::new(), indices: Vec::new(), unbounded: Vec::new() };
+      for (i, b) in bounds.iter().enumerate() {
+         if b.is_finite() {
+            bvh.indices.push(i);
+         } else {
+            bvh.unbounded.push(i);
+         }
+      }
+
+      if !bvh.indices.is_empty() {
+         let centroids: Vec<Vector> = bounds.iter().map(|b| b.centroid()).collect();
+         let count = bvh.indices.len();
+         bvh.nodes.push(Node { bounds: Aabb::empty(), start: 0, count });
+         bvh.build(bounds, &centroids, 0);
+      }
+      bvh
+   }
+
+   fn build(&mut self, bounds: &[Aabb], centroids: &[Vector], node: usize) {
+      let (start, count) = (self.nodes[node].start, self.nodes[node].count);
+      let items = start .. start + count;
+
+      let mut nb = Aabb::empty();
+      let mut cb = Aabb::empty();
+      for &i in self.indices[items.clone()].iter() {
+         nb = nb.union(&bounds[i]);
+         cb = cb.grow(centroids[i]);
+      }
+      self.nodes[node].bounds = nb;
+
+      if count <= 2 {
+         return;
+      }
+
+      // find the cheapest split between bins along each axis
+      let mut best: Option<(usize, usize, Float)> = None;
+      for axis in 0 .. 3 {
+         let (lo, hi) = (component(cb.min, axis), component(cb.max, axis));
+         if hi <= lo {
+            continue;
+         }
+         let scale = BINS as Float / (hi - lo);
+
+         let mut bin_bounds = [Aabb::empty(); BINS];
+         let mut bin_counts = [0; BINS];
+         for &i in self.indices[items.clone()].iter() {
+            let b = bin(component(centroids[i], axis), lo, scale);
+            bin_bounds[b] = bin_bounds[b].union(&bounds[i]);
+            bin_counts[b] += 1;
+         }
+
+         // areas and counts of everything right of each split, swept from the end
+         let mut right_area = [0.0; BINS];
+         let mut right_count = [0; BINS];
+         let (mut rb, mut rc) = (Aabb::empty(), 0);
+         for b in (1 .. BINS).rev() {
+            rb = rb.union(&bin_bounds[b]);
+            rc += bin_counts[b];
+            right_area[b] = rb.surface_area();
+            right_count[b] = rc;
+         }
+
+         let (mut lb, mut lc) = (Aabb::empty(), 0);
+         for split in 1 .. BINS {
+            lb = lb.union(&bin_bounds[split - 1]);
+            lc += bin_counts[split - 1];
+            if lc == 0 || right_count[split] == 0 {
+               continue;
+            }
+            let cost = lb.surface_area() * lc as Float + right_area[split] * right_count[split] as Float;
+            if best.is_none_or(|b| cost < b.2) {
+               best = Some((axis, split, cost));
+            }
+         }
+      }
+
+      let area = nb.surface_area();
+      let mid = match best {
+         Some((axis, split, cost)) => {
+            // a leaf is kept when splitting costs more than testing everything
+            let split_cost = TRAVERSAL_COST + if area > 0.0 { cost / area } else { 0.0 };
+            if split_cost >= count as Float && count <= MAX_LEAF {
+               return;
+            }
+            let (lo, hi) = (component(cb.min, axis), component(cb.max, axis));
+            let scale = BINS as Float / (hi - lo);
+            let slice = &mut self.indices[items.clone()];
+            partition(slice, |&i| bin(component(centroids[i], axis), lo, scale) < split)
+         }
+         None => {
+            // all centroids coincide, so just halve the list
+            if count <= MAX_LEAF {
+               return;
+            }
+            count / 2
+         }
+      };
+
+      let left = self.nodes.len();
+      self.nodes.push(Node { bounds: Aabb::empty(), start, count: mid });
+      self.nodes.push(Node { bounds: Aabb::empty(), start: start + mid, count: count - mid });
+      self.nodes[node].start = left;
+      self.nodes[node].count = 0;
+
+      self.build(bounds, centroids, left);
+      self.build(bounds, centroids, left + 1);
+   }
+}
+
+impl Accelerator for Bvh {
+   fn bounds(&self) -> Aabb {
+      if !self.unbounded.is_empty() {
+         Aabb::infinite()
+      } else {
+         self.nodes.first().map_or(Aabb::empty(), |n| n.bounds)
+      }
+   }
+
+   fn closest(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize, Float) -> Option<Float>) {
+      let mut tmax = tmax;
+      for &i in self.unbounded.iter() {
+         if let Some(t) = test(i, tmax) {
+            tmax = t;
+         }
+      }
+      if self.nodes.is_empty() {
+         return;
+      }
+
+      let inv_dir = inverse(r.direction);
+      let mut stack = vec![0];
+      while let Some(n) = stack.pop() {
+         let node = &self.nodes[n];
+         if node.bounds.hit(r, inv_dir, tmin, tmax).is_none() {
+            continue;
+         }
+         if node.count > 0 {
+            for &i in self.indices[node.start .. node.start + node.count].iter() {
+               if let Some(t) = test(i, tmax) {
+                  tmax = t;
+               }
+            }
+         } else {
+            // visit the nearer child first so it can cut off the other
+            let (a, b) = (node.start, node.start + 1);
+            let ta = self.nodes[a].bounds.hit(r, inv_dir, tmin, tmax).map(|h| h.0);
+            let tb = self.nodes[b].bounds.hit(r, inv_dir, tmin, tmax).map(|h| h.0);
+            match (ta, tb) {
+               (Some(x), Some(y)) => {
+                  if x <= y { stack.push(b); stack.push(a); } else { stack.push(a); stack.push(b); }
+               }
+               (Some(_), None) => stack.push(a),
+               (None, Some(_)) => stack.push(b),
+               (None, None) => {},
+            }
+         }
+      }
+   }
+
+   // Nodes are visited while any lane reaches them, nearer child first by
+   // the closest entry among the lanes.
+   fn closest_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &mut Lanes, test: &mut dyn FnMut(usize, &mut Lanes)) {
+      for &i in self.unbounded.iter() {
+         test(i, tmax);
+      }
+      if self.nodes.is_empty() {
+         return;
+      }
+
+      // nodes are stacked with where each lane enters them, and passed
+      // over once every lane has found something nearer
+      let miss = Lanes::splat(Float::INFINITY);
+      let mut stack = vec![(0, self.nodes[0].bounds.hit_packet(r, tmin, tmax))];
+      while let Some((n, entry)) = stack.pop() {
+         if !(entry.lt(miss) & entry.le(*tmax)).any() {
+            continue;
+         }
+         let node = &self.nodes[n];
+         if node.count > 0 {
+            for &i in self.indices[node.start .. node.start + node.count].iter() {
+               test(i, tmax);
+            }
+         } else {
+            let (a, b) = (node.start, node.start + 1);
+            let ea = self.nodes[a].bounds.hit_packet(r, tmin, tmax);
+            let eb = self.nodes[b].bounds.hit_packet(r, tmin, tmax);
+            let (ta, tb) = (ea.least(), eb.least());
+            match (ta < Float::INFINITY, tb < Float::INFINITY) {
+               (true, true) => {
+                  if ta <= tb {
+                     stack.push((b, eb));
+                     stack.push((a, ea));
+                  } else {
+                     stack.push((a, ea));
+                     stack.push((b, eb));
+                  }
+               }
+               (true, false) => stack.push((a, ea)),
+               (false, true) => stack.push((b, eb)),
+               (false, false) => {},
+            }
+         }
+      }
+   }
+
+   fn any(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize) -> bool) -> bool {
+      if self.unbounded.iter().any(|&i| test(i)) {
+         return true;
+      }
+      if self.nodes.is_empty() {
+         return false;
+      }
+
+      let inv_dir = inverse(r.direction);
+      let mut stack = vec![0];
+      while let Some(n) = stack.pop() {
+         let node = &self.nodes[n];
+         if node.bounds.hit(r, inv_dir, tmin, tmax).is_none() {
+            continue;
+         }
+         if node.count > 0 {
+            if self.indices[node.start .. node.start + node.count].iter().any(|&i| test(i)) {
+               return true;
+            }
+         } else {
+            stack.push(node.start);
+            stack.push(node.start + 1);
+         }
+      }
+      false
+   }
+}
+
+fn bin(c: Float, lo: Float, scale: Float) -> usize {
+   (((c - lo) * scale) as usize).min(BINS - 1)
+}
+
+// Moves the items matching pred to the front, returning how many there are.
+fn partition<F: Fn(&usize) -> bool>(items: &mut [usize], pred: F) -> usize {
+   let mut first = 0;
+   for i in 0 .. items.len() {
+      if pred(&items[i]) {
+         items.swap(first, i);
+         first += 1;
+      }
+   }
+   first
+}
@@ -13,39 +13,40 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::f32;
+use vector::float;
 use ray::Ray;
-use vector::Vector;
+use vector::{Vector, Float};
 
 #[derive(Debug,Copy,Clone)]
 pub struct Camera {
-   v_fov: f32,
-   h_fov: f32,
-   h_pixels: f32,
-   v_pixels: f32,
-   l: f32,
-   r: f32,
-   t: f32,
-   b: f32,
-   vp: f32,
+   h_pixels: Float,
+   v_pixels: Float,
+   l: Float,
+   r: Float,
+   t: Float,
+   b: Float,
+   vp: Float,
+   // interval the shutter is open for, the same at both ends for no blur
+   shutter_open: Float,
+   shutter_close: Float,
 }
 
 impl Camera {
 
-   pub fn new(v_fov: f32, h_pixels: f32, v_pixels: f32) -> Camera {
+   pub fn new(v_fov: Float, h_pixels: Float, v_pixels: Float) -> Camera {
       let h_fov = v_fov * h_pixels / v_pixels;
-      let v_fov_rad = v_fov * f32::consts::PI / 180.0;
-      let h_fov_rad = h_fov * f32::consts::PI / 180.0;
+      let v_fov_rad = v_fov * float::consts::PI / 180.0;
+      let h_fov_rad = h_fov * float::consts::PI / 180.0;
       Camera {
-         v_fov: v_fov,
-         h_fov: v_fov * h_pixels / v_pixels,
-         h_pixels: h_pixels,
-         v_pixels: v_pixels,
+         h_pixels,
+         v_pixels,
          l: -(h_fov_rad / 2.0).tan(),
          r: (h_fov_rad / 2.0).tan(),
          b: -(v_fov_rad / 2.0).tan(),
          t: (v_fov_rad / 2.0).tan(),
          vp: -1.0,
+         shutter_open: 0.0,
+         shutter_close: 0.0,
       }
    }
 
@@ -57,9 +58,16 @@ impl Camera {
       self.v_pixels as usize
    }
 
-   pub fn ray(self, x: f32, y: f32) -> Ray {
-      let u = self.l + (self.r - self.l) * ((x + 0.5) / self.h_pixels);
-      let v = self.b + (self.t - self.b) * ((y + 0.5) / self.v_pixels);
+   pub fn set_shutter(&mut self, open: Float, close: Float) {
+      self.shutter_open = open;
+      self.shutter_close = close;
+   }
+
+   // Ray through the point x, y of the image, pixel x covering x to x + 1,
+   // at the fraction s of the way through the shutter interval.
+   pub fn ray(self, x: Float, y: Float, s: Float) -> Ray {
+      let u = self.l + (self.r - self.l) * (x / self.h_pixels);
+      let v = self.b + (self.t - self.b) * (y / self.v_pixels);
 
       let eye = Vector::zero();
       let screen = Vector::new(u, v, self.vp);
@@ -67,6 +75,7 @@ impl Camera {
       Ray {
          origin: eye,
          direction: screen.normalize(),
+         time: self.shutter_open + (self.shutter_close - self.shutter_open) * s,
       }
    }
 }
@@ -14,19 +14,20 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
 use std::ops::{Add, Sub, Mul};
+use vector::Float;
 
 #[derive(Debug,Copy,Clone)]
 pub struct Color {
-   pub r: f32,
-   pub g: f32,
-   pub b: f32,
+   pub r: Float,
+   pub g: Float,
+   pub b: Float,
 }
 
 impl Color {
 
    // Constructors
-   pub fn new(r: f32, g: f32, b: f32) -> Color {
-      Color { r: r, g: g, b: b }
+   pub fn new(r: Float, g: Float, b: Float) -> Color {
+      Color { r, g, b }
    }
 
    pub fn to_bytes(self) -> (u8, u8, u8) {
@@ -36,14 +37,18 @@ impl Color {
       (r as u8, g as u8, b as u8)
    }
 
+   pub fn luminance(&self) -> Float {
+      0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+   }
+
 }
 
 // Operator overloads
 
-impl Mul<f32> for Color {
+impl Mul<Float> for Color {
     type Output = Color;
 
-    fn mul(self, rhs: f32) -> Color {
+    fn mul(self, rhs: Float) -> Color {
         Color {
             r: self.r * rhs,
             g: self.g * rhs,
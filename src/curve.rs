@@ -0,0 +1,353 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Thin curves for hair, fur and grass: a cubic Bezier swept by a width
+// that varies linearly from one end to the other. The curve is treated as
+// a flat ribbon always facing the ray, found by subdividing it in a space
+// where the ray runs along z until the pieces are close enough to straight
+// segments, as in Physically Based Rendering. Normals are bent across the
+// ribbon so it shades like a cylinder.
+//
+// Curve files have one curve per line, the 12 coordinates of its control
+// points followed by the widths at either end. Lines starting with # are
+// comments.
+
+use std::path::Path;
+use vector::{Vector, dot, cross, basis, Float};
+use vector::float::consts;
+use ray::Ray;
+use color::Color;
+use aabb::Aabb;
+use accelerator::{self, Accelerator, Structure};
+use geometry::{Geometry, ShadeRec};
+use mesh::{read_file, parse_floats, import_color};
+use toml::Value;
+
+const MAX_DEPTH: i32 = 10;
+
+#[derive(Debug,Copy,Clone)]
+pub struct Curve {
+   points: [Vector; 4],
+   width: [Float; 2],
+   color: Color,
+}
+
+// Scene geometry made of many curves, found through an acceleration
+// structure.
+pub struct Curves {
+   curves: Vec<Curve>,
+   accelerator: Box<dyn Accelerator>,
+}
+
+impl Curve {
+   pub fn new(points: [Vector; 4], width: [Float; 2], color: Color) -> Curve {
+      Curve { points, width, color }
+   }
+
+   pub fn import(obj: &Value) -> Result<Curve, String> {
+      let elements = vec!["p0.0", "p0.1", "p0.2", "p1.0", "p1.1", "p1.2",
+                          "p2.0", "p2.1", "p2.2", "p3.0", "p3.1", "p3.2"];
+      let mut v = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         v.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      // a single width or one for each end
+      let width = obj.lookup("width").ok_or("Missing element.")?;
+      let width = match width.as_float() {
+         Some(w) => [w as Float, w as Float],
+         None => {
+            let w0 = obj.lookup("width.0").and_then(|w| w.as_float()).ok_or("Invalid float.")?;
+            let w1 = obj.lookup("width.1").and_then(|w| w.as_float()).ok_or("Invalid float.")?;
+            [w0 as Float, w1 as Float]
+         }
+      };
+
+      let points = [Vector::new(v[0], v[1], v[2]), Vector::new(v[3], v[4], v[5]),
+                    Vector::new(v[6], v[7], v[8]), Vector::new(v[9], v[10], v[11])];
+      Ok(Curve::new(points, width, import_color(obj)?))
+   }
+
+   fn width_at(&self, u: Float) -> Float {
+      self.width[0] + (self.width[1] - self.width[0]) * u
+   }
+
+   // Recursively splits the control points cp, given in ray space and
+   // covering u0 to u1 of the curve, returning the closest t and u of a hit.
+   fn recurse(&self, cp: &[Vector; 4], u0: Float, u1: Float, depth: i32, tmin: Float, tmax: Float) -> Option<(Float, Float)> {
+      let half = self.width_at(u0).max(self.width_at(u1)) * 0.5;
+      let b = Aabb::from_points(cp);
+      if b.min.x - half > 0.0 || b.max.x + half < 0.0 || b.min.y - half > 0.0 || b.max.y + half < 0.0 ||
+         b.min.z - half > tmax || b.max.z + half < tmin {
+         return None;
+      }
+
+      if depth > 0 {
+         let (a, b) = split(cp);
+         let um = 0.5 * (u0 + u1);
+         let first = self.recurse(&a, u0, um, depth - 1, tmin, tmax);
+         let tmax = first.map_or(tmax, |h| h.0);
+         return self.recurse(&b, um, u1, depth - 1, tmin, tmax).or(first);
+      }
+
+      // the hit must lie between the lines through the segment's ends
+      // perpendicular to its tangents there, so neighbouring pieces meet
+      // without gaps or overlaps
+      let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+      if edge < 0.0 {
+         return None;
+      }
+      let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+      if edge < 0.0 {
+         return None;
+      }
+
+      // closest point to the ray on the straight segment between the ends
+      let (sx, sy) = (cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+      let denom = sx * sx + sy * sy;
+      if denom == 0.0 {
+         return None;
+      }
+      let w = ((-cp[0].x * sx - cp[0].y * sy) / denom).clamp(0.0, 1.0);
+      let u = u0 + (u1 - u0) * w;
+      let (p, _) = eval(cp, w);
+
+      let half = self.width_at(u) * 0.5;
+      if p.x * p.x + p.y * p.y > half * half || p.z < tmin || p.z > tmax {
+         return None;
+      }
+      Some((p.z, u))
+   }
+}
+
+impl Geometry for Curve {
+   fn bounds(&self) -> Aabb {
+      let half = self.width[0].max(self.width[1]) * 0.5;
+      let b = Aabb::from_points(&self.points);
+      let e = Vector::new(half, half, half);
+      Aabb::new(b.min - e, b.max + e)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      // ray space has the origin at the ray's and z along its direction,
+      // scaled so z is the ray parameter t
+      let len = r.direction.magnitude();
+      let dz = r.direction * (1.0 / len);
+      let (dx, dy) = basis(dz);
+      let mut cp = [Vector::zero(); 4];
+      for (c, &q) in cp.iter_mut().zip(self.points.iter()) {
+         let p = q - r.origin;
+         *c = Vector::new(dot(p, dx), dot(p, dy), dot(p, dz) / len);
+      }
+
+      // enough splits for the pieces to be within a twentieth of the
+      // width of straight, from the bound on a Bezier's flatness
+      let mut l0: Float = 0.0;
+      for i in 0 .. 2 {
+         let d = cp[i] - cp[i + 1] * 2.0 + cp[i + 2];
+         l0 = l0.max(d.x.abs()).max(d.y.abs()).max((d.z * len).abs());
+      }
+      let eps = self.width[0].max(self.width[1]) * 0.05;
+      let bound = consts::SQRT_2 * 6.0 * l0 / 8.0;
+      let depth = if eps > 0.0 && l0 > 0.0 {
+         ((bound / eps).log2().floor() as i32 / 2).clamp(0, MAX_DEPTH)
+      } else {
+         0
+      };
+      // each split quarters how far the pieces may be from straight
+      let flatness = bound / (1u32 << (2 * depth)) as Float;
+
+      let (t, u) = self.recurse(&cp, 0.0, 1.0, depth, tmin, tmax)?;
+
+      let hit_point = r.origin + r.direction * t;
+      let (axis, dpdu) = eval(&self.points, u);
+      let tangent = if dpdu.magnitude_sq() > 0.0 { dpdu.normalize() } else { dz };
+
+      // position across the ribbon from -1 to 1, turned into the normal of
+      // a cylinder seen from the ray
+      let view = -dz;
+      let facing = view - tangent * dot(view, tangent);
+      let facing = if facing.magnitude_sq() > 1e-12 { facing.normalize() } else { view };
+      let side = cross(tangent, facing);
+      let half = self.width_at(u) * 0.5;
+      let s = if half > 0.0 { (dot(hit_point - axis, side) / half).clamp(-1.0, 1.0) } else { 0.0 };
+      let normal = facing * (1.0 - s * s).sqrt() + side * s;
+
+      // the hit is on the ray, but the pieces it was found on may be as far
+      // as their flatness from the curve, which moves it along the ray
+      let mut sr = ShadeRec::new(r, t, normal.normalize(), self.color);
+      let error = sr.error + dz.abs() * flatness;
+      sr.set_hit_point(hit_point, error);
+      // u runs along the curve and v across it, the ribbon itself facing
+      // the ray
+      sr.set_geometric_normal(facing);
+      sr.set_surface((u, (s + 1.0) * 0.5), dpdu, side * (half * 2.0));
+      Some(sr)
+   }
+}
+
+impl Curves {
+   pub fn new(curves: Vec<Curve>, structure: Structure) -> Curves {
+      let bounds: Vec<Aabb> = curves.iter().map(|c| c.bounds()).collect();
+      Curves { accelerator: accelerator::build(structure, &bounds), curves }
+   }
+
+   pub fn load(path: &Path, color: Color) -> Result<Vec<Curve>, String> {
+      let data = read_file(path)?;
+      let mut curves = Vec::new();
+
+      for (lineno, line) in data.lines().enumerate() {
+         let line = line.trim();
+         if line.is_empty() || line.starts_with('#') {
+            continue;
+         }
+         let v = parse_floats(line.split_whitespace(), 14)
+                    .map_err(|e| format!("{}:{}: {}", path.display(), lineno + 1, e))?;
+         let points = [Vector::new(v[0], v[1], v[2]), Vector::new(v[3], v[4], v[5]),
+                       Vector::new(v[6], v[7], v[8]), Vector::new(v[9], v[10], v[11])];
+         curves.push(Curve::new(points, [v[12], v[13]], color));
+      }
+
+      Ok(curves)
+   }
+
+   pub fn import(obj: &Value, dir: &Path, structure: Structure) -> Result<Curves, String> {
+      let file = obj.lookup("file").and_then(|v| v.as_str()).ok_or("Missing file.")?;
+      let color = import_color(obj)?;
+      Ok(Curves::new(Curves::load(&dir.join(file), color)?, structure))
+   }
+}
+
+impl Geometry for Curves {
+   fn bounds(&self) -> Aabb {
+      self.accelerator.bounds()
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let mut hit = None;
+      let curves = &self.curves;
+      self.accelerator.closest(&r, tmin, tmax, &mut |i, tmax| {
+         curves[i].intersect(r, tmin, tmax).map(|sr| {
+            let t = sr.t;
+            hit = Some(sr);
+            t
+         })
+      });
+      hit
+   }
+}
+
+// Position and derivative of a cubic Bezier at u.
+fn eval(cp: &[Vector; 4], u: Float) -> (Vector, Vector) {
+   let s = 1.0 - u;
+   let p = cp[0] * (s * s * s) + cp[1] * (3.0 * u * s * s) + cp[2] * (3.0 * u * u * s) + cp[3] * (u * u * u);
+   let d = (cp[1] - cp[0]) * (3.0 * s * s) + (cp[2] - cp[1]) * (6.0 * u * s) + (cp[3] - cp[2]) * (3.0 * u * u);
+   (p, d)
+}
+
+// Halves of a cubic Bezier split at its middle, by de Casteljau.
+fn split(cp: &[Vector; 4]) -> ([Vector; 4], [Vector; 4]) {
+   let m01 = (cp[0] + cp[1]) * 0.5;
+   let m12 = (cp[1] + cp[2]) * 0.5;
+   let m23 = (cp[2] + cp[3]) * 0.5;
+   let a = (m01 + m12) * 0.5;
+   let b = (m12 + m23) * 0.5;
+   let mid = (a + b) * 0.5;
+   ([cp[0], m01, a, mid], [mid, b, m23, cp[3]])
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Curve, eval};
+   use vector::{Vector, Float};
+   use ray::Ray;
+   use color::Color;
+   use geometry::{Geometry, ShadeRec};
+
+   // Straight along x from 0 to 3, widening from 0.2 to 0.6.
+   fn straight() -> Curve {
+      let points = [Vector::zero(), Vector::new(1.0, 0.0, 0.0), Vector::new(2.0, 0.0, 0.0), Vector::new(3.0, 0.0, 0.0)];
+      Curve::new(points, [0.2, 0.6], Color::new(1.0, 1.0, 1.0))
+   }
+
+   fn down(c: &Curve, x: Float, y: Float) -> Option<ShadeRec> {
+      let r = Ray { origin: Vector::new(x, y, 5.0), direction: Vector::new(0.0, 0.0, -1.0), time: 0.0 };
+      c.intersect(r, 0.0, Float::INFINITY)
+   }
+
+   #[test]
+   fn width_follows_the_curve() {
+      let c = straight();
+      // 0.4 wide half way, 0.24 wide a tenth of the way along
+      for &(x, half) in [(1.5, 0.2), (0.3, 0.12)].iter() {
+         let sr = down(&c, x, half * 0.95).unwrap();
+         assert!((sr.t - 5.0).abs() < 1e-4 && (sr.uv.0 - x / 3.0).abs() < 1e-4, "{} {:?}", sr.t, sr.uv);
+         assert!(down(&c, x, -half * 0.95).is_some());
+         assert!(down(&c, x, half * 1.05).is_none());
+      }
+   }
+
+   #[test]
+   fn ends_are_cut_square() {
+      let c = straight();
+      assert!(down(&c, 0.02, 0.0).is_some());
+      assert!(down(&c, -0.02, 0.0).is_none());
+      assert!(down(&c, 2.98, 0.0).is_some());
+      assert!(down(&c, 3.02, 0.0).is_none());
+   }
+
+   // The ribbon faces the ray, but its normals turn across it as a
+   // cylinder's would.
+   #[test]
+   fn shades_like_a_cylinder() {
+      let c = straight();
+      let centre = down(&c, 1.5, 0.0).unwrap();
+      assert!((centre.normal.z - 1.0).abs() < 1e-4, "{:?}", centre.normal);
+      for &y in [0.18, -0.18, 0.1].iter() {
+         let sr = down(&c, 1.5, y).unwrap();
+         let s = y / 0.2;
+         assert!((sr.geometric_normal.z - 1.0).abs() < 1e-4, "{:?}", sr.geometric_normal);
+         assert!((sr.normal.y - s).abs() < 1e-3 && (sr.normal.z - (1.0 - s * s).sqrt()).abs() < 1e-3,
+                 "{:?} at {}", sr.normal, y);
+      }
+   }
+
+   // Hits on a bent curve are as deep as the nearest point of the curve to
+   // within the error given for them, which is a few twentieths of the
+   // width the subdivision aims for at most.
+   #[test]
+   fn hits_are_within_their_error() {
+      let points = [Vector::zero(), Vector::new(1.0, 2.0, -1.0), Vector::new(2.0, -2.0, 1.0), Vector::new(3.0, 0.0, 0.5)];
+      let c = Curve::new(points, [0.05, 0.05], Color::new(1.0, 1.0, 1.0));
+      let mut hits = 0;
+      for i in 0 .. 300 {
+         let x = 3.0 * i as Float / 300.0;
+         let y = eval(&points, x / 3.0).0.y;
+         if let Some(sr) = down(&c, x, y) {
+            hits += 1;
+            let nearest = (0 .. 3001).map(|k| eval(&points, k as Float / 3000.0).0).fold(Vector::zero(), |a, p| {
+               let d = |p: Vector| (p.x - x) * (p.x - x) + (p.y - y) * (p.y - y);
+               if d(p) < d(a) { p } else { a }
+            });
+            assert!((nearest.z - sr.hit_point.z).abs() <= sr.error.z + 1e-3, "{} from the curve, {:?}",
+                    nearest.z - sr.hit_point.z, sr.error);
+            assert!(sr.error.z < 4.0 * 0.05 * 0.05, "{:?}", sr.error);
+         }
+      }
+      assert!(hits > 200, "{} hits", hits);
+   }
+}
@@ -0,0 +1,226 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use vector::float::consts;
+use vector::{Vector, Float};
+use color::Color;
+use image::Image;
+
+// Equirectangular environment map. The centre of the image looks down -z
+// with +y at the top. Carries a piecewise constant distribution over the
+// pixels, proportional to luminance, for importance sampling.
+pub struct EnvironmentMap {
+   image: Image,
+   marginal: Vec<Float>,
+   conditional: Vec<Vec<Float>>,
+   func: Vec<Float>,
+   total: Float,
+}
+
+impl EnvironmentMap {
+   pub fn new(image: Image) -> EnvironmentMap {
+      let (w, h) = (image.width, image.height);
+      let mut func = Vec::with_capacity(w * h);
+
+      for y in 0 .. h {
+         // weight by sin(theta) to account for the distortion at the poles
+         let sin_theta = (consts::PI * (y as Float + 0.5) / h as Float).sin();
+         for x in 0 .. w {
+            func.push(image.get(x, y).luminance().max(0.0) * sin_theta);
+         }
+      }
+
+      // a black map still needs a valid distribution
+      if func.iter().all(|&f| f == 0.0) {
+         for y in 0 .. h {
+            let sin_theta = (consts::PI * (y as Float + 0.5) / h as Float).sin();
+            for x in 0 .. w {
+               func[x + y * w] = sin_theta;
+            }
+         }
+      }
+
+      let mut conditional = Vec::with_capacity(h);
+      let mut row_sums = Vec::with_capacity(h);
+      for y in 0 .. h {
+         let (cdf, sum) = build_cdf(&func[y * w .. (y + 1) * w]);
+         conditional.push(cdf);
+         row_sums.push(sum);
+      }
+      let (marginal, total) = build_cdf(&row_sums);
+
+      EnvironmentMap {
+         image,
+         marginal,
+         conditional,
+         func,
+         total,
+      }
+   }
+
+   fn pixel(&self, d: Vector) -> (usize, usize) {
+      let d = d.normalize();
+      let phi = d.x.atan2(-d.z);
+      let theta = d.y.clamp(-1.0, 1.0).acos();
+      let u = (phi + consts::PI) / (2.0 * consts::PI);
+      let v = theta / consts::PI;
+      let x = ((u * self.image.width as Float) as usize).min(self.image.width - 1);
+      let y = ((v * self.image.height as Float) as usize).min(self.image.height - 1);
+      (x, y)
+   }
+
+   pub fn lookup(&self, d: Vector) -> Color {
+      let (x, y) = self.pixel(d);
+      self.image.get(x, y)
+   }
+
+   // Returns a direction distributed according to the map's luminance
+   // along with its solid angle pdf.
+   pub fn sample(&self, u1: Float, u2: Float) -> (Vector, Float) {
+      let y = sample_cdf(&self.marginal, u1);
+      let x = sample_cdf(&self.conditional[y], u2);
+
+      let dv = remap(&self.marginal, y, u1);
+      let du = remap(&self.conditional[y], x, u2);
+      let u = (x as Float + du) / self.image.width as Float;
+      let v = (y as Float + dv) / self.image.height as Float;
+
+      let theta = v * consts::PI;
+      let phi = u * 2.0 * consts::PI - consts::PI;
+      let sin_theta = theta.sin();
+      let d = Vector::new(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos());
+
+      (d, self.pdf_pixel(x, y, sin_theta))
+   }
+
+   // The sampler has the pixel at hand, so only the tests look directions up.
+   #[cfg(test)]
+   pub fn pdf(&self, d: Vector) -> Float {
+      let (x, y) = self.pixel(d);
+      let sin_theta = (1.0 - d.normalize().y.powi(2)).max(0.0).sqrt();
+      self.pdf_pixel(x, y, sin_theta)
+   }
+
+   fn pdf_pixel(&self, x: usize, y: usize, sin_theta: Float) -> Float {
+      if sin_theta == 0.0 || self.total == 0.0 {
+         return 0.0;
+      }
+      let (w, h) = (self.image.width, self.image.height);
+      let p = self.func[x + y * w] / self.total * (w * h) as Float;
+      p / (2.0 * consts::PI * consts::PI * sin_theta)
+   }
+}
+
+// Normalised cumulative distribution of f with f.len() + 1 entries.
+fn build_cdf(f: &[Float]) -> (Vec<Float>, Float) {
+   let mut cdf = Vec::with_capacity(f.len() + 1);
+   let mut sum = 0.0;
+   cdf.push(0.0);
+   for v in f {
+      sum += *v;
+      cdf.push(sum);
+   }
+   if sum > 0.0 {
+      for c in cdf.iter_mut() {
+         *c /= sum;
+      }
+   }
+   (cdf, sum)
+}
+
+// Index of the last cdf entry not greater than u, skipping empty buckets.
+fn sample_cdf(cdf: &[Float], u: Float) -> usize {
+   let (mut lo, mut hi) = (0, cdf.len() - 1);
+   while lo + 1 < hi {
+      let mid = (lo + hi) / 2;
+      if cdf[mid] <= u { lo = mid; } else { hi = mid; }
+   }
+   lo
+}
+
+fn remap(cdf: &[Float], i: usize, u: Float) -> Float {
+   let width = cdf[i + 1] - cdf[i];
+   if width > 0.0 { ((u - cdf[i]) / width).min(1.0) } else { 0.5 }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{EnvironmentMap, build_cdf, sample_cdf, remap};
+   use vector::float::consts;
+   use vector::{Vector, Float, dot};
+   use color::Color;
+   use image::Image;
+
+   fn map(w: usize, h: usize, bright: (usize, usize)) -> EnvironmentMap {
+      let mut pixels = vec![Color::new(0.1, 0.1, 0.1); w * h];
+      pixels[bright.0 + bright.1 * w] = Color::new(50.0, 50.0, 50.0);
+      EnvironmentMap::new(Image { width: w, height: h, pixels })
+   }
+
+   #[test]
+   fn cdf_is_normalised() {
+      let (cdf, sum) = build_cdf(&[1.0, 3.0]);
+      assert_eq!(sum, 4.0);
+      assert_eq!(cdf, vec![0.0, 0.25, 1.0]);
+   }
+
+   #[test]
+   fn sampling_skips_empty_buckets() {
+      let (cdf, _) = build_cdf(&[0.0, 1.0, 0.0, 1.0]);
+      assert_eq!(sample_cdf(&cdf, 0.0), 1);
+      assert_eq!(sample_cdf(&cdf, 0.25), 1);
+      assert_eq!(sample_cdf(&cdf, 0.5), 3);
+      assert_eq!(sample_cdf(&cdf, 0.99), 3);
+      assert_eq!(remap(&cdf, 1, 0.25), 0.5);
+   }
+
+   #[test]
+   fn samples_follow_luminance() {
+      let m = map(16, 8, (5, 3));
+      let hits = (0 .. 1000).filter(|&i| {
+         let (d, _) = m.sample((i as Float + 0.5) / 1000.0, ((i * 7919) % 1000) as Float / 1000.0);
+         m.pixel(d) == (5, 3)
+      }).count();
+      // the bright pixel holds most of the map's weighted luminance
+      assert!(hits > 800, "{} samples on the bright pixel", hits);
+   }
+
+   #[test]
+   fn sample_pdf_matches_pdf() {
+      let m = map(16, 8, (12, 2));
+      for i in 0 .. 50 {
+         let (d, pdf) = m.sample((i as Float + 0.5) / 50.0, ((i * 31) % 50) as Float / 50.0 + 0.01);
+         assert!((dot(d, d) - 1.0).abs() < 1e-4);
+         assert!((m.pdf(d) - pdf).abs() <= 1e-3 * pdf, "{} != {}", m.pdf(d), pdf);
+      }
+   }
+
+   #[test]
+   fn pdf_integrates_to_one() {
+      let m = map(16, 8, (3, 6));
+      let (nt, np) = (200, 400);
+      let mut sum = 0.0;
+      for i in 0 .. nt {
+         let theta = consts::PI * (i as Float + 0.5) / nt as Float;
+         for j in 0 .. np {
+            let phi = 2.0 * consts::PI * (j as Float + 0.5) / np as Float;
+            let d = Vector::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            let area = theta.sin() * (consts::PI / nt as Float) * (2.0 * consts::PI / np as Float);
+            sum += m.pdf(d) * area;
+         }
+      }
+      assert!((sum - 1.0).abs() < 0.01, "pdf integrates to {}", sum);
+   }
+}
@@ -0,0 +1,468 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// OpenEXR reader for single part scanline images, uncompressed or with
+// RLE, ZIPS or ZIP compression, which covers what most tools write for
+// environment maps. Pixels come from the R, G and B channels, or Y for
+// grey images, in half, float or uint precision. The data window is read
+// and the display window ignored.
+// http://www.openexr.com/documentation/openexrfilelayout.pdf
+
+use std::fs::File;
+use std::path::Path;
+use std::io::{Cursor, Read};
+use color::Color;
+use vector::Float;
+use image::Image;
+use byteorder::{LittleEndian, ByteOrder, ReadBytesExt};
+use flate2::read::ZlibDecoder;
+
+const MAGIC: u32 = 20000630;
+// version flags for tiled, deep and multipart files
+const UNSUPPORTED_FLAGS: u32 = 0x200 | 0x800 | 0x1000;
+// largest image accepted, well past any environment map
+const MAX_PIXELS: usize = 1 << 28;
+
+const UINT: u32 = 0;
+const HALF: u32 = 1;
+const FLOAT: u32 = 2;
+
+struct Channel {
+   name: String,
+   kind: u32,
+   sampling: (i32, i32),
+}
+
+impl Channel {
+   fn size(&self) -> usize {
+      if self.kind == HALF { 2 } else { 4 }
+   }
+}
+
+pub fn load(path: &Path) -> Result<Image, String> {
+   let mut fin = File::open(path).map_err(|e| format!("{} - {}", path.display(), e))?;
+   let mut data = Vec::new();
+   fin.read_to_end(&mut data).map_err(|e| format!("{} - {}", path.display(), e))?;
+   read(&data).map_err(|e| format!("{} - {}", path.display(), e))
+}
+
+fn read(data: &[u8]) -> Result<Image, String> {
+   let mut c = Cursor::new(data);
+   if c.read_u32::<LittleEndian>().ok() != Some(MAGIC) {
+      return Err("Not an OpenEXR file.".to_string());
+   }
+   let version = c.read_u32::<LittleEndian>().map_err(|e| e.to_string())?;
+   if version & 0xff != 2 {
+      return Err(format!("Unsupported OpenEXR version {}.", version & 0xff));
+   }
+   if version & UNSUPPORTED_FLAGS != 0 {
+      return Err("Only single part scanline OpenEXR images are supported.".to_string());
+   }
+
+   let mut channels = None;
+   let mut compression = None;
+   let mut window = None;
+   loop {
+      let name = read_string(&mut c)?;
+      if name.is_empty() {
+         break;
+      }
+      read_string(&mut c)?;
+      let size = c.read_u32::<LittleEndian>().map_err(|e| e.to_string())? as usize;
+      let start = c.position() as usize;
+      if size > data.len() - start {
+         return Err("Truncated header.".to_string());
+      }
+      let value = &data[start .. start + size];
+      c.set_position((start + size) as u64);
+
+      match name.as_str() {
+         "channels" => channels = Some(read_channels(value)?),
+         "compression" if size == 1 => compression = Some(value[0]),
+         "dataWindow" if size == 16 => {
+            let mut w = [0; 4];
+            LittleEndian::read_i32_into(value, &mut w);
+            window = Some(w);
+         }
+         _ => {},
+      }
+   }
+
+   let channels = channels.ok_or("Missing channels.")?;
+   let compression = compression.ok_or("Missing compression.")?;
+   let window = window.ok_or("Missing data window.")?;
+
+   let lines_per_block = match compression {
+      0..=2 => 1,
+      3 => 16,
+      4 => return Err("Unsupported compression PIZ.".to_string()),
+      5 => return Err("Unsupported compression PXR24.".to_string()),
+      6 | 7 => return Err("Unsupported compression B44.".to_string()),
+      8 | 9 => return Err("Unsupported compression DWA.".to_string()),
+      n => return Err(format!("Unknown compression {}.", n)),
+   };
+
+   let width = window[2] as i64 - window[0] as i64 + 1;
+   let height = window[3] as i64 - window[1] as i64 + 1;
+   if width <= 0 || height <= 0 || width * height > MAX_PIXELS as i64 {
+      return Err(format!("Bad data window {:?}.", window));
+   }
+   let (width, height) = (width as usize, height as usize);
+
+   if channels.iter().any(|ch| ch.sampling != (1, 1)) {
+      return Err("Subsampled channels aren't supported.".to_string());
+   }
+   let find = |name: &str| channels.iter().position(|ch| ch.name == name);
+   let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+      (Some(r), Some(g), Some(b), _) => [r, g, b],
+      (None, None, None, Some(y)) => [y, y, y],
+      _ => return Err("Needs R, G and B channels or a Y channel.".to_string()),
+   };
+
+   // where each channel starts within a line of pixels
+   let mut starts = Vec::with_capacity(channels.len());
+   let mut line_size = 0;
+   for ch in channels.iter() {
+      starts.push(line_size * width);
+      line_size += ch.size();
+   }
+   let line_size = line_size * width;
+
+   let blocks = height.div_ceil(lines_per_block);
+   let mut offsets = Vec::with_capacity(blocks);
+   for _ in 0 .. blocks {
+      offsets.push(c.read_u64::<LittleEndian>().map_err(|_| "Truncated offset table.")?);
+   }
+
+   let mut pixels = vec![Color::new(0.0, 0.0, 0.0); width * height];
+   for &offset in offsets.iter() {
+      if offset > data.len() as u64 {
+         return Err("Bad block offset.".to_string());
+      }
+      c.set_position(offset);
+      let y = c.read_i32::<LittleEndian>().map_err(|_| "Truncated block.")? as i64 - window[1] as i64;
+      let size = c.read_u32::<LittleEndian>().map_err(|_| "Truncated block.")? as usize;
+      if y < 0 || y >= height as i64 || !(y as usize).is_multiple_of(lines_per_block) {
+         return Err("Bad block position.".to_string());
+      }
+      let start = c.position() as usize;
+      if size > data.len() - start {
+         return Err("Truncated block.".to_string());
+      }
+      let packed = &data[start .. start + size];
+
+      let y = y as usize;
+      let lines = lines_per_block.min(height - y);
+      let expected = lines * line_size;
+      // blocks that wouldn't shrink are stored as they are
+      let block = if size == expected {
+         packed.to_vec()
+      } else {
+         match compression {
+            1 => unpredict(unrle(packed, expected)?),
+            2 | 3 => unpredict(inflate(packed, expected)?),
+            _ => return Err("Bad block size.".to_string()),
+         }
+      };
+      if block.len() != expected {
+         return Err("Bad block size.".to_string());
+      }
+
+      for line in 0 .. lines {
+         let row = &block[line * line_size .. (line + 1) * line_size];
+         for x in 0 .. width {
+            let mut v = [0.0; 3];
+            for i in 0 .. 3 {
+               let ch = &channels[rgb[i]];
+               v[i] = value(ch.kind, &row[starts[rgb[i]] + x * ch.size() ..]);
+            }
+            pixels[x + (y + line) * width] = Color::new(v[0], v[1], v[2]);
+         }
+      }
+   }
+
+   Ok(Image { width, height, pixels })
+}
+
+fn read_string(c: &mut Cursor<&[u8]>) -> Result<String, String> {
+   let mut s = Vec::new();
+   loop {
+      match c.read_u8().map_err(|_| "Truncated header.")? {
+         0 => return Ok(String::from_utf8_lossy(&s).into_owned()),
+         b => s.push(b),
+      }
+   }
+}
+
+// A list of channels, each a name, its type, a linear flag, three reserved
+// bytes and its sampling rates, ended by an empty name.
+fn read_channels(value: &[u8]) -> Result<Vec<Channel>, String> {
+   let mut c = Cursor::new(value);
+   let mut channels = Vec::new();
+   loop {
+      let name = read_string(&mut c)?;
+      if name.is_empty() {
+         return Ok(channels);
+      }
+      let mut v = [0; 4];
+      c.read_i32_into::<LittleEndian>(&mut v).map_err(|_| "Truncated channel list.")?;
+      let kind = v[0] as u32;
+      if kind > FLOAT {
+         return Err(format!("Unknown type for channel {}.", name));
+      }
+      channels.push(Channel { name, kind, sampling: (v[2], v[3]) });
+   }
+}
+
+fn value(kind: u32, b: &[u8]) -> Float {
+   match kind {
+      UINT => LittleEndian::read_u32(b) as Float,
+      HALF => half_to_float(LittleEndian::read_u16(b)),
+      _ => LittleEndian::read_f32(b) as Float,
+   }
+}
+
+fn half_to_float(h: u16) -> Float {
+   let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+   let exponent = ((h >> 10) & 0x1f) as i32;
+   let mantissa = (h & 0x3ff) as Float;
+   match exponent {
+      0 => sign * mantissa * (2.0 as Float).powi(-24),
+      31 if mantissa == 0.0 => sign * Float::INFINITY,
+      31 => Float::NAN,
+      e => sign * (1.0 + mantissa / 1024.0) * (2.0 as Float).powi(e - 15),
+   }
+}
+
+fn inflate(packed: &[u8], expected: usize) -> Result<Vec<u8>, String> {
+   let mut out = Vec::with_capacity(expected);
+   // reading one byte past the size expected is enough to catch a bad block
+   ZlibDecoder::new(packed).take(expected as u64 + 1).read_to_end(&mut out).map_err(|e| e.to_string())?;
+   Ok(out)
+}
+
+// Runs of a repeated byte have a count of one less than their length, runs
+// of bytes copied as they are a negative count.
+fn unrle(packed: &[u8], expected: usize) -> Result<Vec<u8>, String> {
+   let mut out = Vec::with_capacity(expected);
+   let mut i = 0;
+   while i < packed.len() {
+      let n = packed[i] as i8;
+      i += 1;
+      if n < 0 {
+         let count = -(n as isize) as usize;
+         if i + count > packed.len() {
+            return Err("Bad RLE data.".to_string());
+         }
+         out.extend_from_slice(&packed[i .. i + count]);
+         i += count;
+      } else {
+         if i >= packed.len() {
+            return Err("Bad RLE data.".to_string());
+         }
+         for _ in 0 .. n as usize + 1 {
+            out.push(packed[i]);
+         }
+         i += 1;
+      }
+      if out.len() > expected {
+         return Err("Bad RLE data.".to_string());
+      }
+   }
+   Ok(out)
+}
+
+// Compressed blocks hold the differences between successive bytes, with
+// the bytes at even and odd positions split into two halves.
+fn unpredict(mut t: Vec<u8>) -> Vec<u8> {
+   for i in 1 .. t.len() {
+      t[i] = t[i - 1].wrapping_add(t[i]).wrapping_sub(128);
+   }
+   let half = t.len().div_ceil(2);
+   let mut out = Vec::with_capacity(t.len());
+   for i in 0 .. half {
+      out.push(t[i]);
+      if half + i < t.len() {
+         out.push(t[half + i]);
+      }
+   }
+   out
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{read, half_to_float, unpredict, MAGIC};
+   use std::io::Write;
+   use byteorder::{LittleEndian, WriteBytesExt};
+   use flate2::Compression;
+   use flate2::write::ZlibEncoder;
+   use vector::Float;
+
+   // The inverse of unpredict.
+   fn predict(b: &[u8]) -> Vec<u8> {
+      let half = b.len().div_ceil(2);
+      let mut t = vec![0; b.len()];
+      for (i, &x) in b.iter().enumerate() {
+         t[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = x;
+      }
+      let mut out = t.clone();
+      for i in 1 .. t.len() {
+         out[i] = t[i].wrapping_sub(t[i - 1]).wrapping_add(128);
+      }
+      out
+   }
+
+   fn attribute(f: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+      f.extend_from_slice(name.as_bytes());
+      f.push(0);
+      f.extend_from_slice(kind.as_bytes());
+      f.push(0);
+      f.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+      f.extend_from_slice(value);
+   }
+
+   // A w by h image with B, G and R channels of the given type, where pixel
+   // x, y is (x, y, x + y). Blocks are packed by the compression given.
+   fn exr(w: usize, h: usize, kind: u32, compression: u8, lines: usize, pack: &dyn Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+      let mut f = Vec::new();
+      f.write_u32::<LittleEndian>(MAGIC).unwrap();
+      f.write_u32::<LittleEndian>(2).unwrap();
+      let mut channels = Vec::new();
+      for name in ["B", "G", "R"].iter() {
+         channels.extend_from_slice(name.as_bytes());
+         channels.push(0);
+         for &v in [kind, 0, 1, 1].iter() {
+            channels.write_u32::<LittleEndian>(v).unwrap();
+         }
+      }
+      channels.push(0);
+      attribute(&mut f, "channels", "chlist", &channels);
+      attribute(&mut f, "compression", "compression", &[compression]);
+      let mut window = Vec::new();
+      // placed away from the origin, which shouldn't matter
+      for &v in [3, -2, 3 + w as i32 - 1, -2 + h as i32 - 1].iter() {
+         window.write_i32::<LittleEndian>(v).unwrap();
+      }
+      attribute(&mut f, "dataWindow", "box2i", &window);
+      attribute(&mut f, "lineOrder", "lineOrder", &[0]);
+      f.push(0);
+
+      let blocks = h.div_ceil(lines);
+      let table = f.len();
+      f.resize(table + blocks * 8, 0);
+      for b in 0 .. blocks {
+         let offset = f.len() as u64;
+         (&mut f[table + b * 8 ..]).write_u64::<LittleEndian>(offset).unwrap();
+         let mut raw = Vec::new();
+         for y in b * lines .. h.min((b + 1) * lines) {
+            for &c in [2, 1, 0].iter() {
+               for x in 0 .. w {
+                  let v = [x, y, x + y][c] as f32;
+                  if kind == 1 {
+                     // small whole numbers have exact halves
+                     let bits = if v == 0.0 { 0 } else {
+                        let e = v.log2().floor() as u16;
+                        (e + 15) << 10 | ((v / (1 << e) as f32 - 1.0) * 1024.0) as u16
+                     };
+                     raw.write_u16::<LittleEndian>(bits).unwrap();
+                  } else {
+                     raw.write_f32::<LittleEndian>(v).unwrap();
+                  }
+               }
+            }
+         }
+         let packed = pack(&raw);
+         f.write_i32::<LittleEndian>(-2 + (b * lines) as i32).unwrap();
+         f.write_u32::<LittleEndian>(packed.len() as u32).unwrap();
+         f.extend_from_slice(&packed);
+      }
+      f
+   }
+
+   fn check(data: &[u8], w: usize, h: usize) {
+      let image = read(data).unwrap();
+      assert_eq!((image.width, image.height), (w, h));
+      for y in 0 .. h {
+         for x in 0 .. w {
+            let c = image.get(x, y);
+            assert_eq!((c.r, c.g, c.b), (x as Float, y as Float, (x + y) as Float));
+         }
+      }
+   }
+
+   fn zip(raw: &[u8]) -> Vec<u8> {
+      let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+      e.write_all(&predict(raw)).unwrap();
+      e.finish().unwrap()
+   }
+
+   // Every byte as a copied run, in runs of up to 127.
+   fn rle(raw: &[u8]) -> Vec<u8> {
+      let mut out = Vec::new();
+      for run in predict(raw).chunks(127) {
+         out.push((-(run.len() as i8)) as u8);
+         out.extend_from_slice(run);
+      }
+      out
+   }
+
+   #[test]
+   fn halves_decode() {
+      assert_eq!(half_to_float(0x3c00), 1.0);
+      assert_eq!(half_to_float(0xc000), -2.0);
+      assert_eq!(half_to_float(0x3555), 1365.0 / 4096.0);
+      assert_eq!(half_to_float(0x0001), (2.0 as Float).powi(-24));
+      assert_eq!(half_to_float(0x7c00), Float::INFINITY);
+      assert!(half_to_float(0x7e00).is_nan());
+   }
+
+   #[test]
+   fn prediction_round_trips() {
+      for n in 0 .. 6 {
+         let b: Vec<u8> = (0 .. n).map(|i| (i * 77 + 3) as u8).collect();
+         assert_eq!(unpredict(predict(&b)), b);
+      }
+   }
+
+   #[test]
+   fn reads_uncompressed() {
+      check(&exr(5, 3, 1, 0, 1, &|raw| raw.to_vec()), 5, 3);
+      check(&exr(4, 2, 2, 0, 1, &|raw| raw.to_vec()), 4, 2);
+   }
+
+   #[test]
+   fn reads_compressed() {
+      // blocks of 16 lines with a short one at the end
+      check(&exr(40, 37, 1, 3, 16, &zip), 40, 37);
+      check(&exr(40, 5, 2, 2, 1, &zip), 40, 5);
+      check(&exr(9, 4, 1, 1, 1, &rle), 9, 4);
+   }
+
+   #[test]
+   fn rejects_what_it_cant_read() {
+      assert!(read(b"#?RADIANCE\n").is_err());
+      // PIZ compression
+      assert!(read(&exr(4, 4, 1, 4, 32, &|raw| raw.to_vec())).err().unwrap().contains("PIZ"));
+      // tiled
+      let mut tiled = exr(4, 4, 1, 0, 1, &|raw| raw.to_vec());
+      tiled[5] = 2;
+      assert!(read(&tiled).is_err());
+      // a block cut short
+      let mut cut = exr(4, 4, 1, 3, 16, &zip);
+      let n = cut.len();
+      cut.truncate(n - 4);
+      assert!(read(&cut).is_err());
+   }
+}
@@ -13,29 +13,102 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use vector::{Vector, dot, cross};
-use ray::Ray;
+use vector::{Vector, dot, cross, basis, gamma, next_float_up, Float};
+use ray::{Ray, offset_origin};
+use aabb::Aabb;
 use color::Color;
+use brdf::{self, Brdf};
+use polynomial::solve_quartic;
 use toml::Value;
+use vector::float;
+use std::rc::Rc;
+use std::array;
+use packet::{self, RayPacket, WideVector, Lanes, Mask, LANES};
 
 pub trait Geometry {
-   fn intersect(&self, r: Ray, tmin: f32, tmax: f32) -> Option<ShadeRec>;
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec>;
+
+   // Infinite for geometry without a finite extent.
+   fn bounds(&self) -> Aabb;
+
+   // Every intersection between tmin and tmax in increasing order, which CSG
+   // needs to know where the ray enters and leaves a solid. By default the
+   // nearest hit is found repeatedly, stepping past the previous one by as
+   // far along the ray as its error reaches.
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      let mut hits = Vec::new();
+      let mut t = tmin;
+      while let Some(sr) = self.intersect(r, t, tmax) {
+         t = next_float_up(sr.t + dot(sr.error, r.direction.abs()) / r.direction.magnitude_sq());
+         hits.push(sr);
+      }
+      hits
+   }
+
+   // Nearest hits of the rays of a packet, each lane between tmin and its
+   // own tmax, with lanes whose tmax is below tmin inactive. By default the
+   // rays are intersected one at a time.
+   fn intersect_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &Lanes) -> [Option<ShadeRec>; LANES] {
+      array::from_fn(|k| if tmax[k] >= tmin[k] { self.intersect(r.ray(k), tmin[k], tmax[k]) } else { None })
+   }
+}
+
+// A geometry placed in the scene with the per object settings used when
+// lighting it. The name is what lights refer to when linking.
+pub struct SceneObject {
+   pub name: String,
+   pub casts_shadows: bool,
+   pub receives_shadows: bool,
+   pub geometry: Box<dyn Geometry>,
+   // reflection model, None for plain diffuse in the surface color
+   pub brdf: Option<Rc<dyn Brdf>>,
+}
+
+impl SceneObject {
+   pub fn new(geometry: Box<dyn Geometry>) -> SceneObject {
+      SceneObject {
+         name: String::new(),
+         casts_shadows: true,
+         receives_shadows: true,
+         geometry,
+         brdf: None,
+      }
+   }
+
+   pub fn import(obj: &Value, geometry: Box<dyn Geometry>) -> Result<SceneObject, String> {
+      let mut so = SceneObject::new(geometry);
+
+      if let Some(v) = obj.lookup("name") {
+         so.name = v.as_str().ok_or("Invalid string.")?.to_string();
+      }
+      if let Some(v) = obj.lookup("casts_shadows") {
+         so.casts_shadows = v.as_bool().ok_or("Invalid bool.")?;
+      }
+      if let Some(v) = obj.lookup("receives_shadows") {
+         so.receives_shadows = v.as_bool().ok_or("Invalid bool.")?;
+      }
+      if let Some(v) = obj.lookup("brdf") {
+         so.brdf = Some(Rc::from(brdf::import(v)?));
+      }
+
+      Ok(so)
+   }
 }
 
 #[derive(Debug,Copy,Clone)]
 pub struct Sphere {
    origin: Vector,
-   radius: f32,
+   radius: Float,
    color: Color,
 }
 
 impl Sphere {
-   pub fn new(x: f32, y: f32, z: f32, r: f32, cr: f32, cg: f32, cb: f32) -> Sphere {
+   pub fn new(x: Float, y: Float, z: Float, r: Float, cr: Float, cg: Float, cb: Float) -> Sphere {
       Sphere { origin: Vector::new(x, y, z), radius: r, color: Color::new(cr, cg, cb) }
    }
 
-   pub fn from_vec(v: Vec<f32>) -> Sphere {
-      Sphere { origin: Vector::new(v[0], v[1], v[2]), radius: v[3], color: Color::new(v[4], v[5], v[6]) }
+   pub fn from_vec(v: Vec<Float>) -> Sphere {
+      Sphere::new(v[0], v[1], v[2], v[3], v[4], v[5], v[6])
    }
 
    pub fn import(obj: &Value) -> Result<Sphere, String> {
@@ -43,8 +116,8 @@ impl Sphere {
       let mut values = Vec::new();
 
       for e in elements {
-         let value = try!(obj.lookup(e).ok_or("Missing element."));
-         values.push(try!(value.as_float().ok_or("Invalid float.")) as f32);
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
       }
 
       Ok(Sphere::from_vec(values))
@@ -52,7 +125,12 @@ impl Sphere {
 }
 
 impl Geometry for Sphere {
-   fn intersect(&self, r: Ray, tmin: f32, tmax: f32) -> Option<ShadeRec> {
+   fn bounds(&self) -> Aabb {
+      let r = Vector::new(self.radius, self.radius, self.radius);
+      Aabb::new(self.origin - r, self.origin + r)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
 
       let l = self.origin - r.origin;
       let s = dot(l, r.direction);
@@ -69,21 +147,66 @@ impl Geometry for Sphere {
       }
 
       let q = (rr - mm).sqrt();
-      let t = if ll > rr { s - q } else { s + q };
+      let t = if s - q >= tmin { s - q } else { s + q };
 
       if t < tmin || t > tmax {
          return None;
       }
 
-      let p = r.origin + r.direction * t;
-      let n = (p - self.origin).normalize();
+      Some(self.shade(r, t))
+   }
 
-      Some(ShadeRec {
-         t: t,
-         hit_point: r.origin + r.direction * t,
-         normal: n,
-         color: self.color,
-      })
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      let l = self.origin - r.origin;
+      let s = dot(l, r.direction);
+      let mm = dot(l, l) - s * s;
+      let rr = self.radius * self.radius;
+      if mm > rr {
+         return Vec::new();
+      }
+      let q = (rr - mm).sqrt();
+      in_order(vec![(s - q, ()), (s + q, ())], tmin, tmax).into_iter().map(|(t, _)| self.shade(r, t)).collect()
+   }
+
+   // The same test as intersect in every lane, without leaving early.
+   fn intersect_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &Lanes) -> [Option<ShadeRec>; LANES] {
+      let l = WideVector::splat(self.origin) - r.origin;
+      let s = packet::dot(&l, &r.direction);
+      let ll = packet::dot(&l, &l);
+      let rr = Lanes::splat(self.radius * self.radius);
+      let zero = Lanes::splat(0.0);
+
+      let mm = ll - s * s;
+      let q = (rr - mm).sqrt();
+      let (near, far) = (s - q, s + q);
+      let t = near.ge(*tmin).select(near, far);
+      let miss = (s.lt(zero) & ll.gt(rr)) | mm.gt(rr) | t.lt(*tmin) | t.gt(*tmax);
+
+      let hit = !miss;
+      array::from_fn(|k| if hit.holds(k) { Some(self.shade(r.ray(k), t[k])) } else { None })
+   }
+}
+
+impl Sphere {
+   fn shade(&self, r: Ray, t: Float) -> ShadeRec {
+      // the hit is moved onto the sphere, which is more accurate than
+      // following the ray to it
+      let q = r.origin + r.direction * t - self.origin;
+      let q = q * (self.radius / q.magnitude());
+      let mut sr = ShadeRec::new(r, t, q * (1.0 / self.radius), self.color);
+      sr.set_hit_point(self.origin + q, (self.origin.abs() + q.abs()) * gamma(6));
+
+      // u around the vertical axis and v down from the top, where the
+      // tangents vanish
+      let (u, dpdu) = around_y(q);
+      let theta = (q.y / self.radius).clamp(-1.0, 1.0).acos();
+      let rho = (q.x * q.x + q.z * q.z).sqrt();
+      sr.uv = (u, theta * float::consts::FRAC_1_PI);
+      if rho > 0.0 {
+         sr.dpdu = dpdu;
+         sr.dpdv = Vector::new(q.y * q.x / rho, -rho, q.y * q.z / rho) * float::consts::PI;
+      }
+      sr
    }
 }
 
@@ -93,47 +216,1186 @@ pub struct Triangle {
    pub v1: Vector,
    pub v2: Vector,
    pub color: Color,
+   // per vertex normals, interpolated across the face when present
+   pub normals: Option<[Vector; 3]>,
+}
+
+impl Triangle {
+   pub fn new(v0: Vector, v1: Vector, v2: Vector, color: Color) -> Triangle {
+      Triangle { v0, v1, v2, color, normals: None }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Triangle {
+      Triangle::new(Vector::new(v[0], v[1], v[2]),
+                    Vector::new(v[3], v[4], v[5]),
+                    Vector::new(v[6], v[7], v[8]),
+                    Color::new(v[9], v[10], v[11]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Triangle, String> {
+      let elements = vec!["v0.0", "v0.1", "v0.2", "v1.0", "v1.1", "v1.2", "v2.0", "v2.1", "v2.2",
+                          "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let mut triangle = Triangle::from_vec(values);
+
+      if obj.lookup("n0").is_some() {
+         let elements = vec!["n0.0", "n0.1", "n0.2", "n1.0", "n1.1", "n1.2", "n2.0", "n2.1", "n2.2"];
+         let mut n = Vec::new();
+
+         for e in elements {
+            let value = obj.lookup(e).ok_or("Missing element.")?;
+            n.push(value.as_float().ok_or("Invalid float.")? as Float);
+         }
+
+         triangle.normals = Some([Vector::new(n[0], n[1], n[2]).normalize(),
+                                  Vector::new(n[3], n[4], n[5]).normalize(),
+                                  Vector::new(n[6], n[7], n[8]).normalize()]);
+      }
+
+      Ok(triangle)
+   }
 }
 
 impl Geometry for Triangle {
-   fn intersect(&self, r: Ray, tmin: f32, tmax: f32) -> Option<ShadeRec> {
-      const EPS: f32 = 1e-5;
-      let e1 = self.v1 - self.v0;
-      let e2 = self.v2 - self.v0;
-      let p = cross(r.direction, e2);
-      let a = dot(e1, p);
-      if a > -EPS && a < EPS {
+   fn bounds(&self) -> Aabb {
+      Aabb::from_points(&[self.v0, self.v1, self.v2])
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      intersect_triangle(r, self.v0, self.v1, self.v2, tmin, tmax).map(|(t, u, v)| self.shade(r, t, u, v))
+   }
+
+   fn intersect_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &Lanes) -> [Option<ShadeRec>; LANES] {
+      let (hit, t, u, v) = intersect_triangle_packet(r, self.v0, self.v1, self.v2, tmin, tmax);
+      array::from_fn(|k| if hit.holds(k) { Some(self.shade(r.ray(k), t[k], u[k], v[k])) } else { None })
+   }
+}
+
+impl Triangle {
+   fn shade(&self, r: Ray, t: Float, u: Float, v: Float) -> ShadeRec {
+      let (e1, e2) = (self.v1 - self.v0, self.v2 - self.v0);
+      let face = cross(e1, e2).normalize();
+      let n = match self.normals {
+         Some(n) => (n[0] * (1.0 - u - v) + n[1] * u + n[2] * v).normalize(),
+         None => face,
+      };
+
+      let mut sr = ShadeRec::new(r, t, n, self.color);
+      let (p, error) = triangle_point(self.v0, self.v1, self.v2, u, v);
+      sr.set_hit_point(p, error);
+      sr.set_geometric_normal(face);
+      sr.set_surface((u, v), e1, e2);
+      sr
+   }
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct Plane {
+   point: Vector,
+   normal: Vector,
+   color: Color,
+}
+
+impl Plane {
+   pub fn new(point: Vector, normal: Vector, color: Color) -> Plane {
+      Plane { point, normal: normal.normalize(), color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Plane {
+      Plane::new(Vector::new(v[0], v[1], v[2]), Vector::new(v[3], v[4], v[5]), Color::new(v[6], v[7], v[8]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Plane, String> {
+      let elements = vec!["point.0", "point.1", "point.2", "normal.0", "normal.1", "normal.2",
+                          "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      Ok(Plane::from_vec(values))
+   }
+}
+
+impl Geometry for Plane {
+   fn bounds(&self) -> Aabb {
+      Aabb::infinite()
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let t = intersect_plane(r, self.point, self.normal);
+
+      if !(t >= tmin && t <= tmax) {
+         return None;
+      }
+
+      // coordinates are distances from point along two fixed directions
+      let mut sr = ShadeRec::new(r, t, self.normal, self.color);
+      let (p, error) = onto_plane(sr.hit_point, self.point, self.normal);
+      sr.set_hit_point(p, error);
+      let d = p - self.point;
+      let (e1, e2) = (sr.dpdu, sr.dpdv);
+      sr.uv = (dot(d, e1), dot(d, e2));
+      Some(sr)
+   }
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct Disk {
+   center: Vector,
+   normal: Vector,
+   radius: Float,
+   color: Color,
+}
+
+impl Disk {
+   pub fn new(center: Vector, normal: Vector, radius: Float, color: Color) -> Disk {
+      Disk { center, normal: normal.normalize(), radius, color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Disk {
+      Disk::new(Vector::new(v[0], v[1], v[2]), Vector::new(v[3], v[4], v[5]), v[6],
+                Color::new(v[7], v[8], v[9]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Disk, String> {
+      let elements = vec!["center.0", "center.1", "center.2", "normal.0", "normal.1", "normal.2",
+                          "radius", "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      Ok(Disk::from_vec(values))
+   }
+}
+
+impl Geometry for Disk {
+   fn bounds(&self) -> Aabb {
+      let r = Vector::new(self.radius, self.radius, self.radius);
+      Aabb::new(self.center - r, self.center + r)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let t = intersect_plane(r, self.center, self.normal);
+
+      if !(t >= tmin && t <= tmax) {
          return None;
       }
-      let f = 1.0 / a;
-      let s = r.origin - self.v0;
-      let u = f * dot(s, p);
-      if u < 0.0 || u > 1.0 {
+
+      let (p, error) = onto_plane(r.origin + r.direction * t, self.center, self.normal);
+      let d = p - self.center;
+      if d.magnitude_sq() > self.radius * self.radius {
          return None;
       }
-      let q = cross(s, e1);
-      let v = f * dot(r.direction, q);
-      if v < 0.0 || (u + v) > 1.0 {
+
+      // u around the center and v out from it
+      let mut sr = ShadeRec::new(r, t, self.normal, self.color);
+      sr.set_hit_point(p, error);
+      let (e1, e2) = (sr.dpdu, sr.dpdv);
+      let (x, y) = (dot(d, e1), dot(d, e2));
+      let rho = (x * x + y * y).sqrt();
+      if rho > 0.0 {
+         let tau = 2.0 * float::consts::PI;
+         let u = (y.atan2(x) + tau) % tau / tau;
+         sr.set_surface((u, rho / self.radius), (e2 * x - e1 * y) * tau, d * (self.radius / rho));
+      }
+      Some(sr)
+   }
+}
+
+// Rectangle with a corner at p0 and perpendicular sides a and b. The normal
+// is a x b.
+#[derive(Debug,Copy,Clone)]
+pub struct Rectangle {
+   p0: Vector,
+   a: Vector,
+   b: Vector,
+   normal: Vector,
+   color: Color,
+}
+
+impl Rectangle {
+   pub fn new(p0: Vector, a: Vector, b: Vector, color: Color) -> Rectangle {
+      Rectangle { p0, a, b, normal: cross(a, b).normalize(), color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Rectangle {
+      Rectangle::new(Vector::new(v[0], v[1], v[2]), Vector::new(v[3], v[4], v[5]),
+                     Vector::new(v[6], v[7], v[8]), Color::new(v[9], v[10], v[11]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Rectangle, String> {
+      let elements = vec!["p0.0", "p0.1", "p0.2", "a.0", "a.1", "a.2", "b.0", "b.1", "b.2",
+                          "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      // the containment test and uvs measure along a and b independently
+      let a = Vector::new(values[3], values[4], values[5]);
+      let b = Vector::new(values[6], values[7], values[8]);
+      if dot(a, b).abs() > 1e-4 * a.magnitude() * b.magnitude() {
+         return Err("Rectangle sides aren't perpendicular.".to_string());
+      }
+
+      Ok(Rectangle::from_vec(values))
+   }
+}
+
+impl Geometry for Rectangle {
+   fn bounds(&self) -> Aabb {
+      Aabb::from_points(&[self.p0, self.p0 + self.a, self.p0 + self.b, self.p0 + self.a + self.b])
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let t = intersect_plane(r, self.p0, self.normal);
+
+      if !(t >= tmin && t <= tmax) {
          return None;
       }
-      let t = f * dot(e2, q);
 
-      if t < tmin || t > tmax {
+      let (p, error) = onto_plane(r.origin + r.direction * t, self.p0, self.normal);
+      let d = p - self.p0;
+
+      let da = dot(d, self.a);
+      if da < 0.0 || da > self.a.magnitude_sq() {
+         return None;
+      }
+
+      let db = dot(d, self.b);
+      if db < 0.0 || db > self.b.magnitude_sq() {
          return None;
       }
 
-      Some(ShadeRec {
-         t: t,
-         hit_point: r.origin + r.direction * t,
-         normal: Vector::zero(),
-         color: self.color,
-      })
+      let mut sr = ShadeRec::new(r, t, self.normal, self.color);
+      sr.set_hit_point(p, error);
+      sr.set_surface((da / self.a.magnitude_sq(), db / self.b.magnitude_sq()), self.a, self.b);
+      Some(sr)
+   }
+}
+
+// Axis aligned box between the corners min and max.
+#[derive(Debug,Copy,Clone)]
+pub struct Cuboid {
+   min: Vector,
+   max: Vector,
+   color: Color,
+}
+
+impl Cuboid {
+   pub fn new(min: Vector, max: Vector, color: Color) -> Cuboid {
+      Cuboid { min, max, color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Cuboid {
+      Cuboid::new(Vector::new(v[0], v[1], v[2]), Vector::new(v[3], v[4], v[5]), Color::new(v[6], v[7], v[8]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Cuboid, String> {
+      let elements = vec!["min.0", "min.1", "min.2", "max.0", "max.1", "max.2",
+                          "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      Ok(Cuboid::from_vec(values))
+   }
+}
+
+impl Geometry for Cuboid {
+   fn bounds(&self) -> Aabb {
+      Aabb::new(self.min, self.max)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let (t0, face_in, t1, face_out) = self.slabs(r)?;
+      if t0 >= tmin && t0 <= tmax {
+         Some(self.shade(r, t0, face_in))
+      } else if t1 >= tmin && t1 <= tmax {
+         Some(self.shade(r, t1, face_out))
+      } else {
+         None
+      }
+   }
+
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      match self.slabs(r) {
+         Some((t0, face_in, t1, face_out)) => {
+            in_order(vec![(t0, face_in), (t1, face_out)], tmin, tmax).into_iter()
+               .map(|(t, face)| self.shade(r, t, face)).collect()
+         }
+         None => Vec::new(),
+      }
    }
 }
 
+// A cuboid face as an axis and the sign of its outward normal.
+type Face = (usize, Float);
+
+impl Cuboid {
+   // Entry and exit distances of the line along r, each with the face
+   // crossed.
+   fn slabs(&self, r: Ray) -> Option<(Float, Face, Float, Face)> {
+      let o = [r.origin.x, r.origin.y, r.origin.z];
+      let d = [r.direction.x, r.direction.y, r.direction.z];
+      let lo = [self.min.x, self.min.y, self.min.z];
+      let hi = [self.max.x, self.max.y, self.max.z];
+
+      let mut t0 = Float::NEG_INFINITY;
+      let mut t1 = Float::INFINITY;
+      let (mut face_in, mut face_out) = ((0, 0.0), (0, 0.0));
+
+      for i in 0 .. 3 {
+         let inv = 1.0 / d[i];
+         let (a, b) = ((lo[i] - o[i]) * inv, (hi[i] - o[i]) * inv);
+         let (near, far, sign) = if a < b { (a, b, -1.0) } else { (b, a, 1.0) };
+         if near > t0 {
+            t0 = near;
+            face_in = (i, sign);
+         }
+         if far < t1 {
+            t1 = far;
+            face_out = (i, -sign);
+         }
+      }
+
+      if t0 > t1 { None } else { Some((t0, face_in, t1, face_out)) }
+   }
+
+   fn shade(&self, r: Ray, t: Float, (axis, sign): Face) -> ShadeRec {
+      let lo = [self.min.x, self.min.y, self.min.z];
+      let hi = [self.max.x, self.max.y, self.max.z];
+      let mut sr = ShadeRec::new(r, t, axis_vector(axis, sign), self.color);
+
+      // the hit is put exactly on the face, so it has no error across it
+      let mut p = [sr.hit_point.x, sr.hit_point.y, sr.hit_point.z];
+      let mut e = [sr.error.x, sr.error.y, sr.error.z];
+      p[axis] = if sign > 0.0 { hi[axis] } else { lo[axis] };
+      e[axis] = 0.0;
+      sr.set_hit_point(Vector::new(p[0], p[1], p[2]), Vector::new(e[0], e[1], e[2]));
+
+      // each face is spanned by the next two axes, uv running 0 to 1
+      let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+      let uv = ((p[a] - lo[a]) / (hi[a] - lo[a]), (p[b] - lo[b]) / (hi[b] - lo[b]));
+      sr.set_surface(uv, axis_vector(a, hi[a] - lo[a]), axis_vector(b, hi[b] - lo[b]));
+      sr
+   }
+}
+
+// Cylinder around the vertical axis through base, which is the center of its
+// bottom. Without caps the inside is visible, so normals face the ray.
+#[derive(Debug,Copy,Clone)]
+pub struct Cylinder {
+   base: Vector,
+   height: Float,
+   radius: Float,
+   closed: bool,
+   color: Color,
+}
+
+impl Cylinder {
+   pub fn new(base: Vector, height: Float, radius: Float, closed: bool, color: Color) -> Cylinder {
+      Cylinder { base, height, radius, closed, color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Cylinder {
+      Cylinder::new(Vector::new(v[0], v[1], v[2]), v[3], v[4], false, Color::new(v[5], v[6], v[7]))
+   }
+
+   // Hit on the cap facing ny at p, relative to the base.
+   fn cap(&self, r: Ray, t: Float, p: Vector, ny: Float) -> ShadeRec {
+      let center = self.base + Vector::new(0.0, if ny > 0.0 { self.height } else { 0.0 }, 0.0);
+      cap(r, t, center, Vector::new(p.x, 0.0, p.z), ny, self.radius, self.color)
+   }
+
+   pub fn import(obj: &Value) -> Result<Cylinder, String> {
+      let elements = vec!["base.0", "base.1", "base.2", "height", "radius", "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let mut cylinder = Cylinder::from_vec(values);
+      if let Some(c) = obj.lookup("closed") {
+         cylinder.closed = c.as_bool().ok_or("Invalid bool.")?;
+      }
+
+      Ok(cylinder)
+   }
+}
+
+impl Geometry for Cylinder {
+   fn bounds(&self) -> Aabb {
+      let r = self.radius;
+      Aabb::new(self.base - Vector::new(r, 0.0, r), self.base + Vector::new(r, self.height, r))
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      nearest(self.crossings(r), tmin, tmax).map(|(t, ny)| self.shade(r, t, ny))
+   }
+
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      in_order(self.crossings(r), tmin, tmax).into_iter().map(|(t, ny)| self.shade(r, t, ny)).collect()
+   }
+}
+
+impl Cylinder {
+   // Everywhere the line along r crosses the surface, with the y of the
+   // normal for a cap or 0 for the side.
+   fn crossings(&self, r: Ray) -> Vec<(Float, Float)> {
+      let o = r.origin - self.base;
+      let d = r.direction;
+      let mut found = Vec::new();
+
+      let a = d.x * d.x + d.z * d.z;
+      let b = 2.0 * (o.x * d.x + o.z * d.z);
+      let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+      for t in quadratic_roots(a, b, c) {
+         let y = o.y + t * d.y;
+         if y >= 0.0 && y <= self.height {
+            found.push((t, 0.0));
+         }
+      }
+
+      if self.closed {
+         for &(y, ny) in [(0.0, -1.0), (self.height, 1.0)].iter() {
+            let t = (y - o.y) / d.y;
+            let p = o + d * t;
+            if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+               found.push((t, ny));
+            }
+         }
+      }
+
+      found
+   }
+
+   fn shade(&self, r: Ray, t: Float, ny: Float) -> ShadeRec {
+      let o = r.origin - self.base;
+      let d = r.direction;
+      if ny != 0.0 {
+         return self.cap(r, t, o + d * t, ny);
+      }
+
+      // the hit is moved onto the side, which is more accurate than
+      // following the ray to it
+      let y = o.y + t * d.y;
+      let p = o + d * t;
+      let p = Vector::new(p.x, 0.0, p.z) * (self.radius / (p.x * p.x + p.z * p.z).sqrt()) +
+              Vector::new(0.0, p.y, 0.0);
+      let n = Vector::new(p.x, 0.0, p.z) * (1.0 / self.radius);
+      let shading = if !self.closed && dot(n, d) > 0.0 { -n } else { n };
+      let mut sr = ShadeRec::new(r, t, shading, self.color);
+      let error = (self.base.abs() + p.abs()) * gamma(6) + Vector::new(0.0, sr.error.y, 0.0);
+      sr.set_hit_point(self.base + p, error);
+      sr.set_geometric_normal(n);
+      // u around the axis and v up it
+      let (u, dpdu) = around_y(p);
+      sr.set_surface((u, y / self.height), dpdu, Vector::new(0.0, self.height, 0.0));
+      sr
+   }
+}
+
+// Cone standing on base, the center of its bottom, with its apex height
+// above. Without a cap the inside is visible, so normals face the ray.
+#[derive(Debug,Copy,Clone)]
+pub struct Cone {
+   base: Vector,
+   height: Float,
+   radius: Float,
+   closed: bool,
+   color: Color,
+}
+
+impl Cone {
+   pub fn new(base: Vector, height: Float, radius: Float, closed: bool, color: Color) -> Cone {
+      Cone { base, height, radius, closed, color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Cone {
+      Cone::new(Vector::new(v[0], v[1], v[2]), v[3], v[4], false, Color::new(v[5], v[6], v[7]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Cone, String> {
+      let elements = vec!["base.0", "base.1", "base.2", "height", "radius", "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let mut cone = Cone::from_vec(values);
+      if let Some(c) = obj.lookup("closed") {
+         cone.closed = c.as_bool().ok_or("Invalid bool.")?;
+      }
+
+      Ok(cone)
+   }
+}
+
+impl Geometry for Cone {
+   fn bounds(&self) -> Aabb {
+      let r = self.radius;
+      Aabb::new(self.base - Vector::new(r, 0.0, r), self.base + Vector::new(r, self.height, r))
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      nearest(self.crossings(r), tmin, tmax).map(|(t, on_cap)| self.shade(r, t, on_cap))
+   }
+
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      in_order(self.crossings(r), tmin, tmax).into_iter().map(|(t, on_cap)| self.shade(r, t, on_cap)).collect()
+   }
+}
+
+impl Cone {
+   // Everywhere the line along r crosses the surface, marking the ones on
+   // the cap.
+   fn crossings(&self, r: Ray) -> Vec<(Float, bool)> {
+      let o = r.origin - self.base;
+      let d = r.direction;
+      let h = self.height;
+      let kk = (self.radius / h) * (self.radius / h);
+      let mut found = Vec::new();
+
+      // x^2 + z^2 = k^2 (h - y)^2
+      let oh = h - o.y;
+      let a = d.x * d.x + d.z * d.z - kk * d.y * d.y;
+      let b = 2.0 * (o.x * d.x + o.z * d.z + kk * oh * d.y);
+      let c = o.x * o.x + o.z * o.z - kk * oh * oh;
+      for t in quadratic_roots(a, b, c) {
+         let y = o.y + t * d.y;
+         if y >= 0.0 && y <= h {
+            found.push((t, false));
+         }
+      }
+
+      if self.closed {
+         let t = -o.y / d.y;
+         let p = o + d * t;
+         if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+            found.push((t, true));
+         }
+      }
+
+      found
+   }
+
+   fn shade(&self, r: Ray, t: Float, on_cap: bool) -> ShadeRec {
+      let o = r.origin - self.base;
+      let d = r.direction;
+      let h = self.height;
+      if on_cap {
+         let p = o + d * t;
+         return cap(r, t, self.base, Vector::new(p.x, 0.0, p.z), -1.0, self.radius, self.color);
+      }
+
+      // the hit is moved onto the side at its height, which is more
+      // accurate than following the ray to it
+      let kk = (self.radius / h) * (self.radius / h);
+      let y = o.y + t * d.y;
+      let p = o + d * t;
+      let rho = (p.x * p.x + p.z * p.z).sqrt();
+      let p = if rho > 0.0 {
+         let s = self.radius * (h - p.y) / (h * rho);
+         Vector::new(p.x * s, p.y, p.z * s)
+      } else {
+         p
+      };
+      let n = Vector::new(p.x, kk * (h - p.y), p.z).normalize();
+      let shading = if !self.closed && dot(n, d) > 0.0 { -n } else { n };
+      let mut sr = ShadeRec::new(r, t, shading, self.color);
+      sr.set_hit_point(self.base + p, (self.base.abs() + p.abs()) * gamma(8) + Vector::new(0.0, sr.error.y, 0.0));
+      sr.set_geometric_normal(n);
+      // u around the axis and v up the side to the apex
+      let (u, dpdu) = around_y(p);
+      let rho = (p.x * p.x + p.z * p.z).sqrt();
+      sr.uv = (u, y / h);
+      if rho > 0.0 {
+         sr.dpdu = dpdu;
+         sr.dpdv = Vector::new(-p.x * self.radius / rho, h, -p.z * self.radius / rho);
+      }
+      sr
+   }
+}
+
+// Torus around the vertical axis through center. a is the distance from
+// the center to the middle of the tube and b the radius of the tube.
+#[derive(Debug,Copy,Clone)]
+pub struct Torus {
+   center: Vector,
+   a: Float,
+   b: Float,
+   color: Color,
+}
+
+impl Torus {
+   pub fn new(center: Vector, a: Float, b: Float, color: Color) -> Torus {
+      Torus { center, a, b, color }
+   }
+
+   pub fn from_vec(v: Vec<Float>) -> Torus {
+      Torus::new(Vector::new(v[0], v[1], v[2]), v[3], v[4], Color::new(v[5], v[6], v[7]))
+   }
+
+   pub fn import(obj: &Value) -> Result<Torus, String> {
+      let elements = vec!["center.0", "center.1", "center.2", "a", "b", "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      Ok(Torus::from_vec(values))
+   }
+}
+
+impl Geometry for Torus {
+   fn bounds(&self) -> Aabb {
+      let e = Vector::new(self.a + self.b, self.b, self.a + self.b);
+      Aabb::new(self.center - e, self.center + e)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let mut t = Float::INFINITY;
+      for root in self.roots(r) {
+         if root >= tmin && root <= tmax && root < t {
+            t = root;
+         }
+      }
+      if t == Float::INFINITY {
+         return None;
+      }
+      Some(self.shade(r, t))
+   }
+
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      let roots = self.roots(r).into_iter().map(|t| (t, ())).collect();
+      in_order(roots, tmin, tmax).into_iter().map(|(t, _)| self.shade(r, t)).collect()
+   }
+}
+
+impl Torus {
+   // Distances along r to everywhere its line crosses the surface.
+   #[cfg_attr(feature = "f64", allow(clippy::unnecessary_cast))]
+   fn roots(&self, r: Ray) -> Vec<Float> {
+      let o = r.origin - self.center;
+      let d = r.direction;
+
+      // skip the quartic for rays missing the bounding sphere
+      let reach = self.a + self.b;
+      let oc = dot(o, d) / dot(d, d);
+      if (o - d * oc).magnitude_sq() > reach * reach {
+         return Vec::new();
+      }
+
+      // solved in units of the torus's reach along a unit direction, since
+      // the solver's tolerances are absolute
+      let (unit, len) = (reach as f64, d.magnitude() as f64);
+      let (ox, oy, oz) = (o.x as f64 / unit, o.y as f64 / unit, o.z as f64 / unit);
+      let (dx, dy, dz) = (d.x as f64 / len, d.y as f64 / len, d.z as f64 / len);
+      let (a, b) = (self.a as f64 / unit, self.b as f64 / unit);
+      let (aa, bb) = (a * a, b * b);
+
+      let sum_d_sq = dx * dx + dy * dy + dz * dz;
+      let e = ox * ox + oy * oy + oz * oz - aa - bb;
+      let f = ox * dx + oy * dy + oz * dz;
+      let four_a_sq = 4.0 * aa;
+
+      let roots = solve_quartic([e * e - four_a_sq * (bb - oy * oy),
+                                 4.0 * f * e + 2.0 * four_a_sq * oy * dy,
+                                 2.0 * sum_d_sq * e + 4.0 * f * f + four_a_sq * dy * dy,
+                                 4.0 * sum_d_sq * f,
+                                 sum_d_sq * sum_d_sq]);
+
+      roots.into_iter().map(|root| (root * unit / len) as Float).collect()
+   }
+
+   fn shade(&self, r: Ray, t: Float) -> ShadeRec {
+      let o = r.origin - self.center;
+      let d = r.direction;
+
+      // the quartic's roots are rough, so the hit is moved to the nearest
+      // point on the surface
+      let mut p = o + d * t;
+      let rho = (p.x * p.x + p.z * p.z).sqrt();
+      if rho > 0.0 {
+         let ring = Vector::new(p.x, 0.0, p.z) * (self.a / rho);
+         let tube = p - ring;
+         let l = tube.magnitude();
+         if l > 0.0 {
+            p = ring + tube * (self.b / l);
+         }
+      }
+      let sum_sq = p.magnitude_sq();
+      let param_sq = self.a * self.a + self.b * self.b;
+      let n = Vector::new(p.x * (sum_sq - param_sq),
+                          p.y * (sum_sq - param_sq + 2.0 * self.a * self.a),
+                          p.z * (sum_sq - param_sq)).normalize();
+      let mut sr = ShadeRec::new(r, t, n, self.color);
+      sr.set_hit_point(self.center + p, (self.center.abs() + p.abs()) * gamma(8));
+
+      // u around the vertical axis and v around the tube, from its outside
+      let (u, dpdu) = around_y(p);
+      let rho = (p.x * p.x + p.z * p.z).sqrt();
+      if rho > 0.0 {
+         let tau = 2.0 * float::consts::PI;
+         let out = Vector::new(p.x / rho, 0.0, p.z / rho);
+         let v = (p.y.atan2(rho - self.a) + tau) % tau / tau;
+         sr.set_surface((u, v), dpdu, (out * -p.y + Vector::new(0.0, rho - self.a, 0.0)) * tau);
+      }
+      sr
+   }
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Operation {
+   Union,
+   Intersection,
+   Difference,
+}
+
+// Constructive solid geometry combining two closed solids. The hits of
+// both along the ray are merged in order, tracking whether the ray is
+// inside each one, and kept where that changes whether it is inside the
+// result. Normals must face out of the solids.
+pub struct Csg {
+   op: Operation,
+   a: Box<dyn Geometry>,
+   b: Box<dyn Geometry>,
+}
+
+impl Csg {
+   pub fn new(op: Operation, a: Box<dyn Geometry>, b: Box<dyn Geometry>) -> Csg {
+      Csg { op, a, b }
+   }
+
+   fn inside(&self, in_a: bool, in_b: bool) -> bool {
+      match self.op {
+         Operation::Union => in_a || in_b,
+         Operation::Intersection => in_a && in_b,
+         Operation::Difference => in_a && !in_b,
+      }
+   }
+}
+
+impl Geometry for Csg {
+   fn bounds(&self) -> Aabb {
+      let (a, b) = (self.a.bounds(), self.b.bounds());
+      match self.op {
+         Operation::Union => a.union(&b),
+         Operation::Intersection => a.intersection(&b),
+         Operation::Difference => a,
+      }
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      self.hits(r, tmin, tmax).into_iter().next()
+   }
+
+   fn hits(&self, r: Ray, tmin: Float, tmax: Float) -> Vec<ShadeRec> {
+      // The children are followed to infinity, as a ray that starts inside
+      // one is only known to do so from the way it leaves.
+      let ha = self.a.hits(r, tmin, Float::INFINITY);
+      let hb = self.b.hits(r, tmin, Float::INFINITY);
+      let leaving = |h: &Vec<ShadeRec>| h.first().is_some_and(|sr| !sr.front_face);
+      let mut in_a = leaving(&ha);
+      let mut in_b = leaving(&hb);
+
+      let mut result = Vec::new();
+      let mut ia = ha.into_iter().peekable();
+      let mut ib = hb.into_iter().peekable();
+      loop {
+         let from_a = match (ia.peek(), ib.peek()) {
+            (Some(x), Some(y)) => x.t <= y.t,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+         };
+
+         let was_inside = self.inside(in_a, in_b);
+         let mut sr = if from_a {
+            in_a = !in_a;
+            ia.next().unwrap()
+         } else {
+            in_b = !in_b;
+            ib.next().unwrap()
+         };
+
+         if sr.t > tmax {
+            break;
+         }
+         if self.inside(in_a, in_b) != was_inside {
+            // the inside of b bounds what is left after a difference
+            if !from_a && self.op == Operation::Difference {
+               let n = -sr.geometric_normal;
+               sr.normal = -sr.normal;
+               sr.set_geometric_normal(n);
+            }
+            result.push(sr);
+         }
+      }
+
+      result
+   }
+}
+
+// Real roots of a t^2 + b t + c in increasing order.
+fn quadratic_roots(a: Float, b: Float, c: Float) -> Vec<Float> {
+   if a == 0.0 {
+      return if b == 0.0 { Vec::new() } else { vec![-c / b] };
+   }
+   let disc = b * b - 4.0 * a * c;
+   if disc < 0.0 {
+      return Vec::new();
+   }
+   let e = disc.sqrt();
+   let (t0, t1) = ((-b - e) / (2.0 * a), (-b + e) / (2.0 * a));
+   if t0 < t1 { vec![t0, t1] } else { vec![t1, t0] }
+}
+
+// The nearest of the crossings between tmin and tmax, the last listed
+// where several are equally near.
+fn nearest<T>(crossings: Vec<(Float, T)>, tmin: Float, tmax: Float) -> Option<(Float, T)> {
+   let mut best: Option<(Float, T)> = None;
+   for (t, x) in crossings {
+      if t >= tmin && t <= best.as_ref().map_or(tmax, |b| b.0) {
+         best = Some((t, x));
+      }
+   }
+   best
+}
+
+// The crossings between tmin and tmax in increasing order.
+fn in_order<T>(crossings: Vec<(Float, T)>, tmin: Float, tmax: Float) -> Vec<(Float, T)> {
+   let mut found: Vec<(Float, T)> = crossings.into_iter().filter(|c| c.0 >= tmin && c.0 <= tmax).collect();
+   found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+   found
+}
+
+// Fraction of a turn around the vertical axis from +x towards -z of q,
+// relative to a point on the axis, and the derivative of q along it.
+fn around_y(q: Vector) -> (Float, Vector) {
+   let tau = 2.0 * float::consts::PI;
+   (((-q.z).atan2(q.x) + tau) % tau / tau, Vector::new(q.z, 0.0, -q.x) * tau)
+}
+
+// Vector along axis with length l.
+fn axis_vector(axis: usize, l: Float) -> Vector {
+   match axis {
+      0 => Vector::new(l, 0.0, 0.0),
+      1 => Vector::new(0.0, l, 0.0),
+      _ => Vector::new(0.0, 0.0, l),
+   }
+}
+
+// Hit on the horizontal cap of a cylinder or cone, facing ny, with q the
+// offset of the hit from the cap's center. u runs around it and v out. The
+// hit is put at the height of the cap, so is only as far from it as center
+// is.
+fn cap(r: Ray, t: Float, center: Vector, q: Vector, ny: Float, radius: Float, color: Color) -> ShadeRec {
+   let mut sr = ShadeRec::new(r, t, Vector::new(0.0, ny, 0.0), color);
+   let (p, e) = (sr.hit_point, sr.error);
+   sr.set_hit_point(Vector::new(p.x, center.y, p.z), Vector::new(e.x, center.y.abs() * gamma(1), e.z));
+   let (u, dpdu) = around_y(q);
+   let rho = q.magnitude();
+   if rho > 0.0 {
+      sr.set_surface((u, rho / radius), dpdu, q * (radius / rho));
+   }
+   sr
+}
+
+// Projects p onto the plane through point with the unit normal n, with the
+// bound on the error of the result.
+fn onto_plane(p: Vector, point: Vector, n: Vector) -> (Vector, Vector) {
+   let q = p - n * dot(p - point, n);
+   (q, (q.abs() + point.abs()) * gamma(6))
+}
+
+// Point at barycentric coordinates u and v on a triangle, weighting v1 and
+// v2 respectively, with the bound on its error. Interpolating the vertices
+// is far more accurate than following the ray to the triangle.
+pub fn triangle_point(v0: Vector, v1: Vector, v2: Vector, u: Float, v: Float) -> (Vector, Vector) {
+   let (a, b, c) = (v0 * (1.0 - u - v), v1 * u, v2 * v);
+   (a + b + c, (a.abs() + b.abs() + c.abs()) * gamma(7))
+}
+
+// Distance along r to the plane through point with the given normal. NaN
+// or infinite when the ray is parallel to the plane.
+fn intersect_plane(r: Ray, point: Vector, normal: Vector) -> Float {
+   dot(point - r.origin, normal) / dot(r.direction, normal)
+}
+
+// Moller-Trumbore ray/triangle test. Returns t and the barycentric
+// coordinates u and v of the hit, weighting v1 and v2 respectively. Rays
+// all but parallel to the triangle miss, judged relative to its size so
+// the test works at any scale.
+pub fn intersect_triangle(r: Ray, v0: Vector, v1: Vector, v2: Vector, tmin: Float, tmax: Float)
+   -> Option<(Float, Float, Float)> {
+   const EPS: Float = 1e-7;
+   let e1 = v1 - v0;
+   let e2 = v2 - v0;
+   let p = cross(r.direction, e2);
+   let a = dot(e1, p);
+   if a * a <= EPS * EPS * e1.magnitude_sq() * e2.magnitude_sq() * r.direction.magnitude_sq() {
+      return None;
+   }
+   let f = 1.0 / a;
+   let s = r.origin - v0;
+   let u = f * dot(s, p);
+   if !(0.0..=1.0).contains(&u) {
+      return None;
+   }
+   let q = cross(s, e1);
+   let v = f * dot(r.direction, q);
+   if v < 0.0 || (u + v) > 1.0 {
+      return None;
+   }
+   let t = f * dot(e2, q);
+
+   if t < tmin || t > tmax {
+      return None;
+   }
+
+   Some((t, u, v))
+}
+
+// intersect_triangle for each ray of a packet, giving the lanes that hit
+// and t, u and v for each.
+pub fn intersect_triangle_packet(r: &RayPacket, v0: Vector, v1: Vector, v2: Vector, tmin: &Lanes, tmax: &Lanes)
+   -> (Mask, Lanes, Lanes, Lanes) {
+   const EPS: Float = 1e-7;
+   let e1 = v1 - v0;
+   let e2 = v2 - v0;
+   let scale = Lanes::splat(EPS * EPS * e1.magnitude_sq() * e2.magnitude_sq());
+   let (we1, we2) = (WideVector::splat(e1), WideVector::splat(e2));
+   let p = packet::cross(&r.direction, &we2);
+   let a = packet::dot(&we1, &p);
+   let dd = packet::dot(&r.direction, &r.direction);
+   let f = Lanes::splat(1.0) / a;
+   let s = r.origin - WideVector::splat(v0);
+   let u = f * packet::dot(&s, &p);
+   let q = packet::cross(&s, &we1);
+   let v = f * packet::dot(&r.direction, &q);
+   let t = f * packet::dot(&we2, &q);
+
+   let (zero, one) = (Lanes::splat(0.0), Lanes::splat(1.0));
+   let miss = (a * a).le(scale * dd) | u.lt(zero) | u.gt(one) | v.lt(zero) | (u + v).gt(one) |
+              t.lt(*tmin) | t.gt(*tmax);
+   (!miss, t, u, v)
+}
+
 pub struct ShadeRec {
-   pub t: f32,
+   pub t: Float,
    pub hit_point: Vector,
+   // bound on the rounding error of hit_point in each axis
+   pub error: Vector,
+   // normal used for shading, which may be interpolated or bent
    pub normal: Vector,
+   // normal of the actual surface, out of solids
+   pub geometric_normal: Vector,
+   // surface coordinates and the derivatives of the hit point along them
+   pub uv: (Float, Float),
+   pub dpdu: Vector,
+   pub dpdv: Vector,
+   // whether the ray arrived from the side the geometric normal faces
+   pub front_face: bool,
+   pub ray: Ray,
    pub color: Color,
+   // index of the scene object hit and its material, set by the tracer
+   pub object: usize,
+   pub material: Option<Rc<dyn Brdf>>,
+}
+
+impl ShadeRec {
+   // Hit at t along r with both normals n. Surfaces without coordinates of
+   // their own get uv 0, 0 and unit tangents in an arbitrary direction. The
+   // error allows for the hit point being found from t; surfaces that can
+   // do better give a tighter point with set_hit_point.
+   pub fn new(r: Ray, t: Float, n: Vector, color: Color) -> ShadeRec {
+      let (dpdu, dpdv) = basis(n);
+      let d = r.direction * t;
+      ShadeRec {
+         t,
+         hit_point: r.origin + d,
+         error: (r.origin.abs() + d.abs()) * gamma(7),
+         normal: n,
+         geometric_normal: n,
+         uv: (0.0, 0.0),
+         dpdu,
+         dpdv,
+         front_face: dot(r.direction, n) < 0.0,
+         ray: r,
+         color,
+         object: 0,
+         material: None,
+      }
+   }
+
+   // Sets the geometric normal, keeping front_face in step.
+   pub fn set_geometric_normal(&mut self, n: Vector) {
+      self.geometric_normal = n;
+      self.front_face = dot(self.ray.direction, n) < 0.0;
+   }
+
+   pub fn set_surface(&mut self, uv: (Float, Float), dpdu: Vector, dpdv: Vector) {
+      self.uv = uv;
+      self.dpdu = dpdu;
+      self.dpdv = dpdv;
+   }
+
+   pub fn set_hit_point(&mut self, p: Vector, error: Vector) {
+      self.hit_point = p;
+      self.error = error;
+   }
+
+   // Ray leaving the hit point in direction w, starting clear of the
+   // surface so it needs no epsilon to miss it.
+   pub fn spawn_ray(&self, w: Vector) -> Ray {
+      Ray {
+         origin: offset_origin(self.hit_point, self.error, self.geometric_normal, w),
+         direction: w,
+         time: self.ray.time,
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Geometry, ShadeRec, Sphere, Triangle, Rectangle, Cuboid, Cylinder, Cone, Torus, Csg, Operation};
+   use aabb::Aabb;
+   use color::Color;
+   use ray::Ray;
+   use vector::{Vector, Float};
+   use toml::{Parser, Value};
+
+   fn parse(s: &str) -> Value {
+      Value::Table(Parser::new(s).parse().unwrap())
+   }
+
+   fn ray(o: (Float, Float, Float), d: (Float, Float, Float)) -> Ray {
+      Ray { origin: Vector::new(o.0, o.1, o.2), direction: Vector::new(d.0, d.1, d.2), time: 0.0 }
+   }
+
+   fn hit_distances(g: &dyn Geometry, r: Ray) -> Vec<Float> {
+      g.hits(r, 0.0, Float::INFINITY).iter().map(|sr| sr.t).collect()
+   }
+
+   fn assert_near(a: Vec<Float>, b: &[Float], tolerance: Float) {
+      assert_eq!(a.len(), b.len(), "{:?} != {:?}", a, b);
+      for (x, y) in a.iter().zip(b.iter()) {
+         assert!((x - y).abs() <= tolerance, "{:?} != {:?}", a, b);
+      }
+   }
+
+   // Only has the nearest hit query, so hits comes from the trait.
+   struct NearestOnly(Sphere);
+
+   impl Geometry for NearestOnly {
+      fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+         self.0.intersect(r, tmin, tmax)
+      }
+
+      fn bounds(&self) -> Aabb {
+         self.0.bounds()
+      }
+   }
+
+   #[test]
+   fn analytic_hits() {
+      let white = Color::new(1.0, 1.0, 1.0);
+      let sphere = Sphere::new(0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0);
+      assert_near(hit_distances(&sphere, ray((0.0, 0.0, -5.0), (0.0, 0.0, 1.0))), &[4.0, 6.0], 1e-5);
+      assert_near(hit_distances(&sphere, ray((0.0, 0.0, 0.0), (0.0, 0.0, 1.0))), &[1.0], 1e-5);
+
+      let cuboid = Cuboid::new(Vector::new(-1.0, -1.0, -1.0), Vector::new(1.0, 1.0, 1.0), white);
+      assert_near(hit_distances(&cuboid, ray((0.5, 0.5, 5.0), (0.0, 0.0, -1.0))), &[4.0, 6.0], 1e-5);
+
+      let cylinder = Cylinder::new(Vector::zero(), 2.0, 1.0, true, white);
+      assert_near(hit_distances(&cylinder, ray((0.0, -5.0, 0.0), (0.0, 1.0, 0.0))), &[5.0, 7.0], 1e-5);
+      assert_near(hit_distances(&cylinder, ray((-5.0, 1.0, 0.0), (1.0, 0.0, 0.0))), &[4.0, 6.0], 1e-5);
+
+      let cone = Cone::new(Vector::zero(), 2.0, 1.0, true, white);
+      assert_near(hit_distances(&cone, ray((-5.0, 1.0, 0.0), (1.0, 0.0, 0.0))), &[4.5, 5.5], 1e-5);
+      assert_near(hit_distances(&cone, ray((0.5, -5.0, 0.0), (0.0, 1.0, 0.0))), &[5.0, 6.0], 1e-5);
+
+      let torus = Torus::new(Vector::zero(), 2.0, 0.5, white);
+      assert_near(hit_distances(&torus, ray((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0))), &[2.5, 3.5, 6.5, 7.5], 1e-4);
+   }
+
+   #[test]
+   fn default_hits_step_past_close_hits() {
+      // both sides of a tiny sphere are found however near they are
+      let tiny = NearestOnly(Sphere::new(0.0, 0.0, 0.0, 1e-5, 1.0, 1.0, 1.0));
+      let h = hit_distances(&tiny, ray((0.0, 0.0, -1.0), (0.0, 0.0, 1.0)));
+      assert_eq!(h.len(), 2, "{:?}", h);
+      assert!(h[0] < 1.0 && h[1] > 1.0, "{:?}", h);
+   }
+
+   #[test]
+   fn csg_difference() {
+      let white = Color::new(1.0, 1.0, 1.0);
+      let cuboid = Cuboid::new(Vector::new(-1.0, -1.0, -1.0), Vector::new(1.0, 1.0, 1.0), white);
+      let hole = Sphere::new(0.0, 0.0, 0.0, 0.5, 1.0, 1.0, 1.0);
+      let csg = Csg::new(Operation::Difference, Box::new(cuboid), Box::new(hole));
+      let r = ray((0.0, 0.0, -5.0), (0.0, 0.0, 1.0));
+      let hits = csg.hits(r, 0.0, Float::INFINITY);
+      assert_near(hits.iter().map(|sr| sr.t).collect(), &[4.0, 4.5, 5.5, 6.0], 1e-5);
+      // the walls of the hole face into it
+      assert!(hits[1].normal.z > 0.0 && hits[2].normal.z < 0.0);
+      assert_near(vec![csg.intersect(r, 4.2, Float::INFINITY).unwrap().t], &[4.5], 1e-5);
+   }
+
+   #[test]
+   fn rectangle_sides_must_be_perpendicular() {
+      let square = "p0 = [0.0, 0.0, 0.0]\na = [2.0, 0.0, 0.0]\nb = [0.0, 0.0, 3.0]\ncolor = [1.0, 1.0, 1.0]";
+      assert!(Rectangle::import(&parse(square)).is_ok());
+      let skewed = square.replace("b = [0.0, 0.0, 3.0]", "b = [1.0, 0.0, 3.0]");
+      assert!(Rectangle::import(&parse(&skewed)).is_err());
+   }
+
+   #[test]
+   fn smooth_triangle_normals() {
+      let triangle = "v0 = [0.0, 0.0, 0.0]\nv1 = [1.0, 0.0, 0.0]\nv2 = [0.0, 1.0, 0.0]\ncolor = [1.0, 1.0, 1.0]\n\
+                      n0 = [0.0, 0.0, 1.0]\nn1 = [1.0, 0.0, 1.0]\nn2 = [0.0, 2.0, 2.0]";
+      let t = Triangle::import(&parse(triangle)).unwrap();
+      let sr = t.intersect(ray((0.25, 0.5, 1.0), (0.0, 0.0, -1.0)), 0.0, Float::INFINITY).unwrap();
+
+      // the corner normals, normalised, weighed by the barycentric
+      // coordinates of the hit (0.25, 0.25, 0.5)
+      let s = (0.5 as Float).sqrt();
+      let n = (Vector::new(0.0, 0.0, 1.0) * 0.25 + Vector::new(s, 0.0, s) * 0.25 + Vector::new(0.0, s, s) * 0.5).normalize();
+      assert!((sr.normal - n).magnitude() < 1e-5, "{:?}", sr.normal);
+      assert!((sr.geometric_normal - Vector::new(0.0, 0.0, 1.0)).magnitude() < 1e-6);
+
+      // and at a corner just that corner's normal
+      let sr = t.intersect(ray((1.0 - 1e-4, 0.0, 1.0), (0.0, 0.0, -1.0)), 0.0, Float::INFINITY).unwrap();
+      assert!((sr.normal - Vector::new(s, 0.0, s)).magnitude() < 1e-3, "{:?}", sr.normal);
+
+      // without vertex normals it's flat
+      let flat = Triangle::new(Vector::zero(), Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), Color::new(1.0, 1.0, 1.0));
+      let sr = flat.intersect(ray((0.25, 0.5, 1.0), (0.0, 0.0, -1.0)), 0.0, Float::INFINITY).unwrap();
+      assert!((sr.normal - Vector::new(0.0, 0.0, 1.0)).magnitude() < 1e-6);
+   }
 }
@@ -0,0 +1,234 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Uniform grid walked with a 3D DDA (Amanatides and Woo). The resolution
+// gives about density primitives per cell for evenly spread scenes. With
+// more than one level, crowded cells hold a grid of their own, which copes
+// better with scenes where detail is concentrated in a few places.
+
+use vector::{Vector, Float};
+use aabb::Aabb;
+use ray::Ray;
+use accelerator::{Accelerator, component, inverse};
+
+const MAX_RESOLUTION: usize = 128;
+// cells holding more than this are refined when levels remain
+const MAX_CELL: usize = 8;
+
+enum Cell {
+   Leaf(Vec<usize>),
+   Nested(Box<Grid>),
+}
+
+pub struct Grid {
+   bounds: Aabb,
+   res: [usize; 3],
+   cell_size: [Float; 3],
+   cells: Vec<Cell>,
+   unbounded: Vec<usize>,
+}
+
+impl Grid {
+   pub fn new(bounds: &[Aabb], density: Float, levels: usize) -> Grid {
+      let (finite, unbounded): (Vec<usize>, Vec<usize>) = (0 .. bounds.len()).partition(|&i| bounds[i].is_finite());
+      let region = finite.iter().fold(Aabb::empty(), |b, &i| b.union(&bounds[i]));
+
+      let mut grid = Grid::build(bounds, finite, region, density, levels);
+      grid.unbounded = unbounded;
+      grid
+   }
+
+   fn build(bounds: &[Aabb], items: Vec<usize>, region: Aabb, density: Float, levels: usize) -> Grid {
+      let mut grid = Grid {
+         bounds: region,
+         res: [1; 3],
+         cell_size: [0.0; 3],
+         cells: Vec::new(),
+         unbounded: Vec::new(),
+      };
+      if items.is_empty() {
+         return grid;
+      }
+
+      // flat regions still get some thickness so the cell sizes are usable
+      let d = region.max - region.min;
+      let largest = d.x.max(d.y).max(d.z).max(1e-4);
+      let extent = [d.x.max(largest * 1e-3), d.y.max(largest * 1e-3), d.z.max(largest * 1e-3)];
+      let volume = extent[0] * extent[1] * extent[2];
+      let cells_per_unit = (density * items.len() as Float / volume).cbrt();
+      for (axis, &e) in extent.iter().enumerate() {
+         grid.res[axis] = ((e * cells_per_unit).round() as usize).clamp(1, MAX_RESOLUTION);
+         grid.cell_size[axis] = e / grid.res[axis] as Float;
+      }
+
+      let mut lists = vec![Vec::new(); grid.res[0] * grid.res[1] * grid.res[2]];
+      for &i in items.iter() {
+         let lo = grid.cell_of(bounds[i].min);
+         let hi = grid.cell_of(bounds[i].max);
+         for z in lo[2] .. hi[2] + 1 {
+            for y in lo[1] .. hi[1] + 1 {
+               for x in lo[0] .. hi[0] + 1 {
+                  lists[grid.index([x, y, z])].push(i);
+               }
+            }
+         }
+      }
+
+      for (c, list) in lists.into_iter().enumerate() {
+         // a cell holding everything would only refine into itself
+         let cell = if levels > 1 && list.len() > MAX_CELL && list.len() < items.len() {
+            let region = grid.cell_bounds(c).intersection(&list.iter().fold(Aabb::empty(), |b, &i| b.union(&bounds[i])));
+            Cell::Nested(Box::new(Grid::build(bounds, list, region, density, levels - 1)))
+         } else {
+            Cell::Leaf(list)
+         };
+         grid.cells.push(cell);
+      }
+
+      grid
+   }
+
+   fn cell_of(&self, p: Vector) -> [usize; 3] {
+      let mut c = [0; 3];
+      for (axis, ca) in c.iter_mut().enumerate() {
+         let x = (component(p, axis) - component(self.bounds.min, axis)) / self.cell_size[axis];
+         *ca = if x > 0.0 { (x as usize).min(self.res[axis] - 1) } else { 0 };
+      }
+      c
+   }
+
+   fn index(&self, c: [usize; 3]) -> usize {
+      c[0] + self.res[0] * (c[1] + self.res[1] * c[2])
+   }
+
+   fn cell_bounds(&self, index: usize) -> Aabb {
+      let c = [index % self.res[0], (index / self.res[0]) % self.res[1], index / (self.res[0] * self.res[1])];
+      let lo = |axis: usize| component(self.bounds.min, axis) + c[axis] as Float * self.cell_size[axis];
+      let min = Vector::new(lo(0), lo(1), lo(2));
+      Aabb::new(min, min + Vector::new(self.cell_size[0], self.cell_size[1], self.cell_size[2]))
+   }
+
+   // Calls visit with each cell the ray passes through between tmin and
+   // tmax in order. visit returns the current tmax, which ends the walk
+   // once the ray has left the cell holding that hit, or None to stop.
+   fn walk<F>(&self, r: &Ray, inv_dir: Vector, tmin: Float, tmax: Float, mut visit: F)
+      where F: FnMut(&Cell) -> Option<Float> {
+      if self.cells.is_empty() {
+         return;
+      }
+      let (t0, _) = match self.bounds.hit(r, inv_dir, tmin, tmax) {
+         Some(h) => h,
+         None => return,
+      };
+
+      let mut cell = self.cell_of(r.origin + r.direction * t0);
+      let mut step = [0isize; 3];
+      let mut next = [Float::INFINITY; 3];
+      let mut delta = [Float::INFINITY; 3];
+      for axis in 0 .. 3 {
+         let o = component(r.origin, axis);
+         let inv = component(inv_dir, axis);
+         let lo = component(self.bounds.min, axis) + cell[axis] as Float * self.cell_size[axis];
+         if component(r.direction, axis) > 0.0 {
+            step[axis] = 1;
+            next[axis] = (lo + self.cell_size[axis] - o) * inv;
+            delta[axis] = self.cell_size[axis] * inv;
+         } else if component(r.direction, axis) < 0.0 {
+            step[axis] = -1;
+            next[axis] = (lo - o) * inv;
+            delta[axis] = -self.cell_size[axis] * inv;
+         }
+      }
+
+      loop {
+         let axis = if next[0] < next[1] {
+            if next[0] < next[2] { 0 } else { 2 }
+         } else {
+            if next[1] < next[2] { 1 } else { 2 }
+         };
+         let exit = next[axis];
+
+         let tmax = match visit(&self.cells[self.index(cell)]) {
+            Some(t) => t,
+            None => return,
+         };
+         if tmax <= exit {
+            return;
+         }
+
+         let c = cell[axis] as isize + step[axis];
+         if c < 0 || c >= self.res[axis] as isize {
+            return;
+         }
+         cell[axis] = c as usize;
+         next[axis] += delta[axis];
+      }
+   }
+
+   fn closest_from(&self, r: &Ray, inv_dir: Vector, tmin: Float, tmax: Float,
+                   test: &mut dyn FnMut(usize, Float) -> Option<Float>) -> Float {
+      let mut tmax = tmax;
+      self.walk(r, inv_dir, tmin, tmax, |cell| {
+         match *cell {
+            Cell::Leaf(ref items) => {
+               for &i in items.iter() {
+                  if let Some(t) = test(i, tmax) {
+                     tmax = t;
+                  }
+               }
+            }
+            Cell::Nested(ref g) => tmax = g.closest_from(r, inv_dir, tmin, tmax, test),
+         }
+         Some(tmax)
+      });
+      tmax
+   }
+
+   fn any_from(&self, r: &Ray, inv_dir: Vector, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize) -> bool) -> bool {
+      let mut found = false;
+      self.walk(r, inv_dir, tmin, tmax, |cell| {
+         found = match *cell {
+            Cell::Leaf(ref items) => items.iter().any(|&i| test(i)),
+            Cell::Nested(ref g) => g.any_from(r, inv_dir, tmin, tmax, test),
+         };
+         if found { None } else { Some(tmax) }
+      });
+      found
+   }
+}
+
+impl Accelerator for Grid {
+   fn bounds(&self) -> Aabb {
+      if !self.unbounded.is_empty() {
+         Aabb::infinite()
+      } else {
+         self.bounds
+      }
+   }
+
+   fn closest(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize, Float) -> Option<Float>) {
+      let mut tmax = tmax;
+      for &i in self.unbounded.iter() {
+         if let Some(t) = test(i, tmax) {
+            tmax = t;
+         }
+      }
+      self.closest_from(r, inverse(r.direction), tmin, tmax, test);
+   }
+
+   fn any(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize) -> bool) -> bool {
+      self.unbounded.iter().any(|&i| test(i)) || self.any_from(r, inverse(r.direction), tmin, tmax, test)
+   }
+}
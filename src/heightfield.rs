@@ -0,0 +1,508 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Terrain from a regular grid of heights. Each cell between four samples
+// is split into two triangles, but they're only made when a ray reaches
+// the cell: rays walk the grid in two levels, first over blocks of cells
+// that are skipped when the ray passes above or below their range of
+// heights, then over the cells of the blocks it can hit.
+//
+// Heights are read from binary or ascii PGM images, scaled to 0 to 1, or
+// from raw little endian 32 bit floats. Image columns run along x and rows
+// along z, starting at the minimum corner.
+
+use std::fs::File;
+use std::path::Path;
+use std::io::Read;
+use vector::{Vector, cross, Float};
+use ray::Ray;
+use color::Color;
+use aabb::Aabb;
+use accelerator::inverse;
+use geometry::{Geometry, ShadeRec, intersect_triangle, triangle_point};
+use mesh::import_color;
+use toml::Value;
+
+// cells along each side of a block
+const BLOCK: usize = 16;
+
+pub struct Heightfield {
+   // number of samples along x and z
+   nx: usize,
+   nz: usize,
+   // world space heights, row by row
+   heights: Vec<Float>,
+   min: Vector,
+   // spacing of the samples along x and z
+   dx: Float,
+   dz: Float,
+   // lowest and highest height in each block
+   blocks: Vec<(Float, Float)>,
+   bx: usize,
+   bounds: Aabb,
+   color: Color,
+}
+
+impl Heightfield {
+   // Heights in data, nx by nz of them, are scaled by size.y and raised
+   // by min.y, and spread over size.x by size.z.
+   pub fn new(nx: usize, nz: usize, data: Vec<Float>, min: Vector, size: Vector, color: Color) -> Heightfield {
+      let heights: Vec<Float> = data.iter().map(|h| min.y + h * size.y).collect();
+      let (cx, cz) = (nx - 1, nz - 1);
+      let (bx, bz) = (cx.div_ceil(BLOCK), cz.div_ceil(BLOCK));
+
+      let mut blocks = Vec::with_capacity(bx * bz);
+      for bj in 0 .. bz {
+         for bi in 0 .. bx {
+            let mut range = (Float::INFINITY, Float::NEG_INFINITY);
+            for j in bj * BLOCK .. ((bj + 1) * BLOCK).min(cz) + 1 {
+               for i in bi * BLOCK .. ((bi + 1) * BLOCK).min(cx) + 1 {
+                  let h = heights[j * nx + i];
+                  range = (range.0.min(h), range.1.max(h));
+               }
+            }
+            blocks.push(range);
+         }
+      }
+
+      let low = blocks.iter().fold(Float::INFINITY, |m, b| m.min(b.0));
+      let high = blocks.iter().fold(Float::NEG_INFINITY, |m, b| m.max(b.1));
+      let bounds = Aabb::new(Vector::new(min.x, low, min.z), Vector::new(min.x + size.x, high, min.z + size.z));
+
+      Heightfield {
+         nx,
+         nz,
+         heights,
+         min,
+         dx: size.x / cx as Float,
+         dz: size.z / cz as Float,
+         blocks,
+         bx,
+         bounds,
+         color,
+      }
+   }
+
+   pub fn import(obj: &Value, dir: &Path) -> Result<Heightfield, String> {
+      let file = obj.lookup("file").and_then(|v| v.as_str()).ok_or("Missing file.")?;
+      let elements = vec!["min.0", "min.1", "min.2", "size.0", "size.1", "size.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let path = dir.join(file);
+      let (nx, nz, data) = match path.extension().and_then(|e| e.to_str()) {
+         Some("pgm") => load_pgm(&path),
+         Some("raw") => {
+            // raw data has no header, so needs its size given
+            let w = obj.lookup("resolution.0").and_then(|v| v.as_integer()).ok_or("Invalid integer.")?;
+            let h = obj.lookup("resolution.1").and_then(|v| v.as_integer()).ok_or("Invalid integer.")?;
+            if w < 2 || h < 2 || w > u32::MAX as i64 || h > u32::MAX as i64 {
+               return Err(format!("Invalid resolution {} x {}.", w, h));
+            }
+            load_raw(&path, w as usize, h as usize).map(|d| (w as usize, h as usize, d))
+         }
+         _ => Err(format!("Unsupported heightfield format {}.", path.display())),
+      }?;
+      if nx < 2 || nz < 2 {
+         return Err("Heightfield needs at least 2 x 2 samples.".to_string());
+      }
+
+      Ok(Heightfield::new(nx, nz, data,
+                          Vector::new(values[0], values[1], values[2]),
+                          Vector::new(values[3], values[4], values[5]),
+                          import_color(obj)?))
+   }
+
+   // The ray in grid space, in units of cells from the minimum corner
+   // along x and z.
+   fn to_grid(&self, r: Ray) -> Ray {
+      Ray {
+         origin: Vector::new((r.origin.x - self.min.x) / self.dx, r.origin.y, (r.origin.z - self.min.z) / self.dz),
+         direction: Vector::new(r.direction.x / self.dx, r.direction.y, r.direction.z / self.dz),
+         time: r.time,
+      }
+   }
+
+   // Sample i, j in grid space, where cells are a unit across.
+   fn point(&self, i: usize, j: usize) -> Vector {
+      Vector::new(i as Float, self.heights[j * self.nx + i], j as Float)
+   }
+
+   // Vertex normal from central differences, one sided at the edges.
+   fn normal(&self, i: usize, j: usize) -> Vector {
+      let h = |i: usize, j: usize| self.heights[j * self.nx + i];
+      let (i0, i1) = (if i > 0 { i - 1 } else { i }, (i + 1).min(self.nx - 1));
+      let (j0, j1) = (if j > 0 { j - 1 } else { j }, (j + 1).min(self.nz - 1));
+      let sx = (h(i1, j) - h(i0, j)) / ((i1 - i0) as Float * self.dx);
+      let sz = (h(i, j1) - h(i, j0)) / ((j1 - j0) as Float * self.dz);
+      Vector::new(-sx, 1.0, -sz).normalize()
+   }
+
+   // The two triangles of cell i, j, found with the ray in grid space where
+   // the cells are unit squares. Distances along the ray are the same in
+   // both spaces.
+   fn intersect_cell(&self, r: Ray, local: Ray, i: usize, j: usize, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let corners = [(i, j), (i, j + 1), (i + 1, j), (i + 1, j + 1)];
+      let mut hit = None;
+      let mut tmax = tmax;
+      for tri in [[0, 1, 2], [2, 1, 3]].iter() {
+         let (a, b, c) = (corners[tri[0]], corners[tri[1]], corners[tri[2]]);
+         let (pa, pb, pc) = (self.point(a.0, a.1), self.point(b.0, b.1), self.point(c.0, c.1));
+         if let Some((t, u, v)) = intersect_triangle(local, pa, pb, pc, tmin, tmax) {
+            let n = self.normal(a.0, a.1) * (1.0 - u - v) + self.normal(b.0, b.1) * u + self.normal(c.0, c.1) * v;
+            let mut sr = ShadeRec::new(r, t, n.normalize(), self.color);
+
+            // back in world space the face's slope gives the tangents along
+            // uv, which span the whole heightfield
+            let scale = |p: Vector| Vector::new(p.x * self.dx, p.y, p.z * self.dz);
+            let world = |p: Vector| scale(p) + Vector::new(self.min.x, 0.0, self.min.z);
+            let (p, error) = triangle_point(world(pa), world(pb), world(pc), u, v);
+            sr.set_hit_point(p, error);
+            let face = cross(scale(pb - pa), scale(pc - pa)).normalize();
+            let (sx, sz) = (self.dx * (self.nx - 1) as Float, self.dz * (self.nz - 1) as Float);
+            let g = pa * (1.0 - u - v) + pb * u + pc * v;
+            sr.set_geometric_normal(face);
+            sr.set_surface((g.x / (self.nx - 1) as Float, g.z / (self.nz - 1) as Float),
+                           Vector::new(sx, -face.x / face.y * sx, 0.0),
+                           Vector::new(0.0, -face.z / face.y * sz, sz));
+            tmax = t;
+            hit = Some(sr);
+         }
+      }
+      hit
+   }
+}
+
+impl Geometry for Heightfield {
+   fn bounds(&self) -> Aabb {
+      self.bounds
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let (t0, t1) = self.bounds.hit(&r, inverse(r.direction), tmin, tmax)?;
+
+      let local = self.to_grid(r);
+      let o = (local.origin.x, local.origin.z);
+      let d = (local.direction.x, local.direction.z);
+      let y = |t: Float| r.origin.y + r.direction.y * t;
+      let overlaps = |ta: Float, tb: Float, range: (Float, Float)| y(ta).min(y(tb)) <= range.1 && y(ta).max(y(tb)) >= range.0;
+
+      let (cx, cz) = (self.nx - 1, self.nz - 1);
+      let b = BLOCK as Float;
+      let mut hit = None;
+      walk((o.0 / b, o.1 / b), (d.0 / b, d.1 / b), t0, t1, (self.bx, self.blocks.len() / self.bx), |bi, bj, ta, tb| {
+         if !overlaps(ta, tb, self.blocks[bj * self.bx + bi]) {
+            return false;
+         }
+         let (i0, j0) = (bi * BLOCK, bj * BLOCK);
+         let n = (BLOCK.min(cx - i0), BLOCK.min(cz - j0));
+         walk((o.0 - i0 as Float, o.1 - j0 as Float), d, ta, tb, n, |i, j, ta, tb| {
+            let (i, j) = (i0 + i, j0 + j);
+            let h = [self.heights[j * self.nx + i], self.heights[j * self.nx + i + 1],
+                     self.heights[(j + 1) * self.nx + i], self.heights[(j + 1) * self.nx + i + 1]];
+            let range = (h[0].min(h[1]).min(h[2]).min(h[3]), h[0].max(h[1]).max(h[2]).max(h[3]));
+            if !overlaps(ta, tb, range) {
+               return false;
+            }
+            // cells are visited front to back, so the first hit is closest
+            hit = self.intersect_cell(r, local, i, j, tmin, tmax);
+            hit.is_some()
+         })
+      });
+      hit
+   }
+}
+
+// Walks the unit cells of an n.0 by n.1 grid that the ray o + t d crosses
+// between t0 and t1, front to back. visit gets each cell with the span of
+// t inside it, and stops the walk by returning true.
+fn walk<F>(o: (Float, Float), d: (Float, Float), t0: Float, t1: Float, n: (usize, usize), mut visit: F) -> bool
+   where F: FnMut(usize, usize, Float, Float) -> bool {
+   let cell = |p: Float, n: usize| (p.floor().max(0.0) as usize).min(n - 1) as isize;
+   let mut i = cell(o.0 + d.0 * t0, n.0);
+   let mut j = cell(o.1 + d.1 * t0, n.1);
+
+   let axis = |o: Float, d: Float, c: isize| {
+      if d > 0.0 {
+         (1, ((c + 1) as Float - o) / d, 1.0 / d)
+      } else if d < 0.0 {
+         (-1, (c as Float - o) / d, -1.0 / d)
+      } else {
+         (0, Float::INFINITY, Float::INFINITY)
+      }
+   };
+   let (step_i, mut next_i, delta_i) = axis(o.0, d.0, i);
+   let (step_j, mut next_j, delta_j) = axis(o.1, d.1, j);
+
+   let mut t = t0;
+   loop {
+      let exit = next_i.min(next_j).min(t1);
+      if visit(i as usize, j as usize, t, exit) {
+         return true;
+      }
+      if exit >= t1 {
+         return false;
+      }
+      if next_i < next_j {
+         i += step_i;
+         t = next_i;
+         next_i += delta_i;
+      } else {
+         j += step_j;
+         t = next_j;
+         next_j += delta_j;
+      }
+      if i < 0 || j < 0 || i >= n.0 as isize || j >= n.1 as isize {
+         return false;
+      }
+   }
+}
+
+// Bytes taken by w by h samples, which may come from a file's header.
+fn data_size(w: usize, h: usize, bytes: usize) -> Result<usize, String> {
+   w.checked_mul(h).and_then(|n| n.checked_mul(bytes)).ok_or(format!("Invalid resolution {} x {}.", w, h))
+}
+
+fn read_bytes(path: &Path) -> Result<Vec<u8>, String> {
+   let mut fin = File::open(path).map_err(|e| format!("{} - {}", path.display(), e))?;
+   let mut data = Vec::new();
+   fin.read_to_end(&mut data).map_err(|e| format!("{} - {}", path.display(), e))?;
+   Ok(data)
+}
+
+// Binary (P5) or ascii (P2) greymap with 8 or 16 bit samples.
+pub fn load_pgm(path: &Path) -> Result<(usize, usize, Vec<Float>), String> {
+   let data = read_bytes(path)?;
+
+   // the header is four whitespace separated values, with comments
+   // running from # to the end of a line
+   let mut fields = Vec::new();
+   let mut pos = 0;
+   while fields.len() < 4 {
+      while pos < data.len() && (data[pos] as char).is_whitespace() {
+         pos += 1;
+      }
+      if pos < data.len() && data[pos] == b'#' {
+         while pos < data.len() && data[pos] != b'\n' {
+            pos += 1;
+         }
+         continue;
+      }
+      let start = pos;
+      while pos < data.len() && !(data[pos] as char).is_whitespace() {
+         pos += 1;
+      }
+      if start == pos {
+         return Err("Truncated header.".to_string());
+      }
+      fields.push(String::from_utf8_lossy(&data[start .. pos]).into_owned());
+   }
+   // a single whitespace character separates the header from binary data
+   pos += 1;
+
+   let number = |s: &str| s.parse::<usize>().map_err(|_| format!("Invalid number {}.", s));
+   let (w, h, max) = (number(&fields[1])?, number(&fields[2])?, number(&fields[3])?);
+   if max == 0 || max > 65535 {
+      return Err(format!("Invalid maximum value {}.", max));
+   }
+   let scale = 1.0 / max as Float;
+
+   match &fields[0][..] {
+      "P5" => {
+         let bytes = if max < 256 { 1 } else { 2 };
+         let size = data_size(w, h, bytes)?;
+         if data.len() - pos.min(data.len()) < size {
+            return Err("Truncated image data.".to_string());
+         }
+         let samples = &data[pos .. pos + size];
+         let values = if bytes == 1 {
+            samples.iter().map(|&b| b as Float * scale).collect()
+         } else {
+            samples.chunks(2).map(|b| ((b[0] as u32) << 8 | b[1] as u32) as Float * scale).collect()
+         };
+         Ok((w, h, values))
+      }
+      "P2" => {
+         let n = data_size(w, h, 1)?;
+         let text = String::from_utf8_lossy(&data[pos.min(data.len()) ..]).into_owned();
+         let mut values = Vec::new();
+         for t in text.split_whitespace().take(n) {
+            values.push(number(t)? as Float * scale);
+         }
+         if values.len() < n {
+            return Err("Truncated image data.".to_string());
+         }
+         Ok((w, h, values))
+      }
+      m => Err(format!("Unsupported PGM type {}.", m)),
+   }
+}
+
+pub fn load_raw(path: &Path, w: usize, h: usize) -> Result<Vec<Float>, String> {
+   let size = data_size(w, h, 4)?;
+   let data = read_bytes(path)?;
+   if data.len() < size {
+      return Err("Truncated raw data.".to_string());
+   }
+   Ok(data.chunks(4).take(w * h).map(|b| {
+      f32::from_bits((b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24) as Float
+   }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Heightfield, load_pgm, load_raw};
+   use std::path::Path;
+   use vector::{Vector, Float};
+   use ray::Ray;
+   use color::Color;
+   use geometry::Geometry;
+   use toml::{Parser, Value};
+   use testfile::write;
+
+   // Rolling terrain over 3 by 2 blocks, the last of each row and column
+   // only partly filled.
+   fn terrain() -> Heightfield {
+      let (nx, nz) = (41, 30);
+      let mut data = Vec::new();
+      for j in 0 .. nz {
+         for i in 0 .. nx {
+            data.push(((i as Float * 0.4).sin() * (j as Float * 0.3).cos() + 1.0) * 0.5);
+         }
+      }
+      Heightfield::new(nx, nz, data, Vector::new(-2.0, -0.5, 1.0), Vector::new(8.0, 1.5, 5.8), Color::new(1.0, 1.0, 1.0))
+   }
+
+   // The nearest hit among every triangle of the grid.
+   fn brute_force(hf: &Heightfield, r: Ray, tmin: Float, tmax: Float) -> Option<Float> {
+      let local = hf.to_grid(r);
+      let mut nearest = None;
+      let mut tmax = tmax;
+      for j in 0 .. hf.nz - 1 {
+         for i in 0 .. hf.nx - 1 {
+            if let Some(sr) = hf.intersect_cell(r, local, i, j, tmin, tmax) {
+               tmax = sr.t;
+               nearest = Some(sr.t);
+            }
+         }
+      }
+      nearest
+   }
+
+   fn assert_walk_finds(hf: &Heightfield, r: Ray, tmin: Float) {
+      let walked = hf.intersect(r, tmin, Float::INFINITY).map(|sr| sr.t);
+      let all = brute_force(hf, r, tmin, Float::INFINITY);
+      match (walked, all) {
+         (Some(a), Some(b)) => assert!((a - b).abs() <= 1e-4 * b.max(1.0), "{:?}: {} != {}", r, a, b),
+         (None, None) => {},
+         _ => panic!("{:?}: {:?} != {:?}", r, walked, all),
+      }
+   }
+
+   #[test]
+   fn walk_finds_the_nearest_triangle() {
+      let hf = terrain();
+      let (dx, dz) = (hf.dx, hf.dz);
+      let mut seed = 4321u32;
+      let mut next = || {
+         seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+         (seed >> 8) as Float / (1 << 24) as Float
+      };
+      let ray = |o: Vector, d: Vector| Ray { origin: o, direction: d.normalize(), time: 0.0 };
+
+      for n in 0 .. 1200 {
+         let inside = Vector::new(-2.0 + 8.0 * next(), -0.5 + 1.5 * next(), 1.0 + 5.8 * next());
+         let d = Vector::new(next() - 0.5, next() - 0.5, next() - 0.5);
+         let r = match n % 6 {
+            // from above, looking down across the terrain
+            0 => ray(Vector::new(-4.0 + 12.0 * next(), 3.0, -1.0 + 10.0 * next()), inside - Vector::new(0.0, 3.0, 0.0)),
+            // starting inside the bounds, in any direction
+            1 => ray(inside, d),
+            // along lines between cells, in x and in z
+            2 => ray(Vector::new(-2.0 + (n % 41) as Float * dx, 2.0, -1.0), Vector::new(0.0, -0.3 - next(), 1.0)),
+            3 => ray(Vector::new(-3.0, 2.0, 1.0 + (n % 30) as Float * dz), Vector::new(1.0, -0.3 - next(), 0.0)),
+            // along the diagonals splitting cells into triangles
+            4 => ray(Vector::new(-2.0 - dx * 3.0, 1.5, 1.0 + ((n % 30) as Float + 3.0) * dz),
+                     Vector::new(dx, -0.05 - 0.1 * next(), -dz)),
+            // level, just clear of or grazing the ground
+            _ => ray(Vector::new(inside.x, -0.5 + 1.5 * next(), -1.0), Vector::new(next() - 0.5, 0.0, 1.0)),
+         };
+         assert_walk_finds(&hf, r, 0.0);
+         assert_walk_finds(&hf, r, next() * 2.0);
+      }
+   }
+
+   #[test]
+   fn rejects_bad_raw_resolutions() {
+      let parse = |s: &str| Value::Table(Parser::new(s).parse().unwrap());
+      for res in ["[-1, 4]", "[4, 1]", "[4294967296, 4294967296]"].iter() {
+         let obj = parse(&format!("file = \"none.raw\"\nresolution = {}\nmin = [0.0, 0.0, 0.0]\nsize = [1.0, 1.0, 1.0]\n\
+                                   color = [1.0, 1.0, 1.0]", res));
+         let err = Heightfield::import(&obj, Path::new(".")).err().unwrap();
+         assert!(err.contains("resolution"), "{}", err);
+      }
+      let err = load_raw(Path::new("none.raw"), usize::MAX / 2, 3).err().unwrap();
+      assert!(err.contains("resolution"), "{}", err);
+   }
+
+   #[test]
+   fn ascii_pgm() {
+      let path = write("heightfield_ascii.pgm", b"P2\n# a comment\n3 2\n# another\n4\n0 1 2\n3 4 4\n");
+      assert_eq!(load_pgm(&path), Ok((3, 2, vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0])));
+   }
+
+   #[test]
+   fn binary_pgm() {
+      // the 8 bit data starts with a whitespace byte, which must not be
+      // skipped as part of the header
+      let path = write("heightfield_8.pgm", b"P5 2 2 255\n\x20\x00\xff\x33");
+      let (w, h, values) = load_pgm(&path).unwrap();
+      assert_eq!((w, h), (2, 2));
+      let s = 1.0 / 255.0;
+      assert_eq!(values, vec![32.0 * s, 0.0, 1.0, 51.0 * s]);
+
+      // 16 bit samples are big-endian
+      let path = write("heightfield_16.pgm", b"P5\n2 1\n65535\n\x80\x00\xff\xff");
+      assert_eq!(load_pgm(&path), Ok((2, 1, vec![32768.0 * (1.0 / 65535.0), 1.0])));
+   }
+
+   #[test]
+   fn rejects_bad_pgm() {
+      let truncated = write("heightfield_truncated.pgm", b"P5 2 2 255\n\x00\x01\x02");
+      assert!(load_pgm(&truncated).is_err());
+      let short_header = write("heightfield_header.pgm", b"P2 2 2");
+      assert!(load_pgm(&short_header).is_err());
+      let max = write("heightfield_max.pgm", b"P2 1 1 0\n0\n");
+      assert!(load_pgm(&max).is_err());
+      let pixmap = write("heightfield_ppm.pgm", b"P6 1 1 255\n\x00\x00\x00");
+      assert!(load_pgm(&pixmap).is_err());
+   }
+
+   #[test]
+   fn raw_floats() {
+      let mut data = Vec::new();
+      for &x in [0.5f32, -2.0, 8.25].iter() {
+         let b = x.to_bits();
+         data.extend_from_slice(&[b as u8, (b >> 8) as u8, (b >> 16) as u8, (b >> 24) as u8]);
+      }
+      let path = write("heightfield.raw", &data);
+      assert_eq!(load_raw(&path, 3, 1), Ok(vec![0.5, -2.0, 8.25]));
+      assert!(load_raw(&path, 2, 2).is_err());
+   }
+}
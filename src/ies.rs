@@ -0,0 +1,235 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::path::Path;
+use std::io::Read;
+use vector::{Vector, Float};
+use vector::float::consts;
+
+// IES LM-63 photometric profile, type C only. Candela values are normalised
+// so the brightest direction has an intensity of 1.
+//
+// Vertical angles are measured from straight down (-y). Horizontal angles
+// run from +x towards +z.
+#[derive(Debug,Clone)]
+pub struct IesProfile {
+   vertical: Vec<Float>,
+   horizontal: Vec<Float>,
+   candela: Vec<Float>, // horizontal major, vertical.len() values per angle
+}
+
+impl IesProfile {
+   pub fn load(path: &Path) -> Result<IesProfile, String> {
+      let mut fin = File::open(path).map_err(|e| e.to_string())?;
+      let mut data = String::new();
+      fin.read_to_string(&mut data).map_err(|e| e.to_string())?;
+      IesProfile::parse(&data)
+   }
+
+   pub fn parse(data: &str) -> Result<IesProfile, String> {
+      // keywords and free form header lines run up to the TILT line
+      let mut lines = data.lines();
+      let tilt = loop {
+         match lines.next() {
+            Some(l) if l.trim().starts_with("TILT=") => break l.trim()[5 ..].to_string(),
+            Some(_) => {},
+            None => return Err("Missing TILT line.".to_string()),
+         }
+      };
+
+      let rest: Vec<&str> = lines.collect();
+      let mut values = Vec::new();
+      for tok in rest.iter().flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ',')) {
+         if !tok.is_empty() {
+            values.push(tok.parse::<Float>().map_err(|_| format!("Invalid number {}.", tok))?);
+         }
+      }
+      let mut values = values.into_iter();
+
+      match tilt.as_str() {
+         "NONE" => {},
+         "INCLUDE" => {
+            // lamp to luminaire geometry, then angle and multiplier pairs
+            values.next().ok_or("Truncated tilt data.")?;
+            let n = values.next().ok_or("Truncated tilt data.")? as usize;
+            for _ in 0 .. 2 * n {
+               values.next().ok_or("Truncated tilt data.")?;
+            }
+         }
+         _ => return Err(format!("Unsupported TILT={}.", tilt)),
+      }
+
+      let mut header = Vec::new();
+      for _ in 0 .. 13 {
+         header.push(values.next().ok_or("Truncated photometric data.")?);
+      }
+      let multiplier = header[2];
+      let n_vertical = header[3] as usize;
+      let n_horizontal = header[4] as usize;
+      if header[5] as i32 != 1 {
+         return Err("Only type C photometry is supported.".to_string());
+      }
+      if n_vertical == 0 || n_horizontal == 0 {
+         return Err("Empty candela table.".to_string());
+      }
+
+      let vertical: Vec<Float> = values.by_ref().take(n_vertical).collect();
+      let horizontal: Vec<Float> = values.by_ref().take(n_horizontal).collect();
+      let mut candela: Vec<Float> = values.by_ref().take(n_vertical * n_horizontal)
+         .map(|c| c * multiplier).collect();
+      if candela.len() != n_vertical * n_horizontal {
+         return Err("Truncated candela values.".to_string());
+      }
+
+      let max = candela.iter().cloned().fold(0.0, Float::max);
+      if max > 0.0 {
+         for c in candela.iter_mut() {
+            *c /= max;
+         }
+      }
+
+      Ok(IesProfile { vertical, horizontal, candela })
+   }
+
+   // Relative intensity for light leaving the luminaire along d.
+   pub fn intensity(&self, d: Vector) -> Float {
+      let d = d.normalize();
+      let theta = (-d.y).clamp(-1.0, 1.0).acos().to_degrees();
+      let mut phi = d.z.atan2(d.x).to_degrees();
+      if phi < 0.0 {
+         phi += 360.0;
+      }
+
+      // fold phi into the range covered by the file's symmetry
+      let last = self.horizontal[self.horizontal.len() - 1];
+      let phi = if last <= 0.0 {
+         0.0
+      } else if last <= 90.0 {
+         let p = phi % 180.0;
+         if p > 90.0 { 180.0 - p } else { p }
+      } else if last <= 180.0 {
+         if phi > 180.0 { 360.0 - phi } else { phi }
+      } else {
+         phi
+      };
+
+      // no light leaves outside the measured vertical range
+      let (first, last) = (self.vertical[0], self.vertical[self.vertical.len() - 1]);
+      if theta < first - 0.001 || theta > last + 0.001 {
+         return 0.0;
+      }
+
+      let (h0, h1, fh) = bracket(&self.horizontal, phi);
+      let (v0, v1, fv) = bracket(&self.vertical, theta);
+      let n = self.vertical.len();
+      let c = |h: usize, v: usize| self.candela[h * n + v];
+
+      let a = c(h0, v0) * (1.0 - fv) + c(h0, v1) * fv;
+      let b = c(h1, v0) * (1.0 - fv) + c(h1, v1) * fv;
+      a * (1.0 - fh) + b * fh
+   }
+
+   // Relative intensity averaged over every direction, found from evenly
+   // spread directions, so 1 for a light shining equally all round.
+   pub fn mean_intensity(&self) -> Float {
+      let (nz, nphi) = (90, 180);
+      let mut sum = 0.0;
+      for i in 0 .. nz {
+         let y = 1.0 - 2.0 * (i as Float + 0.5) / nz as Float;
+         let r = (1.0 - y * y).sqrt();
+         for j in 0 .. nphi {
+            let phi = 2.0 * consts::PI * (j as Float + 0.5) / nphi as Float;
+            sum += self.intensity(Vector::new(r * phi.cos(), y, r * phi.sin()));
+         }
+      }
+      sum / (nz * nphi) as Float
+   }
+}
+
+// Neighbouring indices and interpolation weight for x in sorted angles.
+// Values outside the table clamp to the end angles.
+fn bracket(angles: &[Float], x: Float) -> (usize, usize, Float) {
+   let last = angles.len() - 1;
+   if x <= angles[0] {
+      return (0, 0, 0.0);
+   }
+   if x >= angles[last] {
+      return (last, last, 0.0);
+   }
+   let i = angles.iter().position(|&a| a > x).unwrap_or(last) - 1;
+   let span = angles[i + 1] - angles[i];
+   let f = if span > 0.0 { (x - angles[i]) / span } else { 0.0 };
+   (i, i + 1, f)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{IesProfile, bracket};
+   use vector::Vector;
+   use vector::float::consts;
+
+   const SPOT: &str = "IESNA:LM-63-2002\n[TEST] spot\nTILT=NONE\n\
+                               1 1000 2.0 3 1 1 1 0.1 0.1 0.0\n1.0 1.0 100\n\
+                               0 45 90\n0\n500, 250, 0\n";
+
+   #[test]
+   fn parses_header_and_table() {
+      let p = IesProfile::parse(SPOT).unwrap();
+      assert_eq!(p.vertical, vec![0.0, 45.0, 90.0]);
+      assert_eq!(p.horizontal, vec![0.0]);
+      assert_eq!(p.candela, vec![1.0, 0.5, 0.0]);
+   }
+
+   #[test]
+   fn skips_included_tilt_data() {
+      let data = SPOT.replace("TILT=NONE\n", "TILT=INCLUDE\n1\n2\n0 90\n1 1\n");
+      assert_eq!(IesProfile::parse(&data).unwrap().candela, vec![1.0, 0.5, 0.0]);
+   }
+
+   #[test]
+   fn rejects_bad_files() {
+      assert!(IesProfile::parse("IESNA:LM-63-2002\n1 2 3\n").is_err());
+      assert!(IesProfile::parse(&SPOT.replace("TILT=NONE", "TILT=lamp.tlt")).is_err());
+      assert!(IesProfile::parse(&SPOT.replace("3 1 1 1", "3 1 2 1")).is_err());
+      assert!(IesProfile::parse(&SPOT.replace("500, 250, 0", "500, 250")).is_err());
+   }
+
+   #[test]
+   fn interpolates_intensity() {
+      let p = IesProfile::parse(SPOT).unwrap();
+      assert!((p.intensity(Vector::new(0.0, -1.0, 0.0)) - 1.0).abs() < 1e-5);
+      assert!((p.intensity(Vector::new(1.0, -1.0, 0.0)) - 0.5).abs() < 1e-5);
+      assert_eq!(p.intensity(Vector::new(0.0, 1.0, 0.0)), 0.0);
+   }
+
+   #[test]
+   fn averages_over_the_sphere() {
+      // falls off linearly from straight down to the horizon
+      let p = IesProfile::parse(SPOT).unwrap();
+      let expected = (1.0 - 2.0 * consts::FRAC_1_PI) / 2.0;
+      assert!((p.mean_intensity() - expected).abs() < 2e-3, "{} != {}", p.mean_intensity(), expected);
+      let all_round = SPOT.replace("3 1 1 1", "2 1 1 1").replace("0 45 90\n0\n500, 250, 0", "0 180\n0\n500 500");
+      assert!((IesProfile::parse(&all_round).unwrap().mean_intensity() - 1.0).abs() < 1e-4);
+   }
+
+   #[test]
+   fn brackets_clamp() {
+      let a = [0.0, 10.0, 30.0];
+      assert_eq!(bracket(&a, -5.0), (0, 0, 0.0));
+      assert_eq!(bracket(&a, 20.0), (1, 2, 0.5));
+      assert_eq!(bracket(&a, 40.0), (2, 2, 0.0));
+   }
+}
@@ -0,0 +1,183 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::path::Path;
+use std::io::Read;
+use color::Color;
+use vector::Float;
+use exr;
+
+pub struct Image {
+   pub width: usize,
+   pub height: usize,
+   pub pixels: Vec<Color>,
+}
+
+impl Image {
+   pub fn get(&self, x: usize, y: usize) -> Color {
+      self.pixels[x + y * self.width]
+   }
+
+   // Radiance and OpenEXR images, told apart by their extension.
+   pub fn load(path: &Path) -> Result<Image, String> {
+      match path.extension().and_then(|e| e.to_str()) {
+         Some("hdr") | Some("pic") => Image::load_hdr(path),
+         Some("exr") => exr::load(path),
+         _ => Err(format!("Unsupported image format {}.", path.display())),
+      }
+   }
+
+   // Radiance RGBE (.hdr) reader. Supports flat and run length encoded
+   // scanlines in the standard -Y +X orientation.
+   // http://radsite.lbl.gov/radiance/refer/filefmts.pdf
+   pub fn load_hdr(path: &Path) -> Result<Image, String> {
+      let mut fin = File::open(path).map_err(|e| e.to_string())?;
+      let mut data = Vec::new();
+      fin.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+      let mut pos = 0;
+      let mut header = true;
+      let mut resolution = None;
+
+      while pos < data.len() && resolution.is_none() {
+         let end = match data[pos ..].iter().position(|&b| b == b'\n') {
+            Some(n) => pos + n,
+            None => return Err("Truncated header.".to_string()),
+         };
+         let line = String::from_utf8_lossy(&data[pos .. end]).into_owned();
+         pos = end + 1;
+
+         if header {
+            if line.is_empty() {
+               header = false;
+            } else if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+               return Err(format!("Unsupported format {}.", &line[7 ..]));
+            }
+         } else {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+               return Err(format!("Unsupported resolution line {}.", line));
+            }
+            let h = fields[1].parse::<usize>().map_err(|e| e.to_string())?;
+            let w = fields[3].parse::<usize>().map_err(|e| e.to_string())?;
+            resolution = Some((w, h));
+         }
+      }
+
+      let (width, height) = resolution.ok_or("Missing resolution.")?;
+      let mut pixels = Vec::with_capacity(width * height);
+      let mut scanline = vec![[0u8; 4]; width];
+
+      for _ in 0 .. height {
+         if data.len() < pos + 4 {
+            return Err("Truncated image data.".to_string());
+         }
+
+         let rle = (8..32768).contains(&width) && data[pos] == 2 && data[pos + 1] == 2
+            && ((data[pos + 2] as usize) << 8 | data[pos + 3] as usize) == width;
+
+         if rle {
+            pos += 4;
+            // channels are stored one after another, each run length encoded
+            for ch in 0 .. 4 {
+               let mut x = 0;
+               while x < width {
+                  if pos >= data.len() {
+                     return Err("Truncated image data.".to_string());
+                  }
+                  let count = data[pos] as usize;
+                  pos += 1;
+                  if count > 128 {
+                     let count = count - 128;
+                     if x + count > width || pos >= data.len() {
+                        return Err("Bad scanline data.".to_string());
+                     }
+                     for p in &mut scanline[x .. x + count] {
+                        p[ch] = data[pos];
+                     }
+                     pos += 1;
+                     x += count;
+                  } else {
+                     if count == 0 || x + count > width || pos + count > data.len() {
+                        return Err("Bad scanline data.".to_string());
+                     }
+                     for p in &mut scanline[x .. x + count] {
+                        p[ch] = data[pos];
+                        pos += 1;
+                     }
+                     x += count;
+                  }
+               }
+            }
+         } else {
+            if data.len() < pos + width * 4 {
+               return Err("Truncated image data.".to_string());
+            }
+            for p in scanline.iter_mut() {
+               p.copy_from_slice(&data[pos .. pos + 4]);
+               pos += 4;
+            }
+         }
+
+         for p in scanline.iter() {
+            pixels.push(rgbe_to_color(*p));
+         }
+      }
+
+      Ok(Image { width, height, pixels })
+   }
+}
+
+fn rgbe_to_color(p: [u8; 4]) -> Color {
+   if p[3] == 0 {
+      return Color::new(0.0, 0.0, 0.0);
+   }
+   let f = (2.0 as Float).powi(p[3] as i32 - (128 + 8));
+   Color::new((p[0] as Float + 0.5) * f, (p[1] as Float + 0.5) * f, (p[2] as Float + 0.5) * f)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Image, rgbe_to_color};
+   use std::path::Path;
+   use testfile::write;
+
+   #[test]
+   fn rgbe_decodes() {
+      let c = rgbe_to_color([128, 64, 0, 129]);
+      assert_eq!((c.r, c.g, c.b), (257.0 / 256.0, 129.0 / 256.0, 1.0 / 256.0));
+      let c = rgbe_to_color([200, 200, 200, 0]);
+      assert_eq!((c.r, c.g, c.b), (0.0, 0.0, 0.0));
+   }
+
+   #[test]
+   fn loads_flat_hdr() {
+      let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 3\n".to_vec();
+      for i in 0 .. 6 {
+         data.extend_from_slice(&[i * 16, 0, 128, 129]);
+      }
+      let path = write("flat.hdr", &data);
+      let image = Image::load(&path).unwrap();
+      assert_eq!((image.width, image.height), (3, 2));
+      assert!((image.get(2, 1).r - 80.5 / 128.0).abs() < 1e-6);
+      assert!((image.get(0, 0).b - 128.5 / 128.0).abs() < 1e-6);
+   }
+
+   #[test]
+   fn rejects_other_formats() {
+      assert!(Image::load(Path::new("sky.png")).err().unwrap().contains("Unsupported"));
+   }
+}
@@ -0,0 +1,228 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::rc::Rc;
+use std::array;
+use matrix::{Matrix, Transform};
+use vector::{Vector, Float};
+use ray::Ray;
+use aabb::Aabb;
+use geometry::{Geometry, ShadeRec};
+use packet::{RayPacket, Lanes, LANES};
+
+// frames used to bound the swept volume of moving geometry
+const MOTION_STEPS: usize = 32;
+
+// A geometry placed with an affine transform. Rays are taken into object
+// space rather than transforming the geometry, so any number of instances
+// can share one object.
+pub struct Instance {
+   geometry: Rc<dyn Geometry>,
+   transform: Matrix,
+   inverse: Matrix,
+}
+
+// A geometry moving while the shutter is open, placed at the time of each
+// ray by blending its transforms at times 0 and 1. Earlier and later times
+// hold it at the ends of its path.
+pub struct Moving {
+   geometry: Rc<dyn Geometry>,
+   start: Transform,
+   end: Transform,
+}
+
+impl Instance {
+   pub fn new(geometry: Rc<dyn Geometry>, transform: Matrix) -> Result<Instance, String> {
+      let inverse = transform.inverse().ok_or("Singular transform.")?;
+      Ok(Instance { geometry, transform, inverse })
+   }
+}
+
+impl Geometry for Instance {
+   fn bounds(&self) -> Aabb {
+      self.geometry.bounds().transform(&self.transform)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      intersect_transformed(&*self.geometry, &self.transform, &self.inverse, r, tmin, tmax)
+   }
+
+   fn intersect_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &Lanes) -> [Option<ShadeRec>; LANES] {
+      intersect_packet_transformed(&*self.geometry, &self.transform, &self.inverse, r, tmin, tmax)
+   }
+}
+
+impl Moving {
+   pub fn new(geometry: Rc<dyn Geometry>, start: Transform, end: Transform) -> Result<Moving, String> {
+      // scales changing sign pass through zero on the way
+      let (a, b) = (start.scale, end.scale);
+      if a.x * b.x <= 0.0 || a.y * b.y <= 0.0 || a.z * b.z <= 0.0 {
+         return Err("Singular transform.".to_string());
+      }
+      Ok(Moving { geometry, start, end })
+   }
+
+   fn at(&self, time: Float) -> Transform {
+      self.start.lerp(&self.end, time.clamp(0.0, 1.0))
+   }
+
+   // The furthest any point of b strays from the chord between two frames
+   // sampled for the bounds. Each coordinate of T + R S x is within h^2/8
+   // of its second derivative of that chord, and |R'' S x + 2 R' S' x| is at
+   // most w^2 |S x| + 2 w |S' x| where w, the sum of the three rotation
+   // rates, bounds how fast the object turns.
+   fn chord_error(&self, b: &Aabb) -> Float {
+      let (s0, s1) = (self.start.scale, self.end.scale);
+      let turn = (self.end.rotate - self.start.rotate).abs();
+      let w = (turn.x + turn.y + turn.z).to_radians();
+      let mut size: Float = 0.0;
+      let mut growth: Float = 0.0;
+      for i in 0 .. 8 {
+         let x = Vector::new(if i & 1 == 0 { b.min.x } else { b.max.x },
+                             if i & 2 == 0 { b.min.y } else { b.max.y },
+                             if i & 4 == 0 { b.min.z } else { b.max.z });
+         let scaled = Vector::new(s0.x.abs().max(s1.x.abs()) * x.x,
+                                  s0.y.abs().max(s1.y.abs()) * x.y,
+                                  s0.z.abs().max(s1.z.abs()) * x.z);
+         let change = Vector::new((s1.x - s0.x) * x.x, (s1.y - s0.y) * x.y, (s1.z - s0.z) * x.z);
+         size = size.max(scaled.magnitude());
+         growth = growth.max(change.magnitude());
+      }
+      let h = 1.0 / MOTION_STEPS as Float;
+      h * h / 8.0 * (w * w * size + 2.0 * w * growth)
+   }
+}
+
+impl Geometry for Moving {
+   // Bounds of the geometry at evenly spaced times, which hold the chords
+   // between them, grown to take in the arcs a rotation sweeps between them.
+   fn bounds(&self) -> Aabb {
+      let b = self.geometry.bounds();
+      let swept = (0 .. MOTION_STEPS + 1).fold(Aabb::empty(), |a, i| {
+         a.union(&b.transform(&self.at(i as Float / MOTION_STEPS as Float).matrix()))
+      });
+      if !swept.is_finite() {
+         return swept;
+      }
+      let e = self.chord_error(&b);
+      let pad = Vector::new(e, e, e);
+      Aabb::new(swept.min - pad, swept.max + pad)
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let m = self.at(r.time).matrix();
+      let inverse = m.inverse()?;
+      intersect_transformed(&*self.geometry, &m, &inverse, r, tmin, tmax)
+   }
+}
+
+fn intersect_transformed(g: &dyn Geometry, transform: &Matrix, inverse: &Matrix, r: Ray, tmin: Float, tmax: Float)
+                         -> Option<ShadeRec> {
+   let (local, scale) = to_object(inverse, r);
+   g.intersect(local, tmin * scale, tmax * scale).map(|sr| to_world(sr, transform, inverse, r, scale))
+}
+
+// The rays of a packet taken into object space together, so geometry with
+// its own packet test keeps using it when instanced.
+fn intersect_packet_transformed(g: &dyn Geometry, transform: &Matrix, inverse: &Matrix, r: &RayPacket,
+                                tmin: &Lanes, tmax: &Lanes) -> [Option<ShadeRec>; LANES] {
+   let mut rays = [r.ray(0); LANES];
+   let mut scale = [1.0; LANES];
+   for k in 0 .. LANES {
+      let (local, s) = to_object(inverse, r.ray(k));
+      rays[k] = local;
+      scale[k] = s;
+   }
+   let scale = Lanes::from_array(scale);
+   let mut hits = g.intersect_packet(&RayPacket::new(&rays), &(*tmin * scale), &(*tmax * scale));
+   array::from_fn(|k| hits[k].take().map(|sr| to_world(sr, transform, inverse, r.ray(k), scale[k])))
+}
+
+// The object space direction is normalised as the primitives expect,
+// which scales distances along the ray by its length.
+fn to_object(inverse: &Matrix, r: Ray) -> (Ray, Float) {
+   let d = inverse.transform_vector(r.direction);
+   let scale = d.magnitude();
+   let local = Ray {
+      origin: inverse.transform_point(r.origin),
+      direction: d * (1.0 / scale),
+      time: r.time,
+   };
+   (local, scale)
+}
+
+fn to_world(mut sr: ShadeRec, transform: &Matrix, inverse: &Matrix, r: Ray, scale: Float) -> ShadeRec {
+   // the hit point is carried over with its error rather than found
+   // again along the ray, which would lose the care taken over it
+   sr.t /= scale;
+   let error = transform.transform_error(sr.hit_point, sr.error);
+   sr.set_hit_point(transform.transform_point(sr.hit_point), error);
+   sr.ray = r;
+   sr.normal = inverse.transform_normal(sr.normal).normalize();
+   let n = inverse.transform_normal(sr.geometric_normal).normalize();
+   sr.set_geometric_normal(n);
+   sr.dpdu = transform.transform_vector(sr.dpdu);
+   sr.dpdv = transform.transform_vector(sr.dpdv);
+   sr
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Instance, Moving};
+   use std::rc::Rc;
+   use vector::{Vector, Float};
+   use matrix::{Matrix, Transform};
+   use geometry::{Geometry, Sphere};
+   use ray::Ray;
+
+   #[test]
+   fn bounds_hold_the_whole_path() {
+      // a sphere far from the origin, turning and growing quickly
+      let end = Transform {
+         scale: Vector::new(2.0, 1.0, 3.0),
+         rotate: Vector::new(40.0, 370.0, -90.0),
+         translate: Vector::new(1.0, -2.0, 0.5),
+      };
+      let sphere = Sphere::new(6.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0);
+      let corners = sphere.bounds();
+      let moving = Moving::new(Rc::new(sphere), Transform::identity(), end).unwrap();
+      let b = moving.bounds();
+      for i in 0 .. 1001 {
+         let m = moving.at(i as Float / 1000.0).matrix();
+         for &x in [corners.min, corners.max, Vector::new(corners.min.x, corners.max.y, corners.min.z)].iter() {
+            let p = m.transform_point(x);
+            assert!(p.x >= b.min.x && p.y >= b.min.y && p.z >= b.min.z, "{:?} below {:?}", p, b.min);
+            assert!(p.x <= b.max.x && p.y <= b.max.y && p.z <= b.max.z, "{:?} above {:?}", p, b.max);
+         }
+      }
+   }
+
+   #[test]
+   fn normals_under_non_uniform_scale() {
+      // a unit sphere stretched into the ellipsoid x^2 / 4 + y^2 + z^2 = 1,
+      // where the normal follows the gradient (x / 4, y, z)
+      let sphere = Sphere::new(0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0);
+      let instance = Instance::new(Rc::new(sphere), Matrix::scaling(Vector::new(2.0, 1.0, 1.0))).unwrap();
+      let r = Ray { origin: Vector::new(1.0, 0.5, -5.0), direction: Vector::new(0.0, 0.0, 1.0), time: 0.0 };
+      let sr = instance.intersect(r, 0.0, Float::INFINITY).unwrap();
+      let z = -(0.5 as Float).sqrt();
+      assert!((sr.t - (5.0 + z)).abs() < 1e-5, "{}", sr.t);
+      assert!((sr.hit_point - Vector::new(1.0, 0.5, z)).magnitude() < 1e-5);
+
+      let n = Vector::new(0.25, 0.5, z).normalize();
+      assert!((sr.normal - n).magnitude() < 1e-5, "{:?}", sr.normal);
+      assert!((sr.geometric_normal - n).magnitude() < 1e-5, "{:?}", sr.geometric_normal);
+   }
+}
@@ -0,0 +1,255 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// kd-tree with split planes chosen by the surface area heuristic over the
+// bounding box edges of the primitives, as described in Physically Based
+// Rendering. Primitives straddling a plane are referenced from both sides.
+
+use vector::{Vector, Float};
+use aabb::Aabb;
+use ray::Ray;
+use accelerator::{Accelerator, component, inverse};
+
+const TRAVERSAL_COST: Float = 1.0;
+const INTERSECT_COST: Float = 80.0;
+// cost reduction for splits that leave one side empty
+const EMPTY_BONUS: Float = 0.5;
+const MAX_LEAF: usize = 1;
+
+enum Node {
+   // primitives from start in the index list
+   Leaf { start: usize, count: usize },
+   // the child below the plane directly follows its parent
+   Interior { axis: usize, split: Float, above: usize },
+}
+
+pub struct KdTree {
+   bounds: Aabb,
+   nodes: Vec<Node>,
+   indices: Vec<usize>,
+   unbounded: Vec<usize>,
+}
+
+impl KdTree {
+   pub fn new(bounds: &[Aabb]) -> KdTree {
+      let (finite, unbounded): (Vec<usize>, Vec<usize>) = (0 .. bounds.len()).partition(|&i| bounds[i].is_finite());
+      let region = finite.iter().fold(Aabb::empty(), |b, &i| b.union(&bounds[i]));
+
+      let mut tree = KdTree { bounds: region, nodes: Vec::new(), indices: Vec::new(), unbounded };
+      if !finite.is_empty() {
+         let depth = (8.0 + 1.3 * (finite.len() as Float).log2()).round() as usize;
+         tree.build(bounds, finite, region, depth, 0);
+      }
+      tree
+   }
+
+   fn build(&mut self, bounds: &[Aabb], items: Vec<usize>, region: Aabb, depth: usize, bad_refines: usize) {
+      let count = items.len();
+      if count <= MAX_LEAF || depth == 0 {
+         self.push_leaf(items);
+         return;
+      }
+
+      // sweep the box edges along each axis in order, keeping count of the
+      // primitives on either side of each candidate plane
+      let total_area = region.surface_area();
+      let inv_area = if total_area > 0.0 { 1.0 / total_area } else { 0.0 };
+      let d = region.max - region.min;
+      let leaf_cost = INTERSECT_COST * count as Float;
+      let mut best: Option<(usize, Float, Float)> = None;
+
+      for axis in 0 .. 3 {
+         let mut edges: Vec<(Float, bool)> = Vec::with_capacity(2 * count);
+         for &i in items.iter() {
+            edges.push((component(bounds[i].min, axis), true));
+            edges.push((component(bounds[i].max, axis), false));
+         }
+         // at the same position starts sort before ends
+         edges.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then(b.1.cmp(&a.1)));
+
+         let (lo, hi) = (component(region.min, axis), component(region.max, axis));
+         let (o1, o2) = ((axis + 1) % 3, (axis + 2) % 3);
+         let (d1, d2) = (component(d, o1), component(d, o2));
+
+         let (mut below, mut above) = (0, count);
+         for &(t, start) in edges.iter() {
+            if !start {
+               above -= 1;
+            }
+            if t > lo && t < hi {
+               let area_below = 2.0 * (d1 * d2 + (t - lo) * (d1 + d2));
+               let area_above = 2.0 * (d1 * d2 + (hi - t) * (d1 + d2));
+               let bonus = if below == 0 || above == 0 { EMPTY_BONUS } else { 0.0 };
+               let cost = TRAVERSAL_COST + INTERSECT_COST * (1.0 - bonus) *
+                          (area_below * inv_area * below as Float + area_above * inv_area * above as Float);
+               if best.is_none_or(|b| cost < b.2) {
+                  best = Some((axis, t, cost));
+               }
+            }
+            if start {
+               below += 1;
+            }
+         }
+      }
+
+      let (axis, split, cost) = match best {
+         Some(b) => b,
+         None => {
+            self.push_leaf(items);
+            return;
+         }
+      };
+
+      // allow a few splits that don't pay off in case later ones do
+      let bad_refines = if cost > leaf_cost { bad_refines + 1 } else { bad_refines };
+      if (cost > 4.0 * leaf_cost && count < 16) || bad_refines == 3 {
+         self.push_leaf(items);
+         return;
+      }
+
+      let mut below_items = Vec::new();
+      let mut above_items = Vec::new();
+      for &i in items.iter() {
+         let (lo, hi) = (component(bounds[i].min, axis), component(bounds[i].max, axis));
+         if lo < split || (lo == split && hi == split) {
+            below_items.push(i);
+         }
+         if hi > split {
+            above_items.push(i);
+         }
+      }
+
+      let mut below_region = region;
+      let mut above_region = region;
+      set_component(&mut below_region.max, axis, split);
+      set_component(&mut above_region.min, axis, split);
+
+      let node = self.nodes.len();
+      self.nodes.push(Node::Interior { axis, split, above: 0 });
+      self.build(bounds, below_items, below_region, depth - 1, bad_refines);
+      let above = self.nodes.len();
+      if let Node::Interior { above: ref mut a, .. } = self.nodes[node] {
+         *a = above;
+      }
+      self.build(bounds, above_items, above_region, depth - 1, bad_refines);
+   }
+
+   fn push_leaf(&mut self, items: Vec<usize>) {
+      self.nodes.push(Node::Leaf { start: self.indices.len(), count: items.len() });
+      self.indices.extend(items);
+   }
+
+   // Visits the leaves along the ray front to back. visit gets the items
+   // and returns the current tmax, or None to stop the walk.
+   fn walk<F>(&self, r: &Ray, tmin: Float, tmax: Float, mut visit: F)
+      where F: FnMut(&[usize]) -> Option<Float> {
+      if self.nodes.is_empty() {
+         return;
+      }
+      let inv_dir = inverse(r.direction);
+      let (mut t0, mut t1) = match self.bounds.hit(r, inv_dir, tmin, tmax) {
+         Some(h) => h,
+         None => return,
+      };
+
+      let mut tmax = tmax;
+      let mut stack: Vec<(usize, Float, Float)> = Vec::new();
+      let mut node = 0;
+      loop {
+         if tmax < t0 {
+            return;
+         }
+         match self.nodes[node] {
+            Node::Interior { axis, split, above } => {
+               let o = component(r.origin, axis);
+               let t_plane = (split - o) * component(inv_dir, axis);
+               let below_first = o < split || (o == split && component(r.direction, axis) <= 0.0);
+               let (first, second) = if below_first { (node + 1, above) } else { (above, node + 1) };
+
+               if t_plane > t1 || t_plane <= 0.0 {
+                  node = first;
+               } else if t_plane < t0 {
+                  node = second;
+               } else {
+                  stack.push((second, t_plane, t1));
+                  node = first;
+                  t1 = t_plane;
+               }
+            }
+            Node::Leaf { start, count } => {
+               match visit(&self.indices[start .. start + count]) {
+                  Some(t) => tmax = t,
+                  None => return,
+               }
+               match stack.pop() {
+                  Some((n, a, b)) => {
+                     node = n;
+                     t0 = a;
+                     t1 = b;
+                  }
+                  None => return,
+               }
+            }
+         }
+      }
+   }
+}
+
+impl Accelerator for KdTree {
+   fn bounds(&self) -> Aabb {
+      if !self.unbounded.is_empty() {
+         Aabb::infinite()
+      } else {
+         self.bounds
+      }
+   }
+
+   fn closest(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize, Float) -> Option<Float>) {
+      let mut tmax = tmax;
+      for &i in self.unbounded.iter() {
+         if let Some(t) = test(i, tmax) {
+            tmax = t;
+         }
+      }
+      self.walk(r, tmin, tmax, |items| {
+         for &i in items.iter() {
+            if let Some(t) = test(i, tmax) {
+               tmax = t;
+            }
+         }
+         Some(tmax)
+      });
+   }
+
+   fn any(&self, r: &Ray, tmin: Float, tmax: Float, test: &mut dyn FnMut(usize) -> bool) -> bool {
+      if self.unbounded.iter().any(|&i| test(i)) {
+         return true;
+      }
+      let mut found = false;
+      self.walk(r, tmin, tmax, |items| {
+         found = items.iter().any(|&i| test(i));
+         if found { None } else { Some(tmax) }
+      });
+      found
+   }
+}
+
+fn set_component(v: &mut Vector, axis: usize, value: Float) {
+   match axis {
+      0 => v.x = value,
+      1 => v.y = value,
+      _ => v.z = value,
+   }
+}
@@ -13,96 +13,401 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use vector::Vector;
+use std::cell::Cell;
+use std::path::Path;
+use std::rc::Rc;
+use vector::{Vector, Float, cross, dot};
+use vector::float::consts;
 use color::Color;
+use aabb::Aabb;
 use geometry::ShadeRec;
+use environment::EnvironmentMap;
+use ies::IesProfile;
+use image::Image;
+use sampler::Sampler;
 use toml::Value;
 
 pub trait Light {
    fn direction(&self, hr: &ShadeRec) -> Vector;
    fn radiance(&self, hr: &ShadeRec) -> Color;
+   // distance from the hit point to the light along direction()
+   fn distance(&self, hr: &ShadeRec) -> Float;
+
+   fn pdf(&self, _hr: &ShadeRec) -> Float {
+      1.0
+   }
+
+   fn casts_shadows(&self) -> bool {
+      false
+   }
+
+   // whether the light has a surface that shadow rays towards it would hit
+   fn has_surface(&self) -> bool {
+      false
+   }
+
+   fn samples(&self) -> usize {
+      1
+   }
+
+   // whether the named object is lit by this light
+   fn illuminates(&self, _name: &str) -> bool {
+      true
+   }
+
+   // None for lights at infinity
+   fn position(&self) -> Option<Vector> {
+      None
+   }
+
+   // the region light leaves from, for lights with a position
+   fn bounds(&self) -> Option<Aabb> {
+      self.position().map(|p| Aabb::new(p, p))
+   }
+
+   // rough emitted power, used to choose between lights
+   fn power(&self) -> Float {
+      1.0
+   }
 }
 
-#[derive(Debug,Copy,Clone)]
-pub struct AmbientLight {
-   pub color: Color,
-   pub ls: f32, // radiance scaling factor
+// Light linking. An empty include list means every object not excluded.
+#[derive(Debug,Clone)]
+pub struct Linking {
+   include: Vec<String>,
+   exclude: Vec<String>,
+}
+
+impl Linking {
+   pub fn new() -> Linking {
+      Linking { include: Vec::new(), exclude: Vec::new() }
+   }
+
+   pub fn import(obj: &Value) -> Result<Linking, String> {
+      Ok(Linking {
+         include: Linking::import_names(obj, "include")?,
+         exclude: Linking::import_names(obj, "exclude")?,
+      })
+   }
+
+   fn import_names(obj: &Value, key: &str) -> Result<Vec<String>, String> {
+      let mut names = Vec::new();
+      if let Some(v) = obj.lookup(key) {
+         for n in v.as_slice().ok_or("Invalid array.")? {
+            names.push(n.as_str().ok_or("Invalid string.")?.to_string());
+         }
+      }
+      Ok(names)
+   }
+
+   pub fn illuminates(&self, name: &str) -> bool {
+      if !self.include.is_empty() && !self.include.iter().any(|n| n == name) {
+         return false;
+      }
+      !self.exclude.iter().any(|n| n == name)
+   }
 }
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Clone)]
 pub struct PointLight {
    pub location: Vector,
    pub color: Color,
-   pub ls: f32, // radiance scaling factor
+   pub ls: Float, // radiance scaling factor
+   pub shadows: bool,
+   pub linking: Linking,
+   pub ies: Option<Rc<IesProfile>>, // optional photometric profile
+}
+
+// Light given off evenly by one side of a rectangle with corner p0 and
+// perpendicular sides a and b, the side cross(a, b) points to. direction()
+// picks a new point on the rectangle, which the following radiance(), pdf()
+// and distance() calls refer to. Scenes usually place a rectangle object
+// at the light too, so it can be seen.
+pub struct AreaLight {
+   pub p0: Vector,
+   pub a: Vector,
+   pub b: Vector,
+   pub color: Color,
+   pub ls: Float, // radiance scaling factor
+   pub samples: usize,
+   pub shadows: bool,
+   pub linking: Linking,
+   normal: Vector,
+   sampler: Sampler,
+   point: Cell<Vector>,
+   pdf: Cell<Float>,
+}
+
+// Image based light. direction() draws a new sample from the map and the
+// following radiance() and pdf() calls refer to that sample.
+pub struct EnvironmentLight {
+   pub map: Rc<EnvironmentMap>,
+   pub ls: Float, // radiance scaling factor
+   pub samples: usize,
+   pub linking: Linking,
+   sampler: Sampler,
+   wi: Cell<Vector>,
+   pdf: Cell<Float>,
 }
 
-impl AmbientLight {
-   pub fn new() -> AmbientLight {
-      AmbientLight {
+impl PointLight {
+   pub fn new() -> PointLight {
+      PointLight {
+         location: Vector::zero(),
          color: Color::new(1.0, 1.0, 1.0),
          ls: 0.2,
+         shadows: false,
+         linking: Linking::new(),
+         ies: None,
       }
    }
 
-   pub fn from_vec(v: Vec<f32>) -> AmbientLight {
-      AmbientLight { color: Color::new(v[0], v[1], v[2]), ls: v[3] }
+   pub fn from_vec(v: Vec<Float>) -> PointLight {
+      PointLight {
+         location: Vector::new(v[0], v[1], v[2]),
+         color: Color::new(v[3], v[4], v[5]),
+         ls: v[6],
+         ..PointLight::new()
+      }
    }
 
-   pub fn import(obj: &Value) -> Result<AmbientLight, String> {
-      let elements = vec!["color.0", "color.1", "color.2", "ls"];
+   pub fn import(obj: &Value, dir: &Path) -> Result<PointLight, String> {
+      let elements = vec!["location.0", "location.1", "location.2", "color.0", "color.1", "color.2", "ls"];
       let mut values = Vec::new();
 
       for e in elements {
-         let value = try!(obj.lookup(e).ok_or("Missing element."));
-         values.push(try!(value.as_float().ok_or("Invalid float.")) as f32);
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
       }
 
-      Ok(AmbientLight::from_vec(values))
+      let mut pl = PointLight::from_vec(values);
+      if let Some(v) = obj.lookup("shadows") {
+         pl.shadows = v.as_bool().ok_or("Invalid bool.")?;
+      }
+      pl.linking = Linking::import(obj)?;
+      if let Some(v) = obj.lookup("ies") {
+         let file = v.as_str().ok_or("Invalid string.")?;
+         pl.ies = Some(Rc::new(IesProfile::load(&dir.join(file))?));
+      }
+
+      Ok(pl)
    }
 }
 
-impl PointLight {
-   pub fn new() -> PointLight {
-      PointLight {
-         location: Vector::zero(),
-         color: Color::new(1.0, 1.0, 1.0),
-         ls: 0.2,
+impl AreaLight {
+   pub fn new(p0: Vector, a: Vector, b: Vector, color: Color, ls: Float, samples: usize) -> AreaLight {
+      AreaLight {
+         p0,
+         a,
+         b,
+         color,
+         ls,
+         samples,
+         shadows: true,
+         linking: Linking::new(),
+         normal: cross(a, b).normalize(),
+         sampler: Sampler::new(samples as u32),
+         point: Cell::new(p0),
+         pdf: Cell::new(0.0),
       }
    }
 
-   pub fn from_vec(v: Vec<f32>) -> PointLight {
-      PointLight { location: Vector::new(v[0], v[1], v[2]), color: Color::new(v[3], v[4], v[5]), ls: v[6] }
+   pub fn import(obj: &Value) -> Result<AreaLight, String> {
+      let elements = vec!["p0.0", "p0.1", "p0.2", "a.0", "a.1", "a.2", "b.0", "b.1", "b.2",
+                          "color.0", "color.1", "color.2", "ls"];
+      let mut v = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         v.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let a = Vector::new(v[3], v[4], v[5]);
+      let b = Vector::new(v[6], v[7], v[8]);
+      let area = cross(a, b).magnitude();
+      if area.is_nan() || area <= 0.0 {
+         return Err("Area light has no area.".to_string());
+      }
+      if dot(a, b).abs() > 1e-4 * a.magnitude() * b.magnitude() {
+         return Err("Area light sides aren't perpendicular.".to_string());
+      }
+      let samples = match obj.lookup("samples") {
+         Some(v) => v.as_integer().ok_or("Invalid integer.")? as usize,
+         None => 16,
+      };
+
+      let mut al = AreaLight::new(Vector::new(v[0], v[1], v[2]), a, b, Color::new(v[9], v[10], v[11]), v[12],
+                                  samples.max(1));
+      if let Some(v) = obj.lookup("shadows") {
+         al.shadows = v.as_bool().ok_or("Invalid bool.")?;
+      }
+      al.linking = Linking::import(obj)?;
+
+      Ok(al)
    }
 
-   pub fn import(obj: &Value) -> Result<PointLight, String> {
-      let elements = vec!["location.0", "location.1", "location.2", "color.0", "color.1", "color.2", "ls"];
-      let mut values = Vec::new();
+   fn area(&self) -> Float {
+      cross(self.a, self.b).magnitude()
+   }
+}
 
-      for e in elements {
-         let value = try!(obj.lookup(e).ok_or("Missing element."));
-         values.push(try!(value.as_float().ok_or("Invalid float.")) as f32);
+impl EnvironmentLight {
+   pub fn new(map: Rc<EnvironmentMap>, ls: Float, samples: usize) -> EnvironmentLight {
+      EnvironmentLight {
+         map,
+         ls,
+         samples,
+         linking: Linking::new(),
+         sampler: Sampler::new(samples as u32),
+         wi: Cell::new(Vector::zero()),
+         pdf: Cell::new(0.0),
       }
+   }
+
+   pub fn import(obj: &Value, dir: &Path) -> Result<EnvironmentLight, String> {
+      let file = obj.lookup("file").and_then(|v| v.as_str()).ok_or("Missing file.")?;
+      let image = Image::load(&dir.join(file))?;
+
+      let ls = match obj.lookup("ls") {
+         Some(v) => v.as_float().ok_or("Invalid float.")? as Float,
+         None => 1.0,
+      };
+      let samples = match obj.lookup("samples") {
+         Some(v) => v.as_integer().ok_or("Invalid integer.")? as usize,
+         None => 16,
+      };
 
-      Ok(PointLight::from_vec(values))
+      let mut el = EnvironmentLight::new(Rc::new(EnvironmentMap::new(image)), ls, samples.max(1));
+      el.linking = Linking::import(obj)?;
+
+      Ok(el)
    }
 }
 
-impl Light for AmbientLight {
+impl Light for PointLight {
    fn direction(&self, hr: &ShadeRec) -> Vector {
-      Vector::zero()
+     (self.location - hr.hit_point).normalize()
+   }
+
+   fn distance(&self, hr: &ShadeRec) -> Float {
+      (self.location - hr.hit_point).magnitude()
    }
 
    fn radiance(&self, hr: &ShadeRec) -> Color {
-      self.color * self.ls
+      match self.ies {
+         Some(ref ies) => self.color * (self.ls * ies.intensity(hr.hit_point - self.location)),
+         None => self.color * self.ls,
+      }
+   }
+
+   fn casts_shadows(&self) -> bool {
+      self.shadows
+   }
+
+   fn illuminates(&self, name: &str) -> bool {
+      self.linking.illuminates(name)
+   }
+
+   fn position(&self) -> Option<Vector> {
+      Some(self.location)
+   }
+
+   // a profile dims the light by its average over every direction
+   fn power(&self) -> Float {
+      let spread = self.ies.as_ref().map_or(1.0, |ies| ies.mean_intensity());
+      self.color.luminance() * self.ls * spread
    }
 }
 
-impl Light for PointLight {
+impl Light for AreaLight {
    fn direction(&self, hr: &ShadeRec) -> Vector {
-     (self.location - hr.hit_point).normalize()
+      let (u1, u2) = self.sampler.sample_unit_square();
+      let point = self.p0 + self.a * u1 + self.b * u2;
+      let d = point - hr.hit_point;
+      let wi = d.normalize();
+      // uniform over the area, as a density over directions
+      let cos = dot(self.normal, wi).abs();
+      self.point.set(point);
+      self.pdf.set(if cos > 0.0 { d.magnitude_sq() / (cos * self.area()) } else { 0.0 });
+      wi
    }
 
    fn radiance(&self, hr: &ShadeRec) -> Color {
-      self.color * self.ls
+      if dot(self.normal, hr.hit_point - self.point.get()) > 0.0 {
+         self.color * self.ls
+      } else {
+         Color::new(0.0, 0.0, 0.0)
+      }
+   }
+
+   fn distance(&self, hr: &ShadeRec) -> Float {
+      (self.point.get() - hr.hit_point).magnitude()
+   }
+
+   fn pdf(&self, _hr: &ShadeRec) -> Float {
+      self.pdf.get()
+   }
+
+   fn casts_shadows(&self) -> bool {
+      self.shadows
+   }
+
+   fn has_surface(&self) -> bool {
+      true
+   }
+
+   fn samples(&self) -> usize {
+      self.samples
+   }
+
+   fn illuminates(&self, name: &str) -> bool {
+      self.linking.illuminates(name)
+   }
+
+   fn position(&self) -> Option<Vector> {
+      Some(self.p0 + (self.a + self.b) * 0.5)
+   }
+
+   fn bounds(&self) -> Option<Aabb> {
+      Some(Aabb::from_points(&[self.p0, self.p0 + self.a, self.p0 + self.b, self.p0 + self.a + self.b]))
+   }
+
+   // pi times the radiance over the area
+   fn power(&self) -> Float {
+      consts::PI * self.color.luminance() * self.ls * self.area()
+   }
+}
+
+impl Light for EnvironmentLight {
+   fn direction(&self, _hr: &ShadeRec) -> Vector {
+      let (u1, u2) = self.sampler.sample_unit_square();
+      let (wi, pdf) = self.map.sample(u1, u2);
+      self.wi.set(wi);
+      self.pdf.set(pdf);
+      wi
+   }
+
+   fn radiance(&self, _hr: &ShadeRec) -> Color {
+      self.map.lookup(self.wi.get()) * self.ls
+   }
+
+   fn distance(&self, _hr: &ShadeRec) -> Float {
+      Float::INFINITY
+   }
+
+   fn pdf(&self, _hr: &ShadeRec) -> Float {
+      self.pdf.get()
+   }
+
+   fn casts_shadows(&self) -> bool {
+      true
+   }
+
+   fn samples(&self) -> usize {
+      self.samples
+   }
+
+   fn illuminates(&self, name: &str) -> bool {
+      self.linking.illuminates(name)
    }
 }
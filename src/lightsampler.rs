@@ -0,0 +1,276 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use vector::{Vector, dot, Float};
+use light::Light;
+use aabb::Aabb;
+
+// largest float below 1
+const ONE_MINUS_EPSILON: Float = 1.0 - Float::EPSILON / 2.0;
+
+// Picks one light for a shading point. Returns the index into the scene's
+// light list and the probability it was chosen with, or None when no light
+// can contribute. Only lights with a position take part, lights at infinity
+// are always sampled directly.
+pub trait LightSampler {
+   fn select(&self, p: Vector, n: Vector, u: Float) -> Option<(usize, Float)>;
+}
+
+// Chooses lights in proportion to their power.
+pub struct PowerSampler {
+   lights: Vec<usize>,
+   cdf: Vec<Float>,
+}
+
+// Binary tree over the bounds of lights. Each step down picks a child by an
+// estimate of how much it contributes to the shading point.
+pub struct LightTree {
+   nodes: Vec<Node>,
+}
+
+struct Node {
+   min: Vector,
+   max: Vector,
+   power: Float,
+   // leaf light index, or index of the right child (the left follows the node)
+   light: Option<usize>,
+   right: usize,
+}
+
+struct Entry {
+   index: usize,
+   position: Vector,
+   bounds: Aabb,
+   power: Float,
+}
+
+fn entries(lights: &[Box<dyn Light>]) -> Vec<Entry> {
+   let mut v = Vec::new();
+   for (i, l) in lights.iter().enumerate() {
+      if let (Some(p), Some(b)) = (l.position(), l.bounds()) {
+         if l.power() > 0.0 {
+            v.push(Entry { index: i, position: p, bounds: b, power: l.power() });
+         }
+      }
+   }
+   v
+}
+
+impl PowerSampler {
+   pub fn new(lights: &[Box<dyn Light>]) -> PowerSampler {
+      let e = entries(lights);
+      let total: Float = e.iter().map(|e| e.power).sum();
+      let mut cdf = Vec::with_capacity(e.len());
+      let mut sum = 0.0;
+      for x in e.iter() {
+         sum += x.power / total;
+         cdf.push(sum);
+      }
+
+      PowerSampler { lights: e.iter().map(|e| e.index).collect(), cdf }
+   }
+}
+
+impl LightSampler for PowerSampler {
+   fn select(&self, _p: Vector, _n: Vector, u: Float) -> Option<(usize, Float)> {
+      if self.lights.is_empty() {
+         return None;
+      }
+      let i = self.cdf.partition_point(|&c| c <= u).min(self.cdf.len() - 1);
+      let prev = if i == 0 { 0.0 } else { self.cdf[i - 1] };
+      Some((self.lights[i], self.cdf[i] - prev))
+   }
+}
+
+impl LightTree {
+   pub fn new(lights: &[Box<dyn Light>]) -> LightTree {
+      let mut e = entries(lights);
+      let mut tree = LightTree { nodes: Vec::with_capacity(2 * e.len()) };
+      if !e.is_empty() {
+         tree.build(&mut e);
+      }
+      tree
+   }
+
+   fn build(&mut self, e: &mut [Entry]) -> usize {
+      let b = e.iter().fold(Aabb::empty(), |b, x| b.union(&x.bounds));
+      let (min, max) = (b.min, b.max);
+
+      let index = self.nodes.len();
+      self.nodes.push(Node {
+         min,
+         max,
+         power: e.iter().map(|x| x.power).sum(),
+         light: None,
+         right: 0,
+      });
+
+      if e.len() == 1 {
+         self.nodes[index].light = Some(e[0].index);
+         return index;
+      }
+
+      // split at the median of the longest axis
+      let d = max - min;
+      let axis = |v: Vector| if d.x >= d.y && d.x >= d.z { v.x } else if d.y >= d.z { v.y } else { v.z };
+      e.sort_by(|a, b| axis(a.position).partial_cmp(&axis(b.position)).unwrap());
+
+      let mid = e.len() / 2;
+      let (left, right) = e.split_at_mut(mid);
+      self.build(left);
+      let r = self.build(right);
+      self.nodes[index].right = r;
+      index
+   }
+
+   // Power over squared distance, clamped by the node's extent so nearby
+   // clusters don't blow up. Zero when the whole node is below the surface.
+   fn importance(&self, node: &Node, p: Vector, n: Vector) -> Float {
+      let mut above = false;
+      for i in 0 .. 8 {
+         let c = Vector::new(if i & 1 == 0 { node.min.x } else { node.max.x },
+                             if i & 2 == 0 { node.min.y } else { node.max.y },
+                             if i & 4 == 0 { node.min.z } else { node.max.z });
+         if dot(c - p, n) > 0.0 {
+            above = true;
+            break;
+         }
+      }
+      if !above {
+         return 0.0;
+      }
+
+      let centre = (node.min + node.max) * 0.5;
+      let half = (node.max - node.min) * 0.5;
+      let d2 = (centre - p).magnitude_sq().max(half.magnitude_sq()).max(1e-6);
+      node.power / d2
+   }
+}
+
+impl LightSampler for LightTree {
+   fn select(&self, p: Vector, n: Vector, u: Float) -> Option<(usize, Float)> {
+      if self.nodes.is_empty() || self.importance(&self.nodes[0], p, n) == 0.0 {
+         return None;
+      }
+
+      let mut u = u;
+      let mut prob = 1.0;
+      let mut i = 0;
+      loop {
+         let node = &self.nodes[i];
+         if let Some(l) = node.light {
+            return Some((l, prob));
+         }
+
+         let wl = self.importance(&self.nodes[i + 1], p, n);
+         let wr = self.importance(&self.nodes[node.right], p, n);
+         if wl + wr == 0.0 {
+            return None;
+         }
+
+         // reuse the random number by rescaling it into the chosen range
+         let pl = wl / (wl + wr);
+         if u < pl {
+            u /= pl;
+            prob *= pl;
+            i += 1;
+         } else {
+            u = (u - pl) / (1.0 - pl);
+            prob *= 1.0 - pl;
+            i = node.right;
+         }
+         u = u.min(ONE_MINUS_EPSILON);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{LightSampler, PowerSampler, LightTree};
+   use vector::{Vector, Float, dot};
+   use color::Color;
+   use light::{Light, PointLight, AreaLight};
+
+   fn lights() -> Vec<Box<dyn Light>> {
+      let mut lights: Vec<Box<dyn Light>> = Vec::new();
+      let spots = [(-3.0, 2.0, 0.0, 1.0), (4.0, 1.0, -2.0, 0.2), (0.5, 5.0, 3.0, 2.5),
+                   (-1.0, -2.0, 1.0, 0.7), (2.0, 0.3, 4.0, 0.05), (-6.0, 3.0, -5.0, 1.5)];
+      for &(x, y, z, ls) in spots.iter() {
+         lights.push(Box::new(PointLight::from_vec(vec![x, y, z, 1.0, 0.9, 0.8, ls])));
+      }
+      lights.push(Box::new(AreaLight::new(Vector::new(-1.0, 4.0, -1.0), Vector::new(2.0, 0.0, 0.0),
+                                          Vector::new(0.0, 0.0, 2.0), Color::new(1.0, 1.0, 1.0), 0.3, 1)));
+      lights
+   }
+
+   // A stand in for what a light gives a shading point, zero below the
+   // surface as the samplers may skip those lights.
+   fn contribution(l: &dyn Light, p: Vector, n: Vector) -> Float {
+      let d = l.position().unwrap() - p;
+      l.power() * dot(d.normalize(), n).max(0.0) / d.magnitude_sq()
+   }
+
+   // Dividing what the chosen light gives by its probability averages out
+   // to the sum over every light, and each light comes up as often as the
+   // probability given for it.
+   fn assert_unbiased(sampler: &dyn LightSampler, lights: &[Box<dyn Light>]) {
+      let points = [(Vector::zero(), Vector::new(0.0, 1.0, 0.0)),
+                    (Vector::new(1.0, 0.5, 1.0), Vector::new(1.0, 0.0, 0.0)),
+                    (Vector::new(-2.0, 1.0, -1.0), Vector::new(0.0, 0.6, 0.8))];
+      let n = 20000;
+      for &(p, normal) in points.iter() {
+         let all: Float = lights.iter().map(|l| contribution(&**l, p, normal)).sum();
+         let mut estimate = 0.0;
+         let mut counts = vec![0; lights.len()];
+         let mut probs = vec![0.0; lights.len()];
+         for k in 0 .. n {
+            if let Some((i, prob)) = sampler.select(p, normal, (k as Float + 0.5) / n as Float) {
+               estimate += contribution(&*lights[i], p, normal) / prob;
+               counts[i] += 1;
+               probs[i] = prob;
+            }
+         }
+         estimate /= n as Float;
+         assert!((estimate - all).abs() < 1e-3 * all, "{} != {}", estimate, all);
+         for i in 0 .. lights.len() {
+            let freq = counts[i] as Float / n as Float;
+            assert!((freq - probs[i]).abs() < 1e-3, "light {} chosen {} of the time, not {}", i, freq, probs[i]);
+         }
+      }
+   }
+
+   #[test]
+   fn power_sampling_is_unbiased() {
+      let lights = lights();
+      assert_unbiased(&PowerSampler::new(&lights), &lights);
+   }
+
+   #[test]
+   fn tree_sampling_is_unbiased() {
+      let lights = lights();
+      assert_unbiased(&LightTree::new(&lights), &lights);
+   }
+
+   // The centre of an area light may be below a surface that part of it
+   // still lights.
+   #[test]
+   fn tree_keeps_lights_partly_above() {
+      let lights: Vec<Box<dyn Light>> = vec![Box::new(AreaLight::new(Vector::new(-1.0, -0.5, 0.0), Vector::new(0.0, 2.0, 0.0),
+                                                                 Vector::new(0.0, 0.0, 2.0), Color::new(1.0, 1.0, 1.0), 1.0, 1))];
+      let tree = LightTree::new(&lights);
+      assert_eq!(tree.select(Vector::new(0.0, 0.6, 5.0), Vector::new(0.0, 1.0, 0.0), 0.5), Some((0, 1.0)));
+      assert_eq!(tree.select(Vector::new(0.0, 2.0, 5.0), Vector::new(0.0, 1.0, 0.0), 0.5), None);
+   }
+}
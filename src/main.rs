@@ -15,15 +15,44 @@
 
 extern crate byteorder;
 extern crate toml;
+extern crate flate2;
 
 mod vector;
+mod matrix;
+mod aabb;
 mod ray;
+mod simd;
+mod packet;
 mod geometry;
 mod camera;
 mod color;
 mod brdf;
 mod light;
+mod image;
+mod exr;
+mod environment;
+mod sampler;
+mod background;
+mod ies;
+mod lightsampler;
+mod mesh;
+mod ply;
+mod stl;
+mod bezier;
+mod subdivision;
+mod polynomial;
+mod instance;
+mod accelerator;
+mod bvh;
+mod grid;
+mod kdtree;
+mod sdf;
+mod metaball;
+mod curve;
+mod heightfield;
 mod raytracer;
+#[cfg(test)]
+mod testfile;
 
 use std::env;
 use camera::Camera;
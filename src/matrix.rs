@@ -0,0 +1,288 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::ops::Mul;
+use vector::{Vector, gamma, Float};
+use toml::Value;
+
+// Row major 4x4 affine transform acting on column vectors, so in a * b
+// the transform b is applied first.
+#[derive(Debug,Copy,Clone)]
+pub struct Matrix {
+   pub m: [[Float; 4]; 4],
+}
+
+impl Matrix {
+
+   // Constructors
+   pub fn identity() -> Matrix {
+      Matrix { m: [[1.0, 0.0, 0.0, 0.0],
+                   [0.0, 1.0, 0.0, 0.0],
+                   [0.0, 0.0, 1.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]] }
+   }
+
+   pub fn translation(v: Vector) -> Matrix {
+      let mut r = Matrix::identity();
+      r.m[0][3] = v.x;
+      r.m[1][3] = v.y;
+      r.m[2][3] = v.z;
+      r
+   }
+
+   pub fn scaling(v: Vector) -> Matrix {
+      let mut r = Matrix::identity();
+      r.m[0][0] = v.x;
+      r.m[1][1] = v.y;
+      r.m[2][2] = v.z;
+      r
+   }
+
+   // Rotations about the coordinate axes, angles in degrees.
+   pub fn rotation_x(degrees: Float) -> Matrix {
+      let (s, c) = degrees.to_radians().sin_cos();
+      let mut r = Matrix::identity();
+      r.m[1][1] = c;
+      r.m[1][2] = -s;
+      r.m[2][1] = s;
+      r.m[2][2] = c;
+      r
+   }
+
+   pub fn rotation_y(degrees: Float) -> Matrix {
+      let (s, c) = degrees.to_radians().sin_cos();
+      let mut r = Matrix::identity();
+      r.m[0][0] = c;
+      r.m[0][2] = s;
+      r.m[2][0] = -s;
+      r.m[2][2] = c;
+      r
+   }
+
+   pub fn rotation_z(degrees: Float) -> Matrix {
+      let (s, c) = degrees.to_radians().sin_cos();
+      let mut r = Matrix::identity();
+      r.m[0][0] = c;
+      r.m[0][1] = -s;
+      r.m[1][0] = s;
+      r.m[1][1] = c;
+      r
+   }
+
+   // Gauss-Jordan elimination with partial pivoting, None if singular.
+   pub fn inverse(&self) -> Option<Matrix> {
+      let mut a = self.m;
+      let mut r = Matrix::identity().m;
+
+      for col in 0 .. 4 {
+         let mut pivot = col;
+         for row in col + 1 .. 4 {
+            if a[row][col].abs() > a[pivot][col].abs() {
+               pivot = row;
+            }
+         }
+         if a[pivot][col].abs() < 1e-12 {
+            return None;
+         }
+         a.swap(col, pivot);
+         r.swap(col, pivot);
+
+         let inv = 1.0 / a[col][col];
+         for j in 0 .. 4 {
+            a[col][j] *= inv;
+            r[col][j] *= inv;
+         }
+
+         for row in 0 .. 4 {
+            if row != col {
+               let f = a[row][col];
+               for j in 0 .. 4 {
+                  a[row][j] -= f * a[col][j];
+                  r[row][j] -= f * r[col][j];
+               }
+            }
+         }
+      }
+
+      Some(Matrix { m: r })
+   }
+
+   // Applying a transform to points, directions and surface normals.
+   pub fn transform_point(&self, p: Vector) -> Vector {
+      let m = &self.m;
+      Vector::new(m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+                  m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+                  m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3])
+   }
+
+   // Bound on the error of transform_point(p), where p itself is known to
+   // within error in each axis.
+   pub fn transform_error(&self, p: Vector, error: Vector) -> Vector {
+      let row = |m: &[Float; 4]| {
+         (m[0] * p.x).abs() + (m[1] * p.y).abs() + (m[2] * p.z).abs() + m[3].abs()
+      };
+      let spread = |m: &[Float; 4]| m[0].abs() * error.x + m[1].abs() * error.y + m[2].abs() * error.z;
+      let m = &self.m;
+      Vector::new(row(&m[0]), row(&m[1]), row(&m[2])) * gamma(3) +
+         Vector::new(spread(&m[0]), spread(&m[1]), spread(&m[2])) * (1.0 + gamma(3))
+   }
+
+   pub fn transform_vector(&self, v: Vector) -> Vector {
+      let m = &self.m;
+      Vector::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+                  m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+                  m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+   }
+
+   // Normals transform by the inverse transpose, so this is called on the
+   // inverse of the transform applied to the surface.
+   pub fn transform_normal(&self, n: Vector) -> Vector {
+      let m = &self.m;
+      Vector::new(m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+                  m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+                  m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z)
+   }
+}
+
+// Scale, rotation and translation applied in that order, kept apart so that
+// two of them can be interpolated without shearing. scale is read as a
+// single float or a list of three, rotate as a list of angles in degrees
+// about x, y and z, applied in that order.
+#[derive(Debug,Copy,Clone)]
+pub struct Transform {
+   pub scale: Vector,
+   pub rotate: Vector,
+   pub translate: Vector,
+}
+
+impl Transform {
+   pub fn identity() -> Transform {
+      Transform { scale: Vector::new(1.0, 1.0, 1.0), rotate: Vector::zero(), translate: Vector::zero() }
+   }
+
+   // Whether obj has any of the keys read by import.
+   pub fn given(obj: &Value) -> bool {
+      ["scale", "rotate", "translate"].iter().any(|k| obj.lookup(k).is_some())
+   }
+
+   // Reads the keys of obj, keeping those it doesn't give from base.
+   pub fn import(obj: &Value, base: Transform) -> Result<Transform, String> {
+      let mut t = base;
+      if let Some(s) = obj.lookup("scale") {
+         t.scale = match s.as_float() {
+            Some(f) => Vector::new(f as Float, f as Float, f as Float),
+            None => import_vector(s)?,
+         };
+      }
+      if let Some(r) = obj.lookup("rotate") {
+         t.rotate = import_vector(r)?;
+      }
+      if let Some(v) = obj.lookup("translate") {
+         t.translate = import_vector(v)?;
+      }
+      Ok(t)
+   }
+
+   pub fn matrix(&self) -> Matrix {
+      let r = self.rotate;
+      Matrix::translation(self.translate) *
+         Matrix::rotation_z(r.z) * Matrix::rotation_y(r.y) * Matrix::rotation_x(r.x) *
+         Matrix::scaling(self.scale)
+   }
+
+   // Componentwise blend, s = 0 giving self.
+   pub fn lerp(&self, other: &Transform, s: Float) -> Transform {
+      let mix = |a: Vector, b: Vector| a + (b - a) * s;
+      Transform {
+         scale: mix(self.scale, other.scale),
+         rotate: mix(self.rotate, other.rotate),
+         translate: mix(self.translate, other.translate),
+      }
+   }
+}
+
+pub fn import_vector(v: &Value) -> Result<Vector, String> {
+   let s = v.as_slice().ok_or("Invalid list.")?;
+   if s.len() != 3 {
+      return Err("Expected three elements.".to_string());
+   }
+   let mut f = [0.0; 3];
+   for i in 0 .. 3 {
+      f[i] = s[i].as_float().ok_or("Invalid float.")? as Float;
+   }
+   Ok(Vector::new(f[0], f[1], f[2]))
+}
+
+// Operator overloads
+
+impl Mul for Matrix {
+    type Output = Matrix;
+
+    fn mul(self, rhs: Matrix) -> Matrix {
+        let mut r = [[0.0; 4]; 4];
+        for (i, row) in r.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                for k in 0 .. 4 {
+                    *x += self.m[i][k] * rhs.m[k][j];
+                }
+            }
+        }
+        Matrix { m: r }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::Matrix;
+   use vector::{Vector, dot};
+
+   fn is_identity(m: &Matrix) -> bool {
+      (0 .. 4).all(|i| (0 .. 4).all(|j| (m.m[i][j] - if i == j { 1.0 } else { 0.0 }).abs() < 1e-5))
+   }
+
+   #[test]
+   fn inverse_undoes_the_transform() {
+      let m = Matrix::translation(Vector::new(1.0, -2.0, 3.0)) * Matrix::rotation_y(30.0) *
+              Matrix::rotation_x(-75.0) * Matrix::scaling(Vector::new(2.0, 0.5, 4.0));
+      let inv = m.inverse().unwrap();
+      assert!(is_identity(&(m * inv)));
+      assert!(is_identity(&(inv * m)));
+
+      // a zero in the leading position needs a row swap
+      let mut swap = Matrix::identity();
+      swap.m[0] = [0.0, 1.0, 0.0, 0.0];
+      swap.m[1] = [1.0, 0.0, 0.0, 0.0];
+      assert!(is_identity(&(swap * swap.inverse().unwrap())));
+   }
+
+   #[test]
+   fn singular_matrices_have_no_inverse() {
+      assert!(Matrix::scaling(Vector::new(1.0, 0.0, 1.0)).inverse().is_none());
+      let mut m = Matrix::rotation_z(20.0);
+      m.m[2] = [m.m[0][0] * 2.0, m.m[0][1] * 2.0, m.m[0][2] * 2.0, 0.0];
+      assert!(m.inverse().is_none());
+   }
+
+   #[test]
+   fn normals_stay_perpendicular() {
+      // under a non-uniform scale a tangent and normal pair only stays
+      // perpendicular with the inverse transpose
+      let m = Matrix::rotation_z(30.0) * Matrix::scaling(Vector::new(3.0, 1.0, 0.5));
+      let inv = m.inverse().unwrap();
+      let (t, n) = (Vector::new(1.0, -1.0, 0.0), Vector::new(1.0, 1.0, 1.0));
+      assert!(dot(m.transform_vector(t), inv.transform_normal(n)).abs() < 1e-5);
+      assert!(dot(m.transform_vector(t), m.transform_vector(n)).abs() > 0.1);
+   }
+}
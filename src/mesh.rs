@@ -0,0 +1,529 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::io::Read;
+use std::rc::Rc;
+use std::array;
+use vector::{Vector, cross, Float};
+use ray::Ray;
+use color::Color;
+use geometry::{Geometry, ShadeRec, intersect_triangle, intersect_triangle_packet, triangle_point};
+use packet::{RayPacket, Lanes, LANES};
+use aabb::Aabb;
+use accelerator::{self, Accelerator, Structure};
+use ply;
+use subdivision::PolyMesh;
+use stl;
+use toml::Value;
+
+// Indexed triangle mesh. Vertex data is shared between faces, each face
+// being an index triple into the position list. Normals and uvs use their
+// own index triples when faces supply them, otherwise when there is one per
+// position they share the position indices.
+pub struct TriangleMesh {
+   pub positions: Vec<Vector>,
+   pub normals: Vec<Vector>,
+   pub uvs: Vec<(Float, Float)>,
+   pub indices: Vec<[u32; 3]>,
+   pub normal_indices: Vec<[u32; 3]>,
+   pub uv_indices: Vec<[u32; 3]>,
+   pub groups: Vec<Group>,
+   // group of each face, empty while every face is in the first group
+   pub face_groups: Vec<u16>,
+}
+
+// Marks a face without normals or uvs in the explicit index lists.
+pub const NO_INDEX: u32 = u32::MAX;
+
+#[derive(Debug,Clone)]
+pub struct Group {
+   pub name: String,
+   pub color: Color,
+}
+
+// Reference to a single face of a shared mesh.
+#[derive(Clone)]
+pub struct MeshTriangle {
+   mesh: Rc<TriangleMesh>,
+   index: u32,
+}
+
+// Scene geometry made of every face of a mesh, found through an
+// acceleration structure.
+pub struct Mesh {
+   triangles: Vec<MeshTriangle>,
+   accelerator: Box<dyn Accelerator>,
+}
+
+impl TriangleMesh {
+   pub fn new(color: Color) -> TriangleMesh {
+      TriangleMesh {
+         positions: Vec::new(),
+         normals: Vec::new(),
+         uvs: Vec::new(),
+         indices: Vec::new(),
+         normal_indices: Vec::new(),
+         uv_indices: Vec::new(),
+         groups: vec![Group { name: String::new(), color }],
+         face_groups: Vec::new(),
+      }
+   }
+
+   pub fn len(&self) -> usize {
+      self.indices.len()
+   }
+
+   pub fn push_face(&mut self, v: [u32; 3], n: Option<[u32; 3]>, uv: Option<[u32; 3]>, group: u16) {
+      // the lists are filled in for earlier faces when first needed
+      let count = self.indices.len();
+      if n.is_some() || !self.normal_indices.is_empty() {
+         self.normal_indices.resize(count, [NO_INDEX; 3]);
+         self.normal_indices.push(n.unwrap_or([NO_INDEX; 3]));
+      }
+      if uv.is_some() || !self.uv_indices.is_empty() {
+         self.uv_indices.resize(count, [NO_INDEX; 3]);
+         self.uv_indices.push(uv.unwrap_or([NO_INDEX; 3]));
+      }
+      if group != 0 || !self.face_groups.is_empty() {
+         self.face_groups.resize(count, 0);
+         self.face_groups.push(group);
+      }
+      self.indices.push(v);
+   }
+
+   pub fn vertices(&self, face: usize) -> (Vector, Vector, Vector) {
+      let i = self.indices[face];
+      (self.positions[i[0] as usize], self.positions[i[1] as usize], self.positions[i[2] as usize])
+   }
+
+   // Shading normal at barycentric u, v, falling back to the face normal.
+   pub fn normal(&self, face: usize, u: Float, v: Float) -> Vector {
+      let i = if !self.normal_indices.is_empty() {
+         self.normal_indices[face]
+      } else if !self.normals.is_empty() && self.normals.len() == self.positions.len() {
+         self.indices[face]
+      } else {
+         [NO_INDEX; 3]
+      };
+
+      if i[0] == NO_INDEX {
+         let (v0, v1, v2) = self.vertices(face);
+         return cross(v1 - v0, v2 - v0).normalize();
+      }
+
+      (self.normals[i[0] as usize] * (1.0 - u - v) + self.normals[i[1] as usize] * u
+       + self.normals[i[2] as usize] * v).normalize()
+   }
+
+   // Texture coordinates at the corners of a face, if it has any.
+   pub fn uvs(&self, face: usize) -> Option<[(Float, Float); 3]> {
+      let i = if !self.uv_indices.is_empty() {
+         self.uv_indices[face]
+      } else if !self.uvs.is_empty() && self.uvs.len() == self.positions.len() {
+         self.indices[face]
+      } else {
+         return None;
+      };
+
+      if i[0] == NO_INDEX {
+         return None;
+      }
+      Some([self.uvs[i[0] as usize], self.uvs[i[1] as usize], self.uvs[i[2] as usize]])
+   }
+
+   pub fn color(&self, face: usize) -> Color {
+      match self.face_groups.get(face) {
+         Some(&g) => self.groups[g as usize].color,
+         None => self.groups[0].color,
+      }
+   }
+
+   // Wavefront OBJ. Polygons are triangulated as fans and usemtl switches
+   // group, taking its color from Kd in any mtllib that can be read.
+   pub fn load_obj(path: &Path, color: Color) -> Result<TriangleMesh, String> {
+      let data = read_file(path)?;
+      let dir = path.parent().unwrap_or(Path::new(""));
+
+      let mut mesh = TriangleMesh::new(color);
+      let mut materials = HashMap::new();
+      let mut group = 0;
+
+      for (lineno, line) in data.lines().enumerate() {
+         let mut tokens = line.split_whitespace();
+         let err = |e: &str| format!("{}:{}: {}", path.display(), lineno + 1, e);
+
+         match tokens.next() {
+            Some("v") => {
+               let v = parse_floats(tokens, 3).map_err(|e| err(&e))?;
+               mesh.positions.push(Vector::new(v[0], v[1], v[2]));
+            }
+            Some("vn") => {
+               let v = parse_floats(tokens, 3).map_err(|e| err(&e))?;
+               mesh.normals.push(Vector::new(v[0], v[1], v[2]).normalize());
+            }
+            Some("vt") => {
+               let v = parse_floats(tokens, 2).map_err(|e| err(&e))?;
+               mesh.uvs.push((v[0], v[1]));
+            }
+            Some("f") => {
+               let mut corners = Vec::new();
+               for t in tokens {
+                  let c = parse_corner(t, mesh.positions.len(), mesh.uvs.len(), mesh.normals.len())
+                             .map_err(|e| err(&e))?;
+                  corners.push(c);
+               }
+               if corners.len() < 3 {
+                  return Err(err("Face with fewer than 3 vertices."));
+               }
+               for i in 1 .. corners.len() - 1 {
+                  let (a, b, c) = (corners[0], corners[i], corners[i + 1]);
+                  let uv = match (a.1, b.1, c.1) {
+                     (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                     _ => None,
+                  };
+                  let n = match (a.2, b.2, c.2) {
+                     (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                     _ => None,
+                  };
+                  mesh.push_face([a.0, b.0, c.0], n, uv, group);
+               }
+            }
+            Some("mtllib") => materials.extend(load_mtllib(dir, tokens)),
+            Some("usemtl") => {
+               let name = tokens.collect::<Vec<&str>>().join(" ");
+               group = use_material(&mut mesh.groups, &materials, name, color).map_err(|e| err(&e))?;
+            }
+            _ => {},
+         }
+      }
+
+      // unreferenced normals and uvs must not be mistaken for per vertex data
+      if mesh.normal_indices.is_empty() {
+         mesh.normals.clear();
+      }
+      if mesh.uv_indices.is_empty() {
+         mesh.uvs.clear();
+      }
+
+      Ok(mesh)
+   }
+}
+
+impl MeshTriangle {
+   pub fn new(mesh: Rc<TriangleMesh>, index: usize) -> MeshTriangle {
+      MeshTriangle { mesh, index: index as u32 }
+   }
+}
+
+impl Geometry for MeshTriangle {
+   fn bounds(&self) -> Aabb {
+      let (v0, v1, v2) = self.mesh.vertices(self.index as usize);
+      Aabb::from_points(&[v0, v1, v2])
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let (v0, v1, v2) = self.mesh.vertices(self.index as usize);
+      intersect_triangle(r, v0, v1, v2, tmin, tmax).map(|(t, u, v)| self.shade(r, t, u, v))
+   }
+}
+
+impl MeshTriangle {
+   fn shade(&self, r: Ray, t: Float, u: Float, v: Float) -> ShadeRec {
+      let face = self.index as usize;
+      let (v0, v1, v2) = self.mesh.vertices(face);
+      let (e1, e2) = (v1 - v0, v2 - v0);
+      let mut sr = ShadeRec::new(r, t, self.mesh.normal(face, u, v), self.mesh.color(face));
+      let (p, error) = triangle_point(v0, v1, v2, u, v);
+      sr.set_hit_point(p, error);
+      sr.set_geometric_normal(cross(e1, e2).normalize());
+
+      // tangents follow the texture coordinates where they aren't
+      // degenerate, otherwise the barycentric ones
+      sr.set_surface((u, v), e1, e2);
+      if let Some(uv) = self.mesh.uvs(face) {
+         let (du1, dv1) = (uv[1].0 - uv[0].0, uv[1].1 - uv[0].1);
+         let (du2, dv2) = (uv[2].0 - uv[0].0, uv[2].1 - uv[0].1);
+         let w = 1.0 - u - v;
+         sr.uv = (uv[0].0 * w + uv[1].0 * u + uv[2].0 * v, uv[0].1 * w + uv[1].1 * u + uv[2].1 * v);
+         let det = du1 * dv2 - dv1 * du2;
+         if det.abs() > 1e-12 {
+            let inv = 1.0 / det;
+            sr.dpdu = (e1 * dv2 - e2 * dv1) * inv;
+            sr.dpdv = (e2 * du1 - e1 * du2) * inv;
+         }
+      }
+      sr
+   }
+}
+
+impl Mesh {
+   pub fn new(mesh: Rc<TriangleMesh>, structure: Structure) -> Mesh {
+      let triangles: Vec<MeshTriangle> = (0 .. mesh.len()).map(|i| MeshTriangle::new(mesh.clone(), i)).collect();
+      let bounds: Vec<Aabb> = triangles.iter().map(|t| t.bounds()).collect();
+
+      Mesh {
+         accelerator: accelerator::build(structure, &bounds),
+         triangles,
+      }
+   }
+
+   pub fn import(obj: &Value, dir: &Path, structure: Structure) -> Result<Mesh, String> {
+      let file = obj.lookup("file").and_then(|v| v.as_str()).ok_or("Missing file.")?;
+      let color = import_color(obj)?;
+      let subdivide = match obj.lookup("subdivide") {
+         Some(v) => v.as_integer().ok_or("Invalid integer.")? as usize,
+         None => 0,
+      };
+
+      let path = dir.join(file);
+      let ext = path.extension().and_then(|e| e.to_str());
+      if subdivide > 0 {
+         // subdivision needs the polygons before they're triangulated
+         if ext != Some("obj") {
+            return Err("Only OBJ meshes can be subdivided.".to_string());
+         }
+         let mut poly = PolyMesh::load_obj(&path, color)?;
+         for _ in 0 .. subdivide {
+            poly = poly.subdivide();
+         }
+         return Ok(Mesh::new(Rc::new(poly.to_triangles()), structure));
+      }
+
+      let mesh = match ext {
+         Some("obj") => TriangleMesh::load_obj(&path, color),
+         Some("ply") => ply::load(&path, color),
+         Some("stl") => stl::load(&path, color),
+         _ => Err(format!("Unsupported mesh format {}.", path.display())),
+      }?;
+
+      Ok(Mesh::new(Rc::new(mesh), structure))
+   }
+}
+
+impl Geometry for Mesh {
+   fn bounds(&self) -> Aabb {
+      self.accelerator.bounds()
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let mut hit = None;
+      let triangles = &self.triangles;
+      self.accelerator.closest(&r, tmin, tmax, &mut |i, tmax| {
+         triangles[i].intersect(r, tmin, tmax).map(|sr| {
+            let t = sr.t;
+            hit = Some(sr);
+            t
+         })
+      });
+      hit
+   }
+
+   // Faces are only shaded once the nearest hit of each lane is known.
+   fn intersect_packet(&self, r: &RayPacket, tmin: &Lanes, tmax: &Lanes) -> [Option<ShadeRec>; LANES] {
+      let mut found = [None; LANES];
+      let mut tmax = *tmax;
+      let triangles = &self.triangles;
+      self.accelerator.closest_packet(r, tmin, &mut tmax, &mut |i, tmax| {
+         let (v0, v1, v2) = triangles[i].mesh.vertices(triangles[i].index as usize);
+         let (hit, t, u, v) = intersect_triangle_packet(r, v0, v1, v2, tmin, tmax);
+         let bits = hit.bits();
+         if bits != 0 {
+            *tmax = hit.select(t, *tmax);
+            for k in 0 .. LANES {
+               if bits & 1 << k != 0 {
+                  found[k] = Some((i, t[k], u[k], v[k]));
+               }
+            }
+         }
+      });
+      array::from_fn(|k| found[k].map(|(i, t, u, v)| triangles[i].shade(r.ray(k), t, u, v)))
+   }
+}
+
+// Color for faces without a material, white unless given.
+pub fn import_color(obj: &Value) -> Result<Color, String> {
+   if obj.lookup("color").is_none() {
+      return Ok(Color::new(1.0, 1.0, 1.0));
+   }
+
+   let elements = vec!["color.0", "color.1", "color.2"];
+   let mut values = Vec::new();
+
+   for e in elements {
+      let value = obj.lookup(e).ok_or("Missing element.")?;
+      values.push(value.as_float().ok_or("Invalid float.")? as Float);
+   }
+   Ok(Color::new(values[0], values[1], values[2]))
+}
+
+pub fn read_file(path: &Path) -> Result<String, String> {
+   let mut fin = File::open(path).map_err(|e| format!("{} - {}", path.display(), e))?;
+   let mut data = String::new();
+   fin.read_to_string(&mut data).map_err(|e| format!("{} - {}", path.display(), e))?;
+   Ok(data)
+}
+
+pub fn parse_floats<'a, I: Iterator<Item=&'a str>>(tokens: I, n: usize) -> Result<Vec<Float>, String> {
+   let mut v = Vec::new();
+   for t in tokens.take(n) {
+      v.push(t.parse::<Float>().map_err(|_| format!("Invalid number {}.", t))?);
+   }
+   if v.len() < n {
+      return Err("Too few values.".to_string());
+   }
+   Ok(v)
+}
+
+// OBJ indices are 1 based, negative values count back from the most
+// recently defined element.
+fn parse_index(s: &str, count: usize) -> Result<u32, String> {
+   let i = s.parse::<i64>().map_err(|_| format!("Invalid index {}.", s))?;
+   let index = if i < 0 { count as i64 + i } else { i - 1 };
+   if index < 0 || index >= count as i64 {
+      return Err(format!("Index {} out of range.", s));
+   }
+   Ok(index as u32)
+}
+
+// v, v/vt, v//vn or v/vt/vn
+pub fn parse_corner(s: &str, nv: usize, nt: usize, nn: usize)
+   -> Result<(u32, Option<u32>, Option<u32>), String> {
+   let mut parts = s.split('/');
+   let v = parse_index(parts.next().unwrap_or(""), nv)?;
+   let t = match parts.next() {
+      Some(x) if !x.is_empty() => Some(parse_index(x, nt)?),
+      _ => None,
+   };
+   let n = match parts.next() {
+      Some(x) if !x.is_empty() => Some(parse_index(x, nn)?),
+      _ => None,
+   };
+   Ok((v, t, n))
+}
+
+// Colors of every material in the libraries named on an mtllib line.
+pub fn load_mtllib<'a, I: Iterator<Item = &'a str>>(dir: &Path, names: I) -> HashMap<String, Color> {
+   let mut materials = HashMap::new();
+   for name in names {
+      // a missing material library only loses colors
+      match load_mtl(&dir.join(name)) {
+         Ok(m) => materials.extend(m),
+         Err(e) => println!("Error reading material library {} - {}", name, e),
+      }
+   }
+   materials
+}
+
+// Group index for a usemtl name, adding a group the first time it's used.
+pub fn use_material(groups: &mut Vec<Group>, materials: &HashMap<String, Color>, name: String, color: Color)
+                    -> Result<u16, String> {
+   match groups.iter().position(|g| g.name == name) {
+      Some(i) => Ok(i as u16),
+      None if groups.len() > u16::MAX as usize => Err("Too many materials.".to_string()),
+      None => {
+         let c = *materials.get(&name).unwrap_or(&color);
+         groups.push(Group { name, color: c });
+         Ok((groups.len() - 1) as u16)
+      }
+   }
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, Color>, String> {
+   let data = read_file(path)?;
+   let mut materials = HashMap::new();
+   let mut current = None;
+
+   for line in data.lines() {
+      let mut tokens = line.split_whitespace();
+      match tokens.next() {
+         Some("newmtl") => current = Some(tokens.collect::<Vec<&str>>().join(" ")),
+         Some("Kd") => {
+            if let Some(ref name) = current {
+               let v = parse_floats(tokens, 3)?;
+               materials.insert(name.clone(), Color::new(v[0], v[1], v[2]));
+            }
+         }
+         _ => {},
+      }
+   }
+
+   Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{TriangleMesh, NO_INDEX, parse_index, parse_corner, parse_floats};
+   use color::Color;
+   use testfile::write;
+
+   #[test]
+   fn optional_face_data_is_filled_in() {
+      let mut mesh = TriangleMesh::new(Color::new(1.0, 1.0, 1.0));
+      mesh.push_face([0, 1, 2], Some([0, 1, 2]), None, 1);
+      mesh.push_face([0, 2, 3], None, Some([3, 4, 5]), 0);
+      assert_eq!(mesh.normal_indices, vec![[0, 1, 2], [NO_INDEX; 3]]);
+      assert_eq!(mesh.uv_indices, vec![[NO_INDEX; 3], [3, 4, 5]]);
+      assert_eq!(mesh.face_groups, vec![1, 0]);
+
+      let mut mesh = TriangleMesh::new(Color::new(1.0, 1.0, 1.0));
+      mesh.push_face([0, 1, 2], None, None, 0);
+      assert!(mesh.normal_indices.is_empty() && mesh.uv_indices.is_empty() && mesh.face_groups.is_empty());
+   }
+
+   #[test]
+   fn indices_are_one_based_or_relative() {
+      assert_eq!(parse_index("1", 3), Ok(0));
+      assert_eq!(parse_index("3", 3), Ok(2));
+      assert_eq!(parse_index("-1", 3), Ok(2));
+      assert_eq!(parse_index("-3", 3), Ok(0));
+      assert!(parse_index("0", 3).is_err());
+      assert!(parse_index("4", 3).is_err());
+      assert!(parse_index("-4", 3).is_err());
+      assert!(parse_index("x", 3).is_err());
+   }
+
+   #[test]
+   fn corners() {
+      assert_eq!(parse_corner("2", 3, 0, 0), Ok((1, None, None)));
+      assert_eq!(parse_corner("2/1", 3, 2, 0), Ok((1, Some(0), None)));
+      assert_eq!(parse_corner("2//-1", 3, 0, 4), Ok((1, None, Some(3))));
+      assert_eq!(parse_corner("1/2/3", 3, 2, 3), Ok((0, Some(1), Some(2))));
+      assert!(parse_corner("1/3/3", 3, 2, 3).is_err());
+      assert!(parse_corner("1//4", 3, 2, 3).is_err());
+   }
+
+   #[test]
+   fn floats() {
+      assert_eq!(parse_floats("1 2.5 -3 4".split_whitespace(), 3), Ok(vec![1.0, 2.5, -3.0]));
+      assert!(parse_floats("1 2".split_whitespace(), 3).is_err());
+      assert!(parse_floats("1 a 2".split_whitespace(), 3).is_err());
+   }
+
+   #[test]
+   fn loads_obj_as_fans() {
+      let path = write("quad.obj", b"v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+                                     usemtl a\nf 1/1 2/2 3/3 4/4\nusemtl b\nf -4 -2 -1\n");
+      let mesh = TriangleMesh::load_obj(&path, Color::new(1.0, 1.0, 1.0)).unwrap();
+      assert_eq!(mesh.len(), 3);
+      assert_eq!(mesh.groups.len(), 3);
+      assert_eq!(mesh.face_groups, vec![1, 1, 2]);
+      assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3], [0, 2, 3]]);
+      assert_eq!(mesh.uvs(1), Some([(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)]));
+      assert_eq!(mesh.uvs(2), None);
+   }
+}
@@ -0,0 +1,315 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Blobby surface where the summed field of a set of balls reaches a
+// threshold. Each ball contributes weight * (1 - r^2 / R^2)^2 within its
+// radius R, which along a ray is a quartic in t. Between the points where
+// the ray enters and leaves the balls the same ones are active, so the
+// surface is found by solving one quartic per span, with bisection as a
+// fallback when the closed form misses a sign change.
+
+use vector::{Vector, Float};
+use ray::Ray;
+use color::Color;
+use aabb::Aabb;
+use geometry::{Geometry, ShadeRec};
+use polynomial::{solve_cubic, solve_quartic};
+use toml::Value;
+
+// Relative accuracy of the roots found for t, well short of double
+// precision, which bounds the error of hit points in f64 builds.
+const ROOT_TOLERANCE: Float = 1e-9;
+
+#[derive(Debug,Copy,Clone)]
+pub struct Ball {
+   pub center: Vector,
+   pub radius: Float,
+   // negative weights carve into the other balls
+   pub weight: Float,
+}
+
+pub struct Metaballs {
+   balls: Vec<Ball>,
+   threshold: Float,
+   color: Color,
+   bounds: Aabb,
+}
+
+impl Metaballs {
+   pub fn new(balls: Vec<Ball>, threshold: Float, color: Color) -> Metaballs {
+      // a ball without a radius has no field, and would divide by zero
+      let balls: Vec<Ball> = balls.into_iter().filter(|b| b.radius > 0.0).collect();
+      // only balls that add to the field can reach the surface
+      let bounds = balls.iter().filter(|b| b.weight > 0.0).fold(Aabb::empty(), |a, b| {
+         let r = Vector::new(b.radius, b.radius, b.radius);
+         a.grow(b.center - r).grow(b.center + r)
+      });
+      Metaballs { balls, threshold, color, bounds }
+   }
+
+   pub fn import(obj: &Value) -> Result<Metaballs, String> {
+      let elements = vec!["threshold", "color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let mut balls = Vec::new();
+      for b in obj.lookup("ball").and_then(|v| v.as_slice()).ok_or("Missing ball.")? {
+         let elements = vec!["center.0", "center.1", "center.2", "radius"];
+         let mut v = Vec::new();
+
+         for e in elements {
+            let value = b.lookup(e).ok_or("Missing element.")?;
+            v.push(value.as_float().ok_or("Invalid float.")? as Float);
+         }
+         let weight = match b.lookup("weight") {
+            Some(w) => w.as_float().ok_or("Invalid float.")? as Float,
+            None => 1.0,
+         };
+         if v[3].is_nan() || v[3] <= 0.0 {
+            return Err("Ball radius must be positive.".to_string());
+         }
+
+         balls.push(Ball { center: Vector::new(v[0], v[1], v[2]), radius: v[3], weight });
+      }
+
+      Ok(Metaballs::new(balls, values[0], Color::new(values[1], values[2], values[3])))
+   }
+
+   // Outward normal, against the field gradient as the field grows
+   // towards the ball centres.
+   fn normal(&self, p: Vector) -> Vector {
+      let mut n = Vector::zero();
+      for b in self.balls.iter() {
+         let d = p - b.center;
+         let rr = b.radius * b.radius;
+         let s = d.magnitude_sq() / rr;
+         if s < 1.0 {
+            n = n + d * (4.0 * b.weight * (1.0 - s) / rr);
+         }
+      }
+      n.normalize()
+   }
+}
+
+impl Geometry for Metaballs {
+   fn bounds(&self) -> Aabb {
+      self.bounds
+   }
+
+   // the casts to f64 only widen when Float is f32
+   #[cfg_attr(feature = "f64", allow(clippy::unnecessary_cast))]
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let d = (r.direction.x as f64, r.direction.y as f64, r.direction.z as f64);
+      let dd = d.0 * d.0 + d.1 * d.1 + d.2 * d.2;
+
+      // polynomial in t of each ball's contribution and the span it covers,
+      // all in double precision so rays leaving the surface don't find it
+      // again
+      let mut spans = Vec::new();
+      for b in self.balls.iter() {
+         let o = (r.origin.x as f64 - b.center.x as f64, r.origin.y as f64 - b.center.y as f64,
+                  r.origin.z as f64 - b.center.z as f64);
+         let half_b = o.0 * d.0 + o.1 * d.1 + o.2 * d.2;
+         let c = o.0 * o.0 + o.1 * o.1 + o.2 * o.2;
+         let rr = b.radius as f64 * b.radius as f64;
+         let disc = half_b * half_b - dd * (c - rr);
+         if disc <= 0.0 {
+            continue;
+         }
+         let (t0, t1) = ((-half_b - disc.sqrt()) / dd, (-half_b + disc.sqrt()) / dd);
+         if t1 < tmin as f64 || t0 > tmax as f64 {
+            continue;
+         }
+
+         // 1 - r^2 / R^2 = alpha t^2 + beta t + gamma, then squared
+         let (alpha, beta, gamma) = (-dd / rr, -2.0 * half_b / rr, 1.0 - c / rr);
+         let w = b.weight as f64;
+         let poly = [w * gamma * gamma,
+                     w * 2.0 * beta * gamma,
+                     w * (beta * beta + 2.0 * alpha * gamma),
+                     w * 2.0 * alpha * beta,
+                     w * alpha * alpha];
+         spans.push((t0, t1, poly));
+      }
+      if spans.is_empty() {
+         return None;
+      }
+
+      let mut events: Vec<f64> = spans.iter().flat_map(|s| vec![s.0, s.1]).collect();
+      // the spans come from positive discriminants, so none are NaN
+      events.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+      for w in events.windows(2) {
+         let lo = w[0].max(tmin as f64);
+         let hi = w[1].min(tmax as f64);
+         if lo >= hi {
+            continue;
+         }
+
+         let mid = 0.5 * (w[0] + w[1]);
+         let mut poly = [-(self.threshold as f64), 0.0, 0.0, 0.0, 0.0];
+         for s in spans.iter().filter(|s| s.0 <= mid && mid <= s.1) {
+            for (a, b) in poly.iter_mut().zip(s.2.iter()) {
+               *a += b;
+            }
+         }
+
+         if let Some(t) = first_root(&poly, lo, hi) {
+            let t = t as Float;
+            let p = r.origin + r.direction * t;
+            let mut n = self.normal(p);
+            // negative weights can leave the gradient at zero
+            if !(n.x.is_finite() && n.y.is_finite() && n.z.is_finite()) {
+               n = -r.direction;
+            }
+            let mut sr = ShadeRec::new(r, t, n, self.color);
+            sr.error = sr.error + (r.direction * t).abs() * ROOT_TOLERANCE;
+            return Some(sr);
+         }
+      }
+
+      None
+   }
+}
+
+fn eval(p: &[f64; 5], t: f64) -> f64 {
+   (((p[4] * t + p[3]) * t + p[2]) * t + p[1]) * t + p[0]
+}
+
+// Smallest root of p in [lo, hi].
+fn first_root(p: &[f64; 5], lo: f64, hi: f64) -> Option<f64> {
+   let roots = if p[4].abs() > 1e-12 {
+      solve_quartic(*p)
+   } else {
+      solve_cubic([p[0], p[1], p[2], p[3]])
+   };
+   let best = roots.into_iter().filter(|&t| t >= lo && t <= hi).fold(f64::INFINITY, f64::min);
+   if best.is_finite() {
+      return Some(best);
+   }
+
+   // the closed form loses roots when it's badly conditioned, but a sign
+   // change means there must be one
+   bisect(p, lo, hi)
+}
+
+// A root of p in [lo, hi] if it changes sign there.
+fn bisect(p: &[f64; 5], lo: f64, hi: f64) -> Option<f64> {
+   let (mut a, mut b) = (lo, hi);
+   let fa = eval(p, a);
+   if fa * eval(p, b) > 0.0 {
+      return None;
+   }
+   for _ in 0 .. 50 {
+      let m = 0.5 * (a + b);
+      if (eval(p, m) > 0.0) == (fa > 0.0) {
+         a = m;
+      } else {
+         b = m;
+      }
+   }
+   Some(0.5 * (a + b))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Metaballs, Ball, first_root, bisect};
+   use vector::{Vector, Float, dot};
+   use color::Color;
+   use ray::Ray;
+   use geometry::Geometry;
+   use toml::{Parser, Value};
+
+   // (t - 1)(t - 2)(t - 3)(t - 4)
+   const QUARTIC: [f64; 5] = [24.0, -50.0, 35.0, -10.0, 1.0];
+
+   fn ray(o: Vector, d: Vector) -> Ray {
+      Ray { origin: o, direction: d, time: 0.0 }
+   }
+
+   #[test]
+   fn first_root_in_range() {
+      assert_eq!(first_root(&QUARTIC, 0.0, 5.0).map(|t| (t * 1e6).round()), Some(1e6));
+      assert_eq!(first_root(&QUARTIC, 1.5, 5.0).map(|t| (t * 1e6).round()), Some(2e6));
+      assert_eq!(first_root(&QUARTIC, 4.5, 5.0), None);
+      // without the quartic term, (t - 1)(t - 2)(t - 3)
+      let cubic = [-6.0, 11.0, -6.0, 1.0, 0.0];
+      assert_eq!(first_root(&cubic, 2.5, 5.0).map(|t| (t * 1e6).round()), Some(3e6));
+   }
+
+   #[test]
+   fn bisection_needs_a_sign_change() {
+      let t = bisect(&QUARTIC, 2.5, 3.7).unwrap();
+      assert!((t - 3.0).abs() < 1e-9);
+      assert_eq!(bisect(&QUARTIC, 1.2, 1.8), None);
+      // two roots in range leave no sign change to find
+      assert_eq!(bisect(&QUARTIC, 0.5, 2.5), None);
+   }
+
+   #[test]
+   fn single_ball_surface() {
+      // (1 - s)^2 = 1/4 at s = r^2 / R^2 = 1/2
+      let balls = vec![Ball { center: Vector::zero(), radius: 2.0, weight: 1.0 }];
+      let m = Metaballs::new(balls, 0.25, Color::new(1.0, 1.0, 1.0));
+      let sr = m.intersect(ray(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0)), 0.0, Float::INFINITY).unwrap();
+      assert!((sr.t - (5.0 - (2.0 as Float).sqrt())).abs() < 1e-4, "{}", sr.t);
+      // the normal points out of the blob, back along the ray
+      assert!(sr.normal.x < -0.999);
+   }
+
+   #[test]
+   fn negative_weights_carve() {
+      let balls = vec![Ball { center: Vector::zero(), radius: 1.0, weight: 1.0 },
+                       Ball { center: Vector::new(0.5, 0.0, 0.0), radius: 0.5, weight: -1.0 }];
+      let m = Metaballs::new(balls, 0.5, Color::new(1.0, 1.0, 1.0));
+      let edge = (1.0 - (0.5 as Float).sqrt()).sqrt();
+
+      // untouched on the far side
+      let r = ray(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+      let sr = m.intersect(r, 0.0, Float::INFINITY).unwrap();
+      assert!((sr.t - (5.0 - edge)).abs() < 1e-4, "{}", sr.t);
+
+      // but hollowed out where the negative ball overlaps
+      let r = ray(Vector::new(5.0, 0.0, 0.0), Vector::new(-1.0, 0.0, 0.0));
+      let sr = m.intersect(r, 0.0, Float::INFINITY).unwrap();
+      assert!(sr.t > 5.0 - 0.25 && sr.t < 5.0, "{}", sr.t);
+      assert!(dot(sr.normal, r.direction) < 0.0);
+
+      // and a negative ball alone has no surface
+      let balls = vec![Ball { center: Vector::zero(), radius: 1.0, weight: -1.0 }];
+      let m = Metaballs::new(balls, 0.5, Color::new(1.0, 1.0, 1.0));
+      let b = m.bounds();
+      assert!(b.min.x > b.max.x);
+      assert!(m.intersect(ray(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0)), 0.0, Float::INFINITY).is_none());
+   }
+
+   #[test]
+   fn zero_radius_balls() {
+      let parse = |s: &str| Value::Table(Parser::new(s).parse().unwrap());
+      let scene = "threshold = 0.5\ncolor = [1.0, 1.0, 1.0]\n\
+                   [[ball]]\ncenter = [0.0, 0.0, 0.0]\nradius = 0.0\n";
+      assert!(Metaballs::import(&parse(scene)).is_err());
+
+      // built directly they're dropped rather than giving NaN spans
+      let balls = vec![Ball { center: Vector::zero(), radius: 0.0, weight: 1.0 },
+                       Ball { center: Vector::zero(), radius: 1.0, weight: 1.0 }];
+      let m = Metaballs::new(balls, 0.5, Color::new(1.0, 1.0, 1.0));
+      assert!(m.intersect(ray(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0)), 0.0, Float::INFINITY).is_some());
+   }
+}
@@ -0,0 +1,595 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Packets of rays traced together. Vectors are stored a component at a
+// time across the lanes, and the lanes of each component are held in SIMD
+// registers, so one instruction does the same step for every ray. Boxes,
+// spheres, triangles, meshes and instances of them test a whole packet at
+// once; other geometry and shading go a ray at a time. Every lane does the
+// same arithmetic as the single ray code, in the same order, so both find
+// exactly the same hits. Vector itself stays scalar, as three components
+// would leave a quarter of a register idle and need shuffles for every dot
+// and cross product.
+
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, BitAnd, BitOr, Not, Index, IndexMut};
+use vector::{Vector, Float};
+use ray::Ray;
+use accelerator::inverse;
+use simd::{self, Reg, WIDTH};
+
+#[cfg(not(feature = "packet8"))]
+pub const LANES: usize = 4;
+#[cfg(feature = "packet8")]
+pub const LANES: usize = 8;
+
+const REGS: usize = LANES / WIDTH;
+
+// One value for each lane.
+#[derive(Copy,Clone)]
+pub struct Lanes([Reg; REGS]);
+
+// Lanes where a condition holds.
+#[derive(Copy,Clone)]
+pub struct Mask([Reg; REGS]);
+
+#[derive(Debug,Copy,Clone)]
+pub struct WideVector {
+   pub x: Lanes,
+   pub y: Lanes,
+   pub z: Lanes,
+}
+
+// Rays in lanes, with the reciprocals of their directions for box tests.
+// Lanes past the number of rays given repeat the first and should be left
+// inactive, which queries mark with a tmax below tmin.
+pub struct RayPacket {
+   pub origin: WideVector,
+   pub direction: WideVector,
+   pub inv_dir: WideVector,
+   rays: [Ray; LANES],
+}
+
+impl Lanes {
+   pub fn splat(x: Float) -> Lanes {
+      Lanes([simd::splat(x); REGS])
+   }
+
+   pub fn from_array(a: [Float; LANES]) -> Lanes {
+      let mut l = Lanes::splat(0.0);
+      for i in 0 .. REGS {
+         let mut part = [0.0; WIDTH];
+         part.copy_from_slice(&a[i * WIDTH .. (i + 1) * WIDTH]);
+         l.0[i] = simd::load(&part);
+      }
+      l
+   }
+
+   pub fn to_array(self) -> [Float; LANES] {
+      let mut a = [0.0; LANES];
+      for k in 0 .. LANES {
+         a[k] = self[k];
+      }
+      a
+   }
+
+   #[inline]
+   fn map<F: Fn(Reg) -> Reg>(self, f: F) -> Lanes {
+      let mut r = self.0;
+      for x in r.iter_mut() {
+         *x = f(*x);
+      }
+      Lanes(r)
+   }
+
+   #[inline]
+   fn zip<F: Fn(Reg, Reg) -> Reg>(self, other: Lanes, f: F) -> Lanes {
+      let mut r = self.0;
+      for (x, y) in r.iter_mut().zip(other.0.iter()) {
+         *x = f(*x, *y);
+      }
+      Lanes(r)
+   }
+
+   #[inline]
+   fn compare<F: Fn(Reg, Reg) -> Reg>(self, other: Lanes, f: F) -> Mask {
+      Mask(self.zip(other, f).0)
+   }
+
+   // self where it's less than other, otherwise other
+   #[inline]
+   pub fn min(self, other: Lanes) -> Lanes {
+      self.zip(other, simd::min)
+   }
+
+   // self where it's greater than other, otherwise other
+   #[inline]
+   pub fn max(self, other: Lanes) -> Lanes {
+      self.zip(other, simd::max)
+   }
+
+   #[inline]
+   pub fn sqrt(self) -> Lanes {
+      self.map(simd::sqrt)
+   }
+
+   #[inline]
+   pub fn lt(self, other: Lanes) -> Mask {
+      self.compare(other, simd::lt)
+   }
+
+   #[inline]
+   pub fn le(self, other: Lanes) -> Mask {
+      self.compare(other, simd::le)
+   }
+
+   #[inline]
+   pub fn gt(self, other: Lanes) -> Mask {
+      self.compare(other, simd::gt)
+   }
+
+   #[inline]
+   pub fn ge(self, other: Lanes) -> Mask {
+      self.compare(other, simd::ge)
+   }
+
+   // The least of the lanes, ignoring NaNs.
+   pub fn least(self) -> Float {
+      let mut m = Float::INFINITY;
+      for k in 0 .. LANES {
+         if self[k] < m {
+            m = self[k];
+         }
+      }
+      m
+   }
+}
+
+impl Mask {
+   // Lanes of a where the mask holds, otherwise of b.
+   #[inline]
+   pub fn select(self, a: Lanes, b: Lanes) -> Lanes {
+      let mut r = a.0;
+      for ((x, m), y) in r.iter_mut().zip(self.0.iter()).zip(b.0.iter()) {
+         *x = simd::select(*m, *x, *y);
+      }
+      Lanes(r)
+   }
+
+   // A bit for each lane where the mask holds, the first lane lowest.
+   #[inline]
+   pub fn bits(self) -> u32 {
+      let mut b = 0;
+      for i in 0 .. REGS {
+         b |= simd::bits(self.0[i]) << (i * WIDTH);
+      }
+      b
+   }
+
+   #[inline]
+   pub fn any(self) -> bool {
+      self.bits() != 0
+   }
+
+   #[inline]
+   pub fn holds(self, k: usize) -> bool {
+      self.bits() & 1 << k != 0
+   }
+}
+
+impl WideVector {
+   // The same vector in every lane.
+   pub fn splat(v: Vector) -> WideVector {
+      WideVector { x: Lanes::splat(v.x), y: Lanes::splat(v.y), z: Lanes::splat(v.z) }
+   }
+
+   pub fn from_vectors(v: &[Vector; LANES]) -> WideVector {
+      WideVector {
+         x: lanes(|k| v[k].x),
+         y: lanes(|k| v[k].y),
+         z: lanes(|k| v[k].z),
+      }
+   }
+}
+
+impl RayPacket {
+   pub fn new(rays: &[Ray]) -> RayPacket {
+      let mut r = [rays[0]; LANES];
+      for (k, ray) in rays.iter().take(LANES).enumerate() {
+         r[k] = *ray;
+      }
+      let mut origin = [Vector::zero(); LANES];
+      let mut direction = [Vector::zero(); LANES];
+      let mut inv_dir = [Vector::zero(); LANES];
+      for k in 0 .. LANES {
+         origin[k] = r[k].origin;
+         direction[k] = r[k].direction;
+         inv_dir[k] = inverse(r[k].direction);
+      }
+      RayPacket {
+         origin: WideVector::from_vectors(&origin),
+         direction: WideVector::from_vectors(&direction),
+         inv_dir: WideVector::from_vectors(&inv_dir),
+         rays: r,
+      }
+   }
+
+   pub fn ray(&self, k: usize) -> Ray {
+      self.rays[k]
+   }
+}
+
+// Lanes set one at a time.
+pub fn lanes<F: Fn(usize) -> Float>(f: F) -> Lanes {
+   let mut l = [0.0; LANES];
+   for (k, x) in l.iter_mut().enumerate() {
+      *x = f(k);
+   }
+   Lanes::from_array(l)
+}
+
+#[inline]
+pub fn dot(a: &WideVector, b: &WideVector) -> Lanes {
+   a.x * b.x + a.y * b.y + a.z * b.z
+}
+
+#[inline]
+pub fn cross(a: &WideVector, b: &WideVector) -> WideVector {
+   WideVector {
+      x: a.y * b.z - a.z * b.y,
+      y: a.z * b.x - a.x * b.z,
+      z: a.x * b.y - a.y * b.x,
+   }
+}
+
+// Operator overloads
+
+impl Index<usize> for Lanes {
+    type Output = Float;
+
+    fn index(&self, k: usize) -> &Float {
+        assert!(k < LANES);
+        // registers hold their floats in lane order
+        unsafe { &*(self.0.as_ptr() as *const Float).add(k) }
+    }
+}
+
+impl IndexMut<usize> for Lanes {
+    fn index_mut(&mut self, k: usize) -> &mut Float {
+        assert!(k < LANES);
+        unsafe { &mut *(self.0.as_mut_ptr() as *mut Float).add(k) }
+    }
+}
+
+impl fmt::Debug for Lanes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_array().fmt(f)
+    }
+}
+
+impl Add for Lanes {
+    type Output = Lanes;
+
+    #[inline]
+    fn add(self, rhs: Lanes) -> Lanes {
+        self.zip(rhs, simd::add)
+    }
+}
+
+impl Sub for Lanes {
+    type Output = Lanes;
+
+    #[inline]
+    fn sub(self, rhs: Lanes) -> Lanes {
+        self.zip(rhs, simd::sub)
+    }
+}
+
+impl Mul for Lanes {
+    type Output = Lanes;
+
+    #[inline]
+    fn mul(self, rhs: Lanes) -> Lanes {
+        self.zip(rhs, simd::mul)
+    }
+}
+
+impl Div for Lanes {
+    type Output = Lanes;
+
+    #[inline]
+    fn div(self, rhs: Lanes) -> Lanes {
+        self.zip(rhs, simd::div)
+    }
+}
+
+impl BitAnd for Mask {
+    type Output = Mask;
+
+    #[inline]
+    fn bitand(self, rhs: Mask) -> Mask {
+        Mask(Lanes(self.0).zip(Lanes(rhs.0), simd::and).0)
+    }
+}
+
+impl BitOr for Mask {
+    type Output = Mask;
+
+    #[inline]
+    fn bitor(self, rhs: Mask) -> Mask {
+        Mask(Lanes(self.0).zip(Lanes(rhs.0), simd::or).0)
+    }
+}
+
+impl Not for Mask {
+    type Output = Mask;
+
+    #[inline]
+    fn not(self) -> Mask {
+        Mask(Lanes(self.0).map(simd::not).0)
+    }
+}
+
+impl Add for WideVector {
+    type Output = WideVector;
+
+    #[inline]
+    fn add(self, rhs: WideVector) -> WideVector {
+        WideVector {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+            z: self.z + rhs.z
+        }
+    }
+}
+
+impl Sub for WideVector {
+    type Output = WideVector;
+
+    #[inline]
+    fn sub(self, rhs: WideVector) -> WideVector {
+        WideVector {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+   use std::array;
+   use std::rc::Rc;
+   use std::time::Instant;
+   use vector::{Vector, Float};
+   use vector::float::consts;
+   use ray::Ray;
+   use color::Color;
+   use matrix::Transform;
+   use mesh::{TriangleMesh, Mesh};
+   use instance::Instance;
+   use accelerator::{self, Structure};
+   use aabb::Aabb;
+   use geometry::{Geometry, Sphere, Triangle};
+   use super::{RayPacket, Lanes, LANES, lanes};
+
+   struct Random(u32);
+
+   impl Random {
+      fn next(&mut self) -> Float {
+         self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+         (self.0 >> 8) as Float / (1 << 24) as Float
+      }
+
+      fn vector(&mut self, size: Float) -> Vector {
+         Vector::new(self.next() - 0.5, self.next() - 0.5, self.next() - 0.5) * (2.0 * size)
+      }
+   }
+
+   fn white() -> Color {
+      Color::new(1.0, 1.0, 1.0)
+   }
+
+   fn sphere_mesh(nu: usize, nv: usize, z: Float) -> TriangleMesh {
+      let mut m = TriangleMesh::new(white());
+      for j in 0 .. nv + 1 {
+         for i in 0 .. nu {
+            let th = j as Float / nv as Float * consts::PI;
+            let ph = i as Float / nu as Float * 2.0 * consts::PI;
+            m.positions.push(Vector::new(th.sin() * ph.cos(), th.cos(), th.sin() * ph.sin() + z));
+         }
+      }
+      for j in 0 .. nv {
+         for i in 0 .. nu {
+            let a = (j * nu + i) as u32;
+            let b = (j * nu + (i + 1) % nu) as u32;
+            let (c, d) = (a + nu as u32, b + nu as u32);
+            m.push_face([a, b, d], None, None, 0);
+            m.push_face([a, d, c], None, None, 0);
+         }
+      }
+      m
+   }
+
+   // Random rays from around the origin toward points near it, a few of
+   // them set up to start inside, skim or run parallel to the test shapes.
+   fn rays(random: &mut Random, n: usize) -> Vec<Ray> {
+      let mut rays = vec![
+         // along the faces and edges of the unit triangle and cube
+         Ray { origin: Vector::new(-1.0, 0.0, 0.0), direction: Vector::new(1.0, 0.0, 0.0), time: 0.0 },
+         Ray { origin: Vector::new(0.5, 0.5, 2.0), direction: Vector::new(0.0, 0.0, -1.0), time: 0.0 },
+         Ray { origin: Vector::new(0.0, 0.0, 2.0), direction: Vector::new(0.0, 0.0, -1.0), time: 0.0 },
+         // tangent to the unit sphere and from its centre
+         Ray { origin: Vector::new(1.0, 0.0, 3.0), direction: Vector::new(0.0, 0.0, -1.0), time: 0.0 },
+         Ray { origin: Vector::zero(), direction: Vector::new(0.0, 1.0, 0.0), time: 0.0 },
+         Ray { origin: Vector::new(0.0, 0.0, 3.0), direction: Vector::new(0.0, 0.0, -1.0), time: 0.0 },
+      ];
+      while rays.len() < n {
+         let o = random.vector(3.0);
+         let d = (random.vector(0.7) - o).normalize();
+         rays.push(Ray { origin: o, direction: d, time: 0.0 });
+      }
+      rays
+   }
+
+   // Intervals for a packet, some lanes left inactive or cut short.
+   fn intervals(random: &mut Random) -> (Lanes, Lanes) {
+      let tmin = lanes(|k| if k % 3 == 1 { 0.5 } else { 0.0 });
+      let tmax = Lanes::from_array(array::from_fn(|_| {
+         let x = random.next();
+         if x < 0.1 { Float::NEG_INFINITY } else if x < 0.3 { x * 10.0 } else { Float::INFINITY }
+      }));
+      (tmin, tmax)
+   }
+
+   fn same(a: Vector, b: Vector) -> bool {
+      a.x == b.x && a.y == b.y && a.z == b.z
+   }
+
+   // Each lane of the packet test finds what its ray does alone.
+   fn assert_packets_match(g: &dyn Geometry, seed: u32) {
+      let mut random = Random(seed);
+      let rays = rays(&mut random, 64 * LANES);
+      for chunk in rays.chunks(LANES) {
+         let (tmin, tmax) = intervals(&mut random);
+         let packet = RayPacket::new(chunk);
+         let found = g.intersect_packet(&packet, &tmin, &tmax);
+         for k in 0 .. LANES {
+            let single = if tmax[k] >= tmin[k] { g.intersect(chunk[k], tmin[k], tmax[k]) } else { None };
+            match (&found[k], &single) {
+               (Some(a), Some(b)) => {
+                  let (p, q) = ((a.t, a.hit_point, a.normal), (b.t, b.hit_point, b.normal));
+                  assert!(p.0 == q.0 && same(p.1, q.1) && same(p.2, q.2), "{:?}: {:?} != {:?}", chunk[k], p, q);
+               }
+               (&None, &None) => {},
+               _ => panic!("lane {} of {:?}: {:?} against {:?}", k, chunk[k],
+                           found[k].as_ref().map(|sr| sr.t), single.map(|sr| sr.t)),
+            }
+         }
+      }
+   }
+
+   #[test]
+   fn spheres_match_single_rays() {
+      assert_packets_match(&Sphere::new(0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0), 1);
+      assert_packets_match(&Sphere::new(0.3, -0.2, 0.1, 0.5, 1.0, 1.0, 1.0), 2);
+   }
+
+   #[test]
+   fn triangles_match_single_rays() {
+      let triangle = Triangle::new(Vector::zero(), Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), white());
+      assert_packets_match(&triangle, 3);
+      let triangle = Triangle::new(Vector::new(-1.0, -1.0, 0.5), Vector::new(1.0, 0.0, -0.5),
+                                   Vector::new(0.0, 1.0, 0.0), white());
+      assert_packets_match(&triangle, 4);
+   }
+
+   // Covers the packet walk of the bounding volume hierarchy and the lane
+   // at a time walk the other structures fall back on.
+   #[test]
+   fn meshes_match_single_rays() {
+      let m = Rc::new(sphere_mesh(24, 12, 0.0));
+      assert_packets_match(&Mesh::new(m.clone(), Structure::Bvh), 5);
+      assert_packets_match(&Mesh::new(m.clone(), Structure::Grid(2.0, 1)), 6);
+      assert_packets_match(&Mesh::new(m, Structure::KdTree), 7);
+   }
+
+   #[test]
+   fn instances_match_single_rays() {
+      let m = Rc::new(Mesh::new(Rc::new(sphere_mesh(24, 12, 0.0)), Structure::Bvh));
+      let t = Transform {
+         scale: Vector::new(0.5, 2.0, 1.5),
+         rotate: Vector::new(30.0, 10.0, 0.0),
+         translate: Vector::new(0.2, 0.0, -0.3),
+      };
+      assert_packets_match(&Instance::new(m, t.matrix()).unwrap(), 8);
+   }
+
+   // The packet walk of each structure reaches the same nearest box as
+   // single rays, with every lane given its own interval.
+   #[test]
+   fn closest_packet_matches_closest() {
+      let mut random = Random(9);
+      let mut boxes = Vec::new();
+      for _ in 0 .. 100 {
+         let c = random.vector(1.0);
+         let e = Vector::new(random.next(), random.next(), random.next()) * 0.2;
+         boxes.push(Aabb::new(c - e, c + e));
+      }
+      let rays = rays(&mut random, 64 * LANES);
+      for &s in [Structure::Bvh, Structure::Grid(2.0, 2), Structure::KdTree].iter() {
+         let a = accelerator::build(s, &boxes);
+         for chunk in rays.chunks(LANES) {
+            let (tmin, tmax) = intervals(&mut random);
+            let packet = RayPacket::new(chunk);
+            let mut nearest = tmax;
+            a.closest_packet(&packet, &tmin, &mut nearest, &mut |i, tmax| {
+               let t = boxes[i].hit_packet(&packet, &tmin, tmax);
+               *tmax = t.lt(*tmax).select(t, *tmax);
+            });
+            for k in 0 .. LANES {
+               let mut single = tmax[k];
+               if tmax[k] >= tmin[k] {
+                  let inv_dir = accelerator::inverse(chunk[k].direction);
+                  a.closest(&chunk[k], tmin[k], tmax[k], &mut |i, t| {
+                     let hit = boxes[i].hit(&chunk[k], inv_dir, tmin[k], t).map(|h| h.0);
+                     if let Some(t) = hit {
+                        single = t;
+                     }
+                     hit
+                  });
+               }
+               assert_eq!(nearest[k], single, "{:?} lane {} of {:?}", s, k, chunk[k]);
+            }
+         }
+      }
+   }
+
+   // Rays per second through a large mesh one at a time and as packets.
+   // Run with --ignored --nocapture in a release build.
+   #[test]
+   #[ignore]
+   fn throughput() {
+      let mesh = Mesh::new(Rc::new(sphere_mesh(512, 256, -3.0)), Structure::Bvh);
+      let n = 512;
+      let mut rays = Vec::new();
+      for y in 0 .. n {
+         for x in 0 .. n {
+            let d = Vector::new((x as Float / n as Float - 0.5) * 0.8, (y as Float / n as Float - 0.5) * 0.8, -1.0).normalize();
+            rays.push(Ray { origin: Vector::zero(), direction: d, time: 0.0 });
+         }
+      }
+      let (mut best_scalar, mut best_packet) = (1e9, 1e9);
+      for _ in 0 .. 7 {
+         let start = Instant::now();
+         let mut hits = 0;
+         for r in rays.iter() {
+            if mesh.intersect(*r, 0.0, Float::INFINITY).is_some() { hits += 1; }
+         }
+         let scalar = start.elapsed().as_secs_f64();
+         let start = Instant::now();
+         let mut packet_hits = 0;
+         for c in rays.chunks(LANES) {
+            let found = mesh.intersect_packet(&RayPacket::new(c), &Lanes::splat(0.0), &Lanes::splat(Float::INFINITY));
+            packet_hits += found.iter().filter(|h| h.is_some()).count();
+         }
+         let packet = start.elapsed().as_secs_f64();
+         assert_eq!(hits, packet_hits);
+         best_scalar = scalar.min(best_scalar);
+         best_packet = packet.min(best_packet);
+      }
+      println!("scalar {:.1}ms packets {:.1}ms speedup {:.2}", best_scalar * 1e3, best_packet * 1e3, best_scalar / best_packet);
+   }
+}
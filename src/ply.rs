@@ -0,0 +1,280 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Stanford PLY reader for ascii and binary files. Reads vertex positions,
+// normals and texture coordinates plus face vertex lists, any other
+// elements and properties are skipped.
+// http://paulbourke.net/dataformats/ply/
+
+use std::fs::File;
+use std::path::Path;
+use std::io::{Cursor, Read};
+use vector::{Vector, Float};
+use color::Color;
+use mesh::TriangleMesh;
+use byteorder::{LittleEndian, BigEndian, ReadBytesExt};
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+enum Format {
+   Ascii,
+   LittleEndian,
+   BigEndian,
+}
+
+#[derive(Debug,Copy,Clone)]
+enum Scalar {
+   I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+#[derive(Debug,Clone)]
+enum Property {
+   Scalar(String, Scalar),
+   List(String, Scalar, Scalar),
+}
+
+struct Element {
+   name: String,
+   count: usize,
+   properties: Vec<Property>,
+}
+
+// Source of values for either encoding.
+struct Body<'a> {
+   format: Format,
+   data: Cursor<&'a [u8]>,
+   tokens: Vec<&'a str>,
+   next: usize,
+}
+
+impl Scalar {
+   fn parse(s: &str) -> Result<Scalar, String> {
+      match s {
+         "char" | "int8" => Ok(Scalar::I8),
+         "uchar" | "uint8" => Ok(Scalar::U8),
+         "short" | "int16" => Ok(Scalar::I16),
+         "ushort" | "uint16" => Ok(Scalar::U16),
+         "int" | "int32" => Ok(Scalar::I32),
+         "uint" | "uint32" => Ok(Scalar::U32),
+         "float" | "float32" => Ok(Scalar::F32),
+         "double" | "float64" => Ok(Scalar::F64),
+         _ => Err(format!("Unknown property type {}.", s)),
+      }
+   }
+}
+
+impl<'a> Body<'a> {
+   fn read(&mut self, ty: Scalar) -> Result<f64, String> {
+      if self.format == Format::Ascii {
+         let t = self.tokens.get(self.next).ok_or("Truncated data.")?;
+         self.next += 1;
+         return t.parse::<f64>().map_err(|_| format!("Invalid number {}.", t));
+      }
+
+      let be = self.format == Format::BigEndian;
+      let d = &mut self.data;
+      let v = match ty {
+         Scalar::I8 => d.read_i8().map(|x| x as f64),
+         Scalar::U8 => d.read_u8().map(|x| x as f64),
+         Scalar::I16 => if be { d.read_i16::<BigEndian>() } else { d.read_i16::<LittleEndian>() }.map(|x| x as f64),
+         Scalar::U16 => if be { d.read_u16::<BigEndian>() } else { d.read_u16::<LittleEndian>() }.map(|x| x as f64),
+         Scalar::I32 => if be { d.read_i32::<BigEndian>() } else { d.read_i32::<LittleEndian>() }.map(|x| x as f64),
+         Scalar::U32 => if be { d.read_u32::<BigEndian>() } else { d.read_u32::<LittleEndian>() }.map(|x| x as f64),
+         Scalar::F32 => if be { d.read_f32::<BigEndian>() } else { d.read_f32::<LittleEndian>() }.map(|x| x as f64),
+         Scalar::F64 => if be { d.read_f64::<BigEndian>() } else { d.read_f64::<LittleEndian>() },
+      };
+      v.map_err(|_| "Truncated data.".to_string())
+   }
+}
+
+pub fn load(path: &Path, color: Color) -> Result<TriangleMesh, String> {
+   let mut fin = File::open(path).map_err(|e| format!("{} - {}", path.display(), e))?;
+   let mut data = Vec::new();
+   fin.read_to_end(&mut data).map_err(|e| format!("{} - {}", path.display(), e))?;
+
+   let marker = b"end_header";
+   let end = data.windows(marker.len()).position(|w| w == marker).ok_or("Missing end_header.")?;
+   let mut body_start = end + marker.len();
+   while body_start < data.len() && data[body_start] != b'\n' {
+      body_start += 1;
+   }
+   body_start += 1;
+
+   let header = String::from_utf8_lossy(&data[.. end]).into_owned();
+   let mut lines = header.lines();
+   if lines.next().map(|l| l.trim()) != Some("ply") {
+      return Err("Not a PLY file.".to_string());
+   }
+
+   let mut format = None;
+   let mut elements: Vec<Element> = Vec::new();
+   for line in lines {
+      let t: Vec<&str> = line.split_whitespace().collect();
+      match t.first().copied() {
+         Some("format") if t.len() >= 2 => {
+            format = Some(match t[1] {
+               "ascii" => Format::Ascii,
+               "binary_little_endian" => Format::LittleEndian,
+               "binary_big_endian" => Format::BigEndian,
+               f => return Err(format!("Unknown format {}.", f)),
+            });
+         }
+         Some("element") if t.len() >= 3 => {
+            let count = t[2].parse::<usize>().map_err(|_| format!("Invalid count {}.", t[2]))?;
+            elements.push(Element { name: t[1].to_string(), count, properties: Vec::new() });
+         }
+         Some("property") => {
+            let e = elements.last_mut().ok_or("Property before element.")?;
+            if t.len() >= 5 && t[1] == "list" {
+               e.properties.push(Property::List(t[4].to_string(),
+                                                Scalar::parse(t[2])?,
+                                                Scalar::parse(t[3])?));
+            } else if t.len() >= 3 {
+               e.properties.push(Property::Scalar(t[2].to_string(), Scalar::parse(t[1])?));
+            } else {
+               return Err(format!("Invalid property {}.", line));
+            }
+         }
+         _ => {},
+      }
+   }
+
+   let format = format.ok_or("Missing format.")?;
+   let ascii = if format == Format::Ascii {
+      String::from_utf8_lossy(&data[body_start.min(data.len()) ..]).into_owned()
+   } else {
+      String::new()
+   };
+   let mut body = Body {
+      format,
+      data: Cursor::new(&data[body_start.min(data.len()) ..]),
+      tokens: ascii.split_whitespace().collect(),
+      next: 0,
+   };
+
+   let mut mesh = TriangleMesh::new(color);
+   let mut faces = Vec::new();
+
+   for e in elements.iter() {
+      for _ in 0 .. e.count {
+         let mut p = [0.0; 3];
+         let mut n = [0.0; 3];
+         let mut uv = [0.0; 2];
+         let (mut has_n, mut has_uv) = (false, false);
+         let mut indices = Vec::new();
+
+         for prop in e.properties.iter() {
+            match *prop {
+               Property::Scalar(ref name, ty) => {
+                  let v = body.read(ty)? as Float;
+                  match name.as_str() {
+                     "x" => p[0] = v,
+                     "y" => p[1] = v,
+                     "z" => p[2] = v,
+                     "nx" => { n[0] = v; has_n = true; }
+                     "ny" => n[1] = v,
+                     "nz" => n[2] = v,
+                     "u" | "s" | "texture_u" => { uv[0] = v; has_uv = true; }
+                     "v" | "t" | "texture_v" => uv[1] = v,
+                     _ => {},
+                  }
+               }
+               Property::List(ref name, count_ty, item_ty) => {
+                  let count = body.read(count_ty)? as usize;
+                  let keep = e.name == "face" && (name == "vertex_indices" || name == "vertex_index");
+                  for _ in 0 .. count {
+                     let v = body.read(item_ty)?;
+                     if keep {
+                        indices.push(v as u32);
+                     }
+                  }
+               }
+            }
+         }
+
+         if e.name == "vertex" {
+            mesh.positions.push(Vector::new(p[0], p[1], p[2]));
+            if has_n {
+               mesh.normals.push(Vector::new(n[0], n[1], n[2]).normalize());
+            }
+            if has_uv {
+               mesh.uvs.push((uv[0], uv[1]));
+            }
+         } else if e.name == "face" {
+            for i in 1 .. indices.len().saturating_sub(1) {
+               faces.push([indices[0], indices[i], indices[i + 1]]);
+            }
+         }
+      }
+   }
+
+   // normals and uvs, when present, are per vertex and share its indices
+   for f in faces {
+      if f.iter().any(|&i| i as usize >= mesh.positions.len()) {
+         return Err("Face index out of range.".to_string());
+      }
+      mesh.push_face(f, None, None, 0);
+   }
+
+   Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::load;
+   use color::Color;
+   use byteorder::{BigEndian, WriteBytesExt};
+   use testfile::write;
+
+   const HEADER: &str = "element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+                                 element face 1\nproperty list uchar int vertex_indices\nend_header\n";
+
+   #[test]
+   fn reads_ascii() {
+      let path = write("ascii_test.ply", b"ply\nformat ascii 1.0\ncomment test\n\
+                        element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+                        property float nx\nproperty float ny\nproperty float nz\n\
+                        element face 1\nproperty list uchar int vertex_indices\nproperty uchar flags\n\
+                        end_header\n0 0 0 0 0 2\n1 0 0 0 0 2\n1 1 0 0 0 2\n0 1 0 0 0 2\n4 0 1 2 3 7\n");
+      let mesh = load(&path, Color::new(1.0, 1.0, 1.0)).unwrap();
+      assert_eq!(mesh.positions.len(), 4);
+      assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+      assert_eq!(mesh.normals.len(), 4);
+      assert_eq!(mesh.normals[3].z, 1.0);
+   }
+
+   #[test]
+   fn reads_big_endian() {
+      let mut data = format!("ply\nformat binary_big_endian 1.0\n{}", HEADER).into_bytes();
+      for v in [0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 2.0, 2.0, 0.0, 0.0, 2.0, 0.0f32].iter() {
+         data.write_f32::<BigEndian>(*v).unwrap();
+      }
+      data.push(3);
+      for i in [3, 1, 2].iter() {
+         data.write_i32::<BigEndian>(*i).unwrap();
+      }
+      let mesh = load(&write("be_test.ply", &data), Color::new(1.0, 1.0, 1.0)).unwrap();
+      assert_eq!(mesh.indices, vec![[3, 1, 2]]);
+      assert_eq!((mesh.positions[2].x, mesh.positions[2].y), (2.0, 2.0));
+   }
+
+   #[test]
+   fn rejects_bad_files() {
+      let bad = format!("ply\nformat ascii 1.0\n{}0 0 0\n1 0 0\n1 1 0\n0 1 0\n3 0 1 4\n", HEADER);
+      assert!(load(&write("bad_index.ply", bad.as_bytes()), Color::new(1.0, 1.0, 1.0)).is_err());
+      let short = format!("ply\nformat ascii 1.0\n{}0 0 0\n1 0 0\n", HEADER);
+      assert!(load(&write("short.ply", short.as_bytes()), Color::new(1.0, 1.0, 1.0)).is_err());
+      assert!(load(&write("not.ply", b"obj\nend_header\n"), Color::new(1.0, 1.0, 1.0)).is_err());
+   }
+}
@@ -0,0 +1,207 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Closed form polynomial root finders after Jochen Schwarze, "Cubic and
+// Quartic Roots", Graphics Gems I. Coefficients are given lowest power
+// first, so c[0] + c[1] x + c[2] x^2 + ... = 0. Roots are unordered.
+
+use std::f64::consts;
+
+const EQN_EPS: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+   x > -EQN_EPS && x < EQN_EPS
+}
+
+pub fn solve_quadric(c: [f64; 3]) -> Vec<f64> {
+   // normal form: x^2 + 2px + q = 0
+   let p = c[1] / (2.0 * c[2]);
+   let q = c[0] / c[2];
+   let d = p * p - q;
+
+   if is_zero(d) {
+      vec![-p]
+   } else if d < 0.0 {
+      Vec::new()
+   } else {
+      let sqrt_d = d.sqrt();
+      vec![sqrt_d - p, -sqrt_d - p]
+   }
+}
+
+pub fn solve_cubic(c: [f64; 4]) -> Vec<f64> {
+   // normal form: x^3 + Ax^2 + Bx + C = 0
+   let a = c[2] / c[3];
+   let b = c[1] / c[3];
+   let cc = c[0] / c[3];
+
+   // substitute x = y - A/3 to eliminate the quadric term: y^3 + 3py + 2q = 0
+   let sq_a = a * a;
+   let p = (-sq_a / 3.0 + b) / 3.0;
+   let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + cc) / 2.0;
+
+   // use Cardano's formula
+   let cb_p = p * p * p;
+   let d = q * q + cb_p;
+
+   let mut s = if is_zero(d) {
+      if is_zero(q) {
+         // one triple solution
+         vec![0.0]
+      } else {
+         // one single and one double solution
+         let u = (-q).cbrt();
+         vec![2.0 * u, -u]
+      }
+   } else if d < 0.0 {
+      // casus irreducibilis: three real solutions
+      let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+      let t = 2.0 * (-p).sqrt();
+      vec![t * phi.cos(), -t * (phi + consts::PI / 3.0).cos(), -t * (phi - consts::PI / 3.0).cos()]
+   } else {
+      // one real solution
+      let sqrt_d = d.sqrt();
+      vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+   };
+
+   for x in s.iter_mut() {
+      *x -= a / 3.0;
+   }
+   s
+}
+
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+   // normal form: x^4 + Ax^3 + Bx^2 + Cx + D = 0
+   let a = c[3] / c[4];
+   let b = c[2] / c[4];
+   let cc = c[1] / c[4];
+   let d = c[0] / c[4];
+
+   // substitute x = y - A/4 to eliminate the cubic term: y^4 + py^2 + qy + r = 0
+   let sq_a = a * a;
+   let p = -3.0 / 8.0 * sq_a + b;
+   let q = sq_a * a / 8.0 - a * b / 2.0 + cc;
+   let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * cc / 4.0 + d;
+
+   let mut s = if is_zero(r) {
+      // no absolute term: y(y^3 + py + q) = 0
+      let mut s = solve_cubic([q, p, 0.0, 1.0]);
+      s.push(0.0);
+      s
+   } else {
+      // solve the resolvent cubic and take its one real solution...
+      let z = solve_cubic([r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0])[0];
+
+      // ...to build two quadric equations
+      let mut u = z * z - r;
+      let mut v = 2.0 * z - p;
+
+      if is_zero(u) {
+         u = 0.0;
+      } else if u > 0.0 {
+         u = u.sqrt();
+      } else {
+         return Vec::new();
+      }
+
+      if is_zero(v) {
+         v = 0.0;
+      } else if v > 0.0 {
+         v = v.sqrt();
+      } else {
+         return Vec::new();
+      }
+
+      let mut s = solve_quadric([z - u, if q < 0.0 { -v } else { v }, 1.0]);
+      s.extend(solve_quadric([z + u, if q < 0.0 { v } else { -v }, 1.0]));
+      s
+   };
+
+   // resubstitute, then polish with Newton's method as the closed form
+   // loses precision when the coefficients differ greatly in magnitude
+   for x in s.iter_mut() {
+      *x -= a / 4.0;
+      for _ in 0 .. 2 {
+         let f = (((c[4] * *x + c[3]) * *x + c[2]) * *x + c[1]) * *x + c[0];
+         let df = ((4.0 * c[4] * *x + 3.0 * c[3]) * *x + 2.0 * c[2]) * *x + c[1];
+         if df != 0.0 {
+            *x -= f / df;
+         }
+      }
+   }
+   s
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{solve_quadric, solve_cubic, solve_quartic};
+
+   fn sorted(mut s: Vec<f64>) -> Vec<f64> {
+      s.sort_by(|a, b| a.partial_cmp(b).unwrap());
+      s
+   }
+
+   fn assert_roots(s: Vec<f64>, expected: &[f64]) {
+      let s = sorted(s);
+      assert_eq!(s.len(), expected.len(), "{:?}", s);
+      for (x, e) in s.iter().zip(expected.iter()) {
+         assert!((x - e).abs() < 1e-6, "{:?} != {:?}", s, expected);
+      }
+   }
+
+   #[test]
+   fn quadric() {
+      assert_roots(solve_quadric([-6.0, 1.0, 1.0]), &[-3.0, 2.0]);
+      assert_roots(solve_quadric([1.0, -2.0, 1.0]), &[1.0]);
+      assert_roots(solve_quadric([1.0, 0.0, 1.0]), &[]);
+   }
+
+   #[test]
+   fn cubic() {
+      // (x - 1)(x - 2)(x + 3)
+      assert_roots(solve_cubic([6.0, -7.0, 0.0, 1.0]), &[-3.0, 1.0, 2.0]);
+      // x^3 - 1 has one real root
+      assert_roots(solve_cubic([-1.0, 0.0, 0.0, 1.0]), &[1.0]);
+      // (x - 2)^3
+      assert_roots(solve_cubic([-8.0, 12.0, -6.0, 1.0]), &[2.0]);
+   }
+
+   #[test]
+   fn quartic() {
+      // (x - 1)(x - 2)(x - 3)(x - 4)
+      assert_roots(solve_quartic([24.0, -50.0, 35.0, -10.0, 1.0]), &[1.0, 2.0, 3.0, 4.0]);
+      // (x^2 - 1)(x^2 + 1)
+      assert_roots(solve_quartic([-1.0, 0.0, 0.0, 0.0, 1.0]), &[-1.0, 1.0]);
+      assert_roots(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]), &[]);
+      // scaled coefficients give the same roots
+      assert_roots(solve_quartic([2.4e-3, -5e-3, 3.5e-3, -1e-3, 1e-4]), &[1.0, 2.0, 3.0, 4.0]);
+   }
+
+   #[test]
+   fn quartic_with_spread_roots() {
+      // roots far apart in magnitude, as when a ray grazes a distant torus
+      let r = [0.001, 0.5, 20.0, 300.0];
+      let c = [r[0] * r[1] * r[2] * r[3],
+               -(r[0] * r[1] * r[2] + r[0] * r[1] * r[3] + r[0] * r[2] * r[3] + r[1] * r[2] * r[3]),
+               r[0] * r[1] + r[0] * r[2] + r[0] * r[3] + r[1] * r[2] + r[1] * r[3] + r[2] * r[3],
+               -(r[0] + r[1] + r[2] + r[3]),
+               1.0];
+      let s = sorted(solve_quartic(c));
+      assert_eq!(s.len(), 4, "{:?}", s);
+      for (x, e) in s.iter().zip(r.iter()) {
+         assert!((x - e).abs() < 1e-6 * e.max(1.0), "{:?}", s);
+      }
+   }
+}
@@ -13,16 +13,56 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use vector::Vector;
+use vector::{Vector, dot, next_float_up, next_float_down, Float};
 
 #[derive(Debug,Copy,Clone)]
 pub struct Ray {
    pub origin: Vector,
    pub direction: Vector,
+   // moment in the shutter interval the ray samples, for moving geometry
+   pub time: Float,
 }
 
+// Origin for a ray leaving a surface in direction w, from a hit point p
+// known to within error in each axis. It is pushed along the surface
+// normal n, to the side w goes, just far enough to clear the error so the
+// ray can't find the surface it starts on again, then rounded further away.
+pub fn offset_origin(p: Vector, error: Vector, n: Vector, w: Vector) -> Vector {
+   let n = if dot(n, w) < 0.0 { -n } else { n };
+   let o = p + n * dot(n.abs(), error);
+   let away = |v: Float, n: Float| if n > 0.0 { next_float_up(v) } else if n < 0.0 { next_float_down(v) } else { v };
+   Vector::new(away(o.x, n.x), away(o.y, n.y), away(o.z, n.z))
+}
 
+#[cfg(test)]
+mod tests {
+   use super::offset_origin;
+   use vector::{Vector, Float, dot};
 
+   #[test]
+   fn offset_clears_the_error() {
+      let p = Vector::new(1.0, 2.0, 3.0);
+      let error = Vector::new(1e-3, 2e-3, 4e-3);
+      let n = Vector::new(0.0, 0.6, 0.8);
 
+      // leaving on the side the normal faces, past the error in that direction
+      let o = offset_origin(p, error, n, Vector::new(1.0, 1.0, 0.0));
+      assert!(dot(o - p, n) > dot(n.abs(), error));
+      assert_eq!(o.x, p.x);
 
+      // leaving the other side flips the offset
+      let o = offset_origin(p, error, n, Vector::new(0.0, 0.0, -1.0));
+      assert!(dot(o - p, n) < -dot(n.abs(), error));
+      assert!(o.y < p.y && o.z < p.z);
+   }
 
+   #[test]
+   fn offset_rounds_away_from_the_surface() {
+      // with no error only the rounding moves the origin, by one float
+      let p = Vector::new(1.0, -1.0, 0.0);
+      let o = offset_origin(p, Vector::zero(), Vector::new(1.0, -1.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+      assert_eq!(o.x, 1.0 + Float::EPSILON);
+      assert_eq!(o.y, -1.0 - Float::EPSILON);
+      assert_eq!(o.z, 0.0);
+   }
+}
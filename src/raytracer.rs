@@ -13,40 +13,76 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use std::collections::HashMap;
 use std::fs::File;
+use std::rc::Rc;
 use std::path::Path;
 use std::io::{Read, Write};
+use std::array;
 use camera::Camera;
-use light::{PointLight, Light};
-use geometry::{Geometry, ShadeRec, Sphere};
+use background::{self, Background};
+use light::{PointLight, AreaLight, EnvironmentLight, Light};
+use lightsampler::{LightSampler, PowerSampler, LightTree};
+use sampler::Sampler;
+use geometry::{Geometry, ShadeRec, SceneObject, Sphere, Triangle, Plane, Disk, Rectangle,
+               Cuboid, Cylinder, Cone, Torus, Csg, Operation};
+use mesh::Mesh;
+use sdf::Implicit;
+use bezier;
+use metaball::Metaballs;
+use curve::{Curve, Curves};
+use heightfield::Heightfield;
+use matrix::{Transform, import_vector};
+use instance::{Instance, Moving};
+use bvh::Bvh;
+use accelerator::{self, Accelerator, Structure};
 use color::Color;
-use vector::dot;
+use vector::{dot, Float};
+use vector::float::consts;
 use ray::Ray;
+use packet::{RayPacket, Lanes, LANES, lanes};
 use byteorder::{LittleEndian, WriteBytesExt};
-use toml::Parser;
+use toml::{Parser, Value};
+
+// Fraction of the distance to a light with a surface that shadow rays stop
+// short by, so they don't find the surface of the light itself.
+const SHADOW_EPSILON: Float = 1e-4;
 
 pub struct RayTracer {
     camera: Camera,
     image: Vec<(u8, u8, u8)>,
-    lights: Vec<Box<Light>>,
-    scene: Vec<Box<Geometry>>,
+    lights: Vec<Box<dyn Light>>,
+    scene: Vec<SceneObject>,
+    accelerator: Box<dyn Accelerator>,
+    background: Box<dyn Background>,
+    light_sampler: Option<Box<dyn LightSampler>>,
+    light_samples: usize,
+    pixel_samples: usize,
+    sampler: Sampler,
 }
 
 impl RayTracer {
 
     pub fn new(camera: Camera) -> RayTracer {
         RayTracer {
-            camera: camera,
+            camera,
             image: vec![(0, 0, 0); camera.screen_width() * camera.screen_height()],
             lights: Vec::new(),
             scene: Vec::new(),
+            accelerator: Box::new(Bvh::new(&[])),
+            background: Box::new(background::Solid::new(Color::new(0.0, 0.4, 0.8))),
+            light_sampler: None,
+            light_samples: 1,
+            pixel_samples: 1,
+            sampler: Sampler::new(1),
         }
     }
 
     pub fn import_scene(&mut self, filename: String) {
 
         let path = Path::new(&filename);
-        let mut fin = File::open(&path).unwrap();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut fin = File::open(path).unwrap();
         let mut scene = String::new();
         fin.read_to_string(&mut scene).unwrap();
 
@@ -54,92 +90,418 @@ impl RayTracer {
         let toml = p.parse().unwrap();
         let objects = toml.get("object").unwrap().as_slice().unwrap();
 
+        // the structure chosen for the scene is also used inside meshes and
+        // curve sets
+        let structure = match accelerator::import(toml.get("acceleration")) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Error parsing acceleration - {}", e);
+                Structure::Bvh
+            }
+        };
+
+        // prototypes are only drawn through the instances that refer to them
+        let mut prototypes = HashMap::new();
+        if let Some(list) = toml.get("prototype").and_then(|v| v.as_slice()) {
+            for p in list {
+                let name = p.lookup("name").and_then(|v| v.as_str());
+                let t = p.lookup("type").and_then(|v| v.as_str());
+                match (name, t) {
+                    (Some(name), Some(t)) => match import_geometry(t, p, dir, &prototypes, structure) {
+                        Ok(Some(g)) => { prototypes.insert(name.to_string(), Rc::from(g)); },
+                        Ok(None) => { println!("Unknown prototype {} ignored.", t); },
+                        Err(e) => {println!("Error parsing prototype {} - {}", name, e)},
+                    },
+                    _ => { println!("Prototype without name or type ignored."); }
+                }
+            }
+        }
+
         for obj in objects {
             let t = obj.lookup("type").unwrap().as_str().unwrap();
             match t {
-                "sphere" => {
-                    match Sphere::import(obj) {
-                        Ok(s) => self.scene.push(Box::new(s)),
-                        Err(e) => {println!("Error parsing sphere - {}", e)},
-                    }
-                }
                 "point_light" => {
-                    match PointLight::import(obj) {
+                    match PointLight::import(obj, dir) {
                         Ok(pl) => self.lights.push(Box::new(pl)),
-                        Err(e) => {println!("Error parsing sphere - {}", e)},
+                        Err(e) => {println!("Error parsing point light - {}", e)},
+                    }
+                }
+                "area_light" => {
+                    match AreaLight::import(obj) {
+                        Ok(al) => self.lights.push(Box::new(al)),
+                        Err(e) => {println!("Error parsing area light - {}", e)},
+                    }
+                }
+                "environment_light" => {
+                    match EnvironmentLight::import(obj, dir) {
+                        Ok(el) => {
+                            self.background = Box::new(background::Environment::new(el.map.clone()));
+                            self.lights.push(Box::new(el));
+                        }
+                        Err(e) => {println!("Error parsing environment light - {}", e)},
+                    }
+                }
+                _ => {
+                    match import_geometry(t, obj, dir, &prototypes, structure) {
+                        Ok(Some(g)) => match SceneObject::import(obj, g) {
+                            Ok(so) => self.scene.push(so),
+                            Err(e) => {println!("Error parsing {} - {}", t, e)},
+                        },
+                        Ok(None) => { println!("Unknown object {} ignored.", t); }
+                        Err(e) => {println!("Error parsing {} - {}", t, e)},
                     }
                 }
-
-                _ => { println!("Unknown object {} ignored.", t); }
            }
         }
+
+        // an explicit background overrides the one implied by an environment light
+        if let Some(bg) = toml.get("background") {
+            match self.import_background(bg, dir) {
+                Ok(b) => self.background = b,
+                Err(e) => {println!("Error parsing background - {}", e)},
+            }
+        }
+
+        if let Some(c) = toml.get("camera") {
+            if let Err(e) = self.import_camera(c) {
+                println!("Error parsing camera - {}", e);
+            }
+        }
+
+        if let Some(l) = toml.get("lighting") {
+            if let Err(e) = self.import_lighting(l) {
+                println!("Error parsing lighting - {}", e);
+            }
+        }
+        let bounds: Vec<_> = self.scene.iter().map(|so| so.geometry.bounds()).collect();
+        self.accelerator = accelerator::build(structure, &bounds);
+    }
+
+    fn import_camera(&mut self, c: &Value) -> Result<(), String> {
+        if let Some(v) = c.lookup("samples") {
+            self.pixel_samples = (v.as_integer().ok_or("Invalid integer.")? as usize).max(1);
+        }
+        if c.lookup("shutter").is_some() {
+            let open = c.lookup("shutter.0").and_then(|v| v.as_float()).ok_or("Invalid float.")?;
+            let close = c.lookup("shutter.1").and_then(|v| v.as_float()).ok_or("Invalid float.")?;
+            self.camera.set_shutter(open as Float, close as Float);
+        }
+        Ok(())
+    }
+
+    fn import_lighting(&mut self, l: &Value) -> Result<(), String> {
+        if let Some(v) = l.lookup("samples") {
+            self.light_samples = (v.as_integer().ok_or("Invalid integer.")? as usize).max(1);
+        }
+        let strategy = match l.lookup("strategy") {
+            Some(v) => v.as_str().ok_or("Invalid string.")?,
+            None => "all",
+        };
+        self.light_sampler = match strategy {
+            "all" => None,
+            "power" => Some(Box::new(PowerSampler::new(&self.lights))),
+            "tree" => Some(Box::new(LightTree::new(&self.lights))),
+            _ => return Err(format!("Unknown strategy {}.", strategy)),
+        };
+        Ok(())
+    }
+
+    fn import_background(&self, bg: &Value, dir: &Path) -> Result<Box<dyn Background>, String> {
+        let t = bg.lookup("type").and_then(|v| v.as_str()).ok_or("Missing type.")?;
+        match t {
+            "solid" => Ok(Box::new(background::Solid::import(bg)?)),
+            "gradient" => Ok(Box::new(background::Gradient::import(bg)?)),
+            "sky" => Ok(Box::new(background::Sky::import(bg)?)),
+            "environment" => Ok(Box::new(background::Environment::import(bg, dir)?)),
+            _ => Err(format!("Unknown background type {}.", t)),
+        }
     }
 
     pub fn render(self, filename: String)  {
         // Write file out as a 24 bit uncompressed TGA.
         // http://en.wikipedia.org/wiki/Truevision_TGA
-        let ref mut fout = File::create(&Path::new(&filename)).unwrap();
+        let fout = &mut File::create(Path::new(&filename)).unwrap();
         // field 1,2,3,4
-        fout.write(&[0, 0, 2, 0, 0, 0, 0, 0]).unwrap();
+        fout.write_all(&[0, 0, 2, 0, 0, 0, 0, 0]).unwrap();
         // field 5
-        fout.write_u16::<LittleEndian>(0 as u16).unwrap();
-        fout.write_u16::<LittleEndian>(0 as u16).unwrap();
+        fout.write_u16::<LittleEndian>(0_u16).unwrap();
+        fout.write_u16::<LittleEndian>(0_u16).unwrap();
         fout.write_u16::<LittleEndian>(self.camera.screen_width() as u16).unwrap();
         fout.write_u16::<LittleEndian>(self.camera.screen_height() as u16).unwrap();
-        fout.write(&[24, 32]).unwrap();
+        fout.write_all(&[24, 32]).unwrap();
         // image data
         for pix in self.image {
             let (p0, p1, p2) = pix;
-            fout.write(&[p2, p1, p0]).unwrap();
+            fout.write_all(&[p2, p1, p0]).unwrap();
         }
     }
 
-    pub fn shade(&mut self, sr: &ShadeRec, ray: &Ray) -> Color {
+    pub fn shade(&mut self, sr: &ShadeRec) -> Color {
         let mut c  = Color::new(0.0, 0.0, 0.0);
+        let obj = &self.scene[sr.object];
 
         // if we had a global ambient light we would add it in here
 
-        // calculate diffuse lighting for each light
-        for l in self.lights.iter() {
+        match self.light_sampler {
+            None => {
+                // calculate diffuse lighting for each light
+                for l in self.lights.iter() {
+                    c = c + self.direct(&**l, sr, obj);
+                }
+            }
+            Some(ref ls) => {
+                // lights at infinity are always sampled, the rest are chosen
+                // stochastically and weighted by the selection probability
+                for l in self.lights.iter().filter(|l| l.position().is_none()) {
+                    c = c + self.direct(&**l, sr, obj);
+                }
+                let n = self.light_samples;
+                for _ in 0 .. n {
+                    if let Some((i, prob)) = ls.select(sr.hit_point, sr.normal, self.sampler.next()) {
+                        c = c + self.direct(&*self.lights[i], sr, obj) * (1.0 / (prob * n as Float));
+                    }
+                }
+            }
+        }
+
+        c
+    }
+
+    fn direct(&self, l: &dyn Light, sr: &ShadeRec, obj: &SceneObject) -> Color {
+        let mut c  = Color::new(0.0, 0.0, 0.0);
+        if !l.illuminates(&obj.name) {
+            return c;
+        }
+
+        let wo = -sr.ray.direction.normalize();
+        let n = l.samples();
+        for _ in 0 .. n {
             let dir = l.direction(sr);
             let d = dot(dir, sr.normal);
-            if d > 0.0 {
-                c = c + l.radiance(&sr) * sr.color * d;
+            let pdf = l.pdf(sr);
+            if d > 0.0 && pdf > 0.0 {
+                if l.casts_shadows() && obj.receives_shadows {
+                    let mut tmax = l.distance(sr);
+                    if l.has_surface() {
+                        tmax *= 1.0 - SHADOW_EPSILON;
+                    }
+                    if self.in_shadow(sr.spawn_ray(dir), tmax) {
+                        continue;
+                    }
+                }
+                let f = match sr.material {
+                    Some(ref b) => b.f(sr, &dir, &wo),
+                    // plain Lambertian in the surface color
+                    None => sr.color * consts::FRAC_1_PI,
+                };
+                c = c + l.radiance(sr) * f * (d / (pdf * n as Float));
             }
         }
 
         c
     }
 
-    pub fn trace(&mut self) {
-        for y in (0 .. self.camera.screen_height()) {
-            for x in (0 .. self.camera.screen_width()) {
-                let mut tmax = 10000.0;
-                let mut hit: Option<ShadeRec> = None;
-
-                let r = self.camera.ray(x as f32, y as f32);
-
-                // find nearest intersection
-                for sh in self.scene.iter() {
-                    match sh.intersect(r, 0.00001, tmax) {
-                        None => { },
-                        Some(hr) => { tmax = hr.t; hit = Some(hr); },
+    // Shadow rays are spawned clear of the surface they leave, so look for
+    // blockers from their origin on.
+    fn in_shadow(&self, ray: Ray, tmax: Float) -> bool {
+        let scene = &self.scene;
+        self.accelerator.any(&ray, 0.0, tmax, &mut |i| {
+            scene[i].casts_shadows && scene[i].geometry.intersect(ray, 0.0, tmax).is_some()
+        })
+    }
+
+    // Traces up to LANES rays as one packet, returning a color for each.
+    fn trace_packet(&mut self, rays: &[Ray]) -> [Color; LANES] {
+        let packet = RayPacket::new(rays);
+        let mut hits: [Option<ShadeRec>; LANES] = array::from_fn(|_| None);
+        // lanes without a ray are left inactive
+        let mut tmax = lanes(|k| if k < rays.len() { Float::INFINITY } else { Float::NEG_INFINITY });
+
+        // find nearest intersections
+        {
+            let scene = &self.scene;
+            let tmin = Lanes::splat(0.0);
+            self.accelerator.closest_packet(&packet, &tmin, &mut tmax, &mut |i, tmax| {
+                let mut found = scene[i].geometry.intersect_packet(&packet, &tmin, tmax);
+                for k in 0 .. LANES {
+                    if let Some(mut hr) = found[k].take() {
+                        hr.object = i;
+                        tmax[k] = hr.t;
+                        hits[k] = Some(hr);
                     }
                 }
+            });
+        }
 
-                let c = match hit {
-                    Some(h) => self.shade(&h, &r),
-                    None => Color::new(0.0, 0.4, 0.8),
-                };
+        let mut colors = [Color::new(0.0, 0.0, 0.0); LANES];
+        for k in 0 .. rays.len() {
+            colors[k] = match hits[k].take() {
+                Some(mut h) => {
+                    h.material = self.scene[h.object].brdf.clone();
+                    self.shade(&h)
+                }
+                None => self.background.color(&rays[k]),
+            };
+        }
+        colors
+    }
 
-                let color = c.to_bytes();
+    pub fn trace(&mut self) {
+        let (w, h) = (self.camera.screen_width(), self.camera.screen_height());
+        let n = self.pixel_samples;
+        for y in 0 .. h {
+            // the camera rays of a row are traced in packets, a pixel's
+            // samples next to each other
+            let mut rays = Vec::with_capacity(w * n);
+            for x in 0 .. w {
+                // a single sample goes through the middle of the pixel and
+                // the shutter interval, more are jittered across both with
+                // their times stratified
+                for k in 0 .. n {
+                    let (sx, sy, st) = if n == 1 {
+                        (0.5, 0.5, 0.5)
+                    } else {
+                        (self.sampler.next(), self.sampler.next(), (k as Float + self.sampler.next()) / n as Float)
+                    };
+                    rays.push(self.camera.ray(x as Float + sx, y as Float + sy, st));
+                }
+            }
 
-                self.image[x + self.camera.screen_width() * (self.camera.screen_height() - y - 1)] = color;
+            let mut samples = Vec::with_capacity(rays.len());
+            for chunk in rays.chunks(LANES) {
+                let colors = self.trace_packet(chunk);
+                samples.extend_from_slice(&colors[.. chunk.len()]);
+            }
+
+            for x in 0 .. w {
+                let c = samples[x * n .. (x + 1) * n].iter().fold(Color::new(0.0, 0.0, 0.0), |a, &c| a + c);
+                let color = (c * (1.0 / n as Float)).to_bytes();
+
+                self.image[x + w * (h - y - 1)] = color;
             }
         }
     }
 }
 
+// Creates the geometry for an object of type t, None if t isn't a geometry.
+// Any transform given with the object wraps the geometry in an instance,
+// and any motion in a moving one.
+fn import_geometry(t: &str, obj: &Value, dir: &Path, prototypes: &HashMap<String, Rc<dyn Geometry>>,
+                   structure: Structure) -> Result<Option<Box<dyn Geometry>>, String> {
+    fn boxed<G: Geometry + 'static>(r: Result<G, String>) -> Result<Box<dyn Geometry>, String> {
+        r.map(|g| Box::new(g) as Box<dyn Geometry>)
+    }
 
+    let g = match t {
+        "sphere" => boxed(Sphere::import(obj)),
+        "triangle" => boxed(Triangle::import(obj)),
+        "plane" => boxed(Plane::import(obj)),
+        "disk" => boxed(Disk::import(obj)),
+        "rectangle" => boxed(Rectangle::import(obj)),
+        "box" => boxed(Cuboid::import(obj)),
+        "cylinder" => boxed(Cylinder::import(obj)),
+        "cone" => boxed(Cone::import(obj)),
+        "torus" => boxed(Torus::import(obj)),
+        "mesh" => boxed(Mesh::import(obj, dir, structure)),
+        "bezier" => boxed(bezier::import(obj, dir, structure)),
+        "implicit" => boxed(Implicit::import(obj)),
+        "metaballs" => boxed(Metaballs::import(obj)),
+        "curve" => boxed(Curve::import(obj)),
+        "curves" => boxed(Curves::import(obj, dir, structure)),
+        "heightfield" => boxed(Heightfield::import(obj, dir)),
+        "union" | "intersection" | "difference" => {
+            // the two solids are given as the subtables a and b
+            let op = match t {
+                "union" => Operation::Union,
+                "intersection" => Operation::Intersection,
+                _ => Operation::Difference,
+            };
+            let mut children = Vec::new();
+            for key in ["a", "b"].iter() {
+                let c = obj.lookup(key).ok_or("Missing element.")?;
+                let ct = c.lookup("type").and_then(|v| v.as_str()).ok_or("Missing type.")?;
+                children.push(import_geometry(ct, c, dir, prototypes, structure)?
+                                  .ok_or(format!("Unknown object {}.", ct))?);
+            }
+            let b = children.pop().unwrap();
+            let a = children.pop().unwrap();
+            Ok(Box::new(Csg::new(op, a, b)) as Box<dyn Geometry>)
+        }
+        "instance" => {
+            let name = obj.lookup("prototype").and_then(|v| v.as_str()).ok_or("Missing prototype.")?;
+            let p = prototypes.get(name).ok_or(format!("Unknown prototype {}.", name))?;
+            return place(p.clone(), obj).map(Some);
+        }
+        _ => return Ok(None),
+    };
 
+    let g = g?;
+    if !Transform::given(obj) && obj.lookup("end").is_none() && obj.lookup("velocity").is_none() {
+        return Ok(Some(g));
+    }
+    place(Rc::from(g), obj).map(Some)
+}
 
+// Places g with the transform of obj. Motion is given either as a velocity,
+// the distance moved between times 0 and 1, or as an end table with the
+// transform keys at time 1, any it leaves out being unchanged.
+fn place(g: Rc<dyn Geometry>, obj: &Value) -> Result<Box<dyn Geometry>, String> {
+    let start = Transform::import(obj, Transform::identity())?;
+    let end = match (obj.lookup("end"), obj.lookup("velocity")) {
+        (Some(e), _) => Some(Transform::import(e, start)?),
+        (None, Some(v)) => {
+            let mut e = start;
+            e.translate = e.translate + import_vector(v)?;
+            Some(e)
+        }
+        (None, None) => None,
+    };
+
+    match end {
+        Some(end) => Ok(Box::new(Moving::new(g, start, end)?)),
+        None => Ok(Box::new(Instance::new(g, start.matrix())?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RayTracer;
+    use camera::Camera;
+    use geometry::{SceneObject, Sphere};
+    use light::{PointLight, Linking};
+    use vector::{Vector, Float};
+    use ray::Ray;
+    use toml::{Parser, Value};
+
+    fn parse(s: &str) -> Value {
+        Value::Table(Parser::new(s).parse().unwrap())
+    }
+
+    #[test]
+    fn linked_lights_skip_excluded_objects() {
+        let mut rt = RayTracer::new(Camera::new(60.0, 4.0, 4.0));
+        for name in ["lit", "excluded"].iter() {
+            let mut so = SceneObject::new(Box::new(Sphere::new(0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0)));
+            so.name = name.to_string();
+            rt.scene.push(so);
+        }
+        let mut light = PointLight::from_vec(vec![0.0, 0.0, -10.0, 1.0, 1.0, 1.0, 1.0]);
+        light.linking = Linking::import(&parse("exclude = [\"excluded\"]")).unwrap();
+        rt.lights.push(Box::new(light));
+
+        let r = Ray { origin: Vector::new(0.0, 0.0, -5.0), direction: Vector::new(0.0, 0.0, 1.0), time: 0.0 };
+        let mut sr = rt.scene[0].geometry.intersect(r, 0.0, Float::INFINITY).unwrap();
+        assert!(rt.shade(&sr).r > 0.0);
+        // the same point on the excluded object gets nothing from the light
+        sr.object = 1;
+        assert_eq!(rt.shade(&sr).r, 0.0);
+
+        // an include list leaves out everything else
+        let mut light = PointLight::from_vec(vec![0.0, 0.0, -10.0, 1.0, 1.0, 1.0, 1.0]);
+        light.linking = Linking::import(&parse("include = [\"excluded\"]")).unwrap();
+        rt.lights[0] = Box::new(light);
+        assert!(rt.shade(&sr).r > 0.0);
+        sr.object = 0;
+        assert_eq!(rt.shade(&sr).r, 0.0);
+    }
+}
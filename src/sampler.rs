@@ -0,0 +1,44 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::cell::Cell;
+use vector::Float;
+
+// Small xorshift generator. Lights and other stochastic elements sample
+// through a shared reference so the state lives in a Cell.
+#[derive(Debug,Clone)]
+pub struct Sampler {
+   state: Cell<u32>,
+}
+
+impl Sampler {
+   pub fn new(seed: u32) -> Sampler {
+      Sampler { state: Cell::new(if seed == 0 { 0x9e3779b9 } else { seed }) }
+   }
+
+   // Uniform sample in [0, 1).
+   pub fn next(&self) -> Float {
+      let mut x = self.state.get();
+      x ^= x << 13;
+      x ^= x >> 17;
+      x ^= x << 5;
+      self.state.set(x);
+      (x >> 8) as Float / 16777216.0
+   }
+
+   pub fn sample_unit_square(&self) -> (Float, Float) {
+      (self.next(), self.next())
+   }
+}
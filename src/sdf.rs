@@ -0,0 +1,399 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Implicit surfaces given by signed distance functions, negative inside,
+// and intersected by sphere tracing (Hart 1996). Shapes are combined with
+// the usual CSG operators, optionally smoothed with the polynomial smooth
+// minimum from https://iquilezles.org/articles/smin/.
+
+use vector::{Vector, dot, gamma, Float};
+use ray::Ray;
+use color::Color;
+use aabb::Aabb;
+use accelerator::inverse;
+use geometry::{Geometry, ShadeRec};
+use toml::Value;
+
+const MAX_STEPS: usize = 512;
+// Tolerances near the origin. Further out they grow with the coordinates
+// to stay above the rounding error in them, taken generously as that of
+// ROUNDING operations since a distance function can take many.
+const HIT_EPSILON: Float = 1e-4;
+const NORMAL_EPSILON: Float = 1e-4;
+const ROUNDING: i32 = 64;
+
+pub trait Sdf {
+   fn distance(&self, p: Vector) -> Float;
+   fn bounds(&self) -> Aabb;
+}
+
+pub struct Sphere {
+   center: Vector,
+   radius: Float,
+}
+
+// Box between min and max with its edges rounded off by radius.
+pub struct Cuboid {
+   center: Vector,
+   half: Vector,
+   radius: Float,
+}
+
+// Torus around the vertical axis, a and b as for geometry::Torus.
+pub struct Torus {
+   center: Vector,
+   a: Float,
+   b: Float,
+}
+
+// Segment from p0 to p1 thickened by radius.
+pub struct Capsule {
+   p0: Vector,
+   p1: Vector,
+   radius: Float,
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Operation {
+   Union,
+   Intersection,
+   Difference,
+}
+
+// CSG of two shapes, with k the width of the smooth blend where they meet
+// or 0 for a sharp edge.
+pub struct Combine {
+   op: Operation,
+   a: Box<dyn Sdf>,
+   b: Box<dyn Sdf>,
+   k: Float,
+}
+
+// Linear interpolation between two shapes, t = 0 giving a.
+pub struct Blend {
+   a: Box<dyn Sdf>,
+   b: Box<dyn Sdf>,
+   t: Float,
+}
+
+impl Sdf for Sphere {
+   fn distance(&self, p: Vector) -> Float {
+      (p - self.center).magnitude() - self.radius
+   }
+
+   fn bounds(&self) -> Aabb {
+      let r = Vector::new(self.radius, self.radius, self.radius);
+      Aabb::new(self.center - r, self.center + r)
+   }
+}
+
+impl Sdf for Cuboid {
+   fn distance(&self, p: Vector) -> Float {
+      let d = p - self.center;
+      let q = Vector::new(d.x.abs() - self.half.x + self.radius,
+                          d.y.abs() - self.half.y + self.radius,
+                          d.z.abs() - self.half.z + self.radius);
+      let outside = Vector::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).magnitude();
+      outside + q.x.max(q.y).max(q.z).min(0.0) - self.radius
+   }
+
+   fn bounds(&self) -> Aabb {
+      Aabb::new(self.center - self.half, self.center + self.half)
+   }
+}
+
+impl Sdf for Torus {
+   fn distance(&self, p: Vector) -> Float {
+      let d = p - self.center;
+      let ring = (d.x * d.x + d.z * d.z).sqrt() - self.a;
+      (ring * ring + d.y * d.y).sqrt() - self.b
+   }
+
+   fn bounds(&self) -> Aabb {
+      let e = Vector::new(self.a + self.b, self.b, self.a + self.b);
+      Aabb::new(self.center - e, self.center + e)
+   }
+}
+
+impl Sdf for Capsule {
+   fn distance(&self, p: Vector) -> Float {
+      let pa = p - self.p0;
+      let ba = self.p1 - self.p0;
+      // a capsule with both ends together is a sphere
+      let h = if dot(ba, ba) > 0.0 { (dot(pa, ba) / dot(ba, ba)).clamp(0.0, 1.0) } else { 0.0 };
+      (pa - ba * h).magnitude() - self.radius
+   }
+
+   fn bounds(&self) -> Aabb {
+      let r = Vector::new(self.radius, self.radius, self.radius);
+      Aabb::from_points(&[self.p0 - r, self.p0 + r, self.p1 - r, self.p1 + r])
+   }
+}
+
+impl Sdf for Combine {
+   fn distance(&self, p: Vector) -> Float {
+      let (a, b) = (self.a.distance(p), self.b.distance(p));
+      match self.op {
+         Operation::Union => smooth_min(a, b, self.k),
+         Operation::Intersection => -smooth_min(-a, -b, self.k),
+         Operation::Difference => -smooth_min(-a, b, self.k),
+      }
+   }
+
+   fn bounds(&self) -> Aabb {
+      let (a, b) = (self.a.bounds(), self.b.bounds());
+      match self.op {
+         // a smooth union bulges out by at most k / 4 where the shapes meet
+         Operation::Union => {
+            let e = Vector::new(self.k, self.k, self.k) * 0.25;
+            let u = a.union(&b);
+            Aabb::new(u.min - e, u.max + e)
+         }
+         Operation::Intersection => a.intersection(&b),
+         Operation::Difference => a,
+      }
+   }
+}
+
+impl Sdf for Blend {
+   fn distance(&self, p: Vector) -> Float {
+      self.a.distance(p) * (1.0 - self.t) + self.b.distance(p) * self.t
+   }
+
+   fn bounds(&self) -> Aabb {
+      self.a.bounds().union(&self.b.bounds())
+   }
+}
+
+fn smooth_min(a: Float, b: Float, k: Float) -> Float {
+   if k <= 0.0 {
+      return a.min(b);
+   }
+   let h = (k - (a - b).abs()).max(0.0) / k;
+   a.min(b) - h * h * k * 0.25
+}
+
+// Reads a shape table, with any operands in its subtables a and b.
+pub fn import(obj: &Value) -> Result<Box<dyn Sdf>, String> {
+   let t = obj.lookup("type").and_then(|v| v.as_str()).ok_or("Missing type.")?;
+   let float = |key: &str| -> Result<Float, String> {
+      let value = obj.lookup(key).ok_or("Missing element.")?;
+      Ok(value.as_float().ok_or("Invalid float.")? as Float)
+   };
+   let vector = |key: &str| -> Result<Vector, String> {
+      Ok(Vector::new(float(&format!("{}.0", key))?,
+                     float(&format!("{}.1", key))?,
+                     float(&format!("{}.2", key))?))
+   };
+   let optional = |key: &str| -> Result<Float, String> {
+      if obj.lookup(key).is_some() { float(key) } else { Ok(0.0) }
+   };
+   let operand = |key: &str| -> Result<Box<dyn Sdf>, String> {
+      import(obj.lookup(key).ok_or("Missing element.")?)
+   };
+
+   match t {
+      "sphere" => Ok(Box::new(Sphere { center: vector("center")?, radius: float("radius")? })),
+      "box" => {
+         let (min, max) = (vector("min")?, vector("max")?);
+         Ok(Box::new(Cuboid { center: (min + max) * 0.5, half: (max - min) * 0.5, radius: optional("radius")? }))
+      }
+      "torus" => Ok(Box::new(Torus { center: vector("center")?, a: float("a")?, b: float("b")? })),
+      "capsule" => Ok(Box::new(Capsule { p0: vector("p0")?, p1: vector("p1")?, radius: float("radius")? })),
+      "union" | "intersection" | "difference" => {
+         let op = match t {
+            "union" => Operation::Union,
+            "intersection" => Operation::Intersection,
+            _ => Operation::Difference,
+         };
+         Ok(Box::new(Combine { op, a: operand("a")?, b: operand("b")?, k: optional("k")? }))
+      }
+      "blend" => Ok(Box::new(Blend { a: operand("a")?, b: operand("b")?, t: float("t")? })),
+      _ => Err(format!("Unknown shape {}.", t)),
+   }
+}
+
+// Scene geometry for the surface of a distance function.
+pub struct Implicit {
+   sdf: Box<dyn Sdf>,
+   bounds: Aabb,
+   color: Color,
+}
+
+impl Implicit {
+   pub fn new(sdf: Box<dyn Sdf>, color: Color) -> Implicit {
+      Implicit { bounds: sdf.bounds(), sdf, color }
+   }
+
+   pub fn import(obj: &Value) -> Result<Implicit, String> {
+      let elements = vec!["color.0", "color.1", "color.2"];
+      let mut values = Vec::new();
+
+      for e in elements {
+         let value = obj.lookup(e).ok_or("Missing element.")?;
+         values.push(value.as_float().ok_or("Invalid float.")? as Float);
+      }
+
+      let shape = obj.lookup("shape").ok_or("Missing shape.")?;
+      Ok(Implicit::new(import(shape)?, Color::new(values[0], values[1], values[2])))
+   }
+
+   fn normal(&self, p: Vector, scale: Float) -> Vector {
+      let e = NORMAL_EPSILON.max(gamma(ROUNDING) * scale);
+      let d = |x: Float, y: Float, z: Float| self.sdf.distance(p + Vector::new(x, y, z));
+      Vector::new(d(e, 0.0, 0.0) - d(-e, 0.0, 0.0),
+                  d(0.0, e, 0.0) - d(0.0, -e, 0.0),
+                  d(0.0, 0.0, e) - d(0.0, 0.0, -e)).normalize()
+   }
+}
+
+impl Geometry for Implicit {
+   fn bounds(&self) -> Aabb {
+      self.bounds
+   }
+
+   fn intersect(&self, r: Ray, tmin: Float, tmax: Float) -> Option<ShadeRec> {
+      let (t0, t1) = self.bounds.hit(&r, inverse(r.direction), tmin, tmax)?;
+
+      // a ray starting inside marches towards the surface on its way out
+      let sign = if self.sdf.distance(r.origin + r.direction * t0) < 0.0 { -1.0 } else { 1.0 };
+      let mut t = t0;
+      for _ in 0 .. MAX_STEPS {
+         let p = r.origin + r.direction * t;
+         let d = self.sdf.distance(p) * sign;
+         // p is rounded from the origin and t, so its error grows with both
+         let size = r.origin.abs() + (r.direction * t).abs();
+         let scale = size.x.max(size.y).max(size.z);
+         let epsilon = HIT_EPSILON.max(gamma(ROUNDING) * scale);
+         if d < epsilon {
+            // the surface is up to epsilon on from p, so rays leaving it
+            // are pushed out at least twice that to start clear of it
+            let mut sr = ShadeRec::new(r, t, self.normal(p, scale), self.color);
+            let e = epsilon * 2.0;
+            sr.error = sr.error + Vector::new(e, e, e);
+            return Some(sr);
+         }
+         t += d;
+         if t > t1 {
+            break;
+         }
+      }
+      None
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::{Implicit, Sdf, Sphere, Combine, Blend, Operation, smooth_min};
+   use vector::{Vector, Float};
+   use color::Color;
+   use ray::Ray;
+   use geometry::Geometry;
+
+   #[test]
+   fn hits_far_from_the_origin() {
+      // a thousand units out single precision floats are too far apart for
+      // steps of 1e-4 to find the normal
+      for &x in [0.0, 1000.0, 10000.0].iter() {
+         let sphere = Sphere { center: Vector::new(0.0, x, 0.0), radius: 1.0 };
+         let implicit = Implicit::new(Box::new(sphere), Color::new(1.0, 1.0, 1.0));
+         let r = Ray { origin: Vector::new(0.0, x + 10.0, 0.3), direction: Vector::new(0.0, -1.0, 0.0), time: 0.0 };
+         let sr = implicit.intersect(r, 0.0, Float::INFINITY).expect("missed the sphere");
+         let y = (1.0 - 0.3 * 0.3 as Float).sqrt();
+         assert!((sr.t - (10.0 - y)).abs() < 1e-4 + 1e-5 * x, "{} at {}", sr.t, x);
+         assert!((sr.normal.y - y).abs() < 0.05 && (sr.normal.z - 0.3).abs() < 0.05, "{:?} at {}", sr.normal, x);
+      }
+   }
+
+   fn sphere(x: Float) -> Box<dyn Sdf> {
+      Box::new(Sphere { center: Vector::new(x, 0.0, 0.0), radius: 1.0 })
+   }
+
+   fn combine(op: Operation, k: Float) -> Combine {
+      Combine { op, a: sphere(0.0), b: sphere(1.0), k }
+   }
+
+   #[test]
+   fn sharp_operators() {
+      let (union, intersection, difference) =
+         (combine(Operation::Union, 0.0), combine(Operation::Intersection, 0.0), combine(Operation::Difference, 0.0));
+      for &x in [-1.5, -0.5, 0.25, 0.5, 0.75, 1.5, 2.5].iter() {
+         let p = Vector::new(x, 0.3, 0.0);
+         let (a, b) = (union.a.distance(p), union.b.distance(p));
+         assert_eq!(union.distance(p), a.min(b));
+         assert_eq!(intersection.distance(p), a.max(b));
+         assert_eq!(difference.distance(p), a.max(-b));
+      }
+      // the lens between the spheres is in both or neither of the solids
+      let p = Vector::new(0.5, 0.0, 0.0);
+      assert!(union.distance(p) < 0.0 && intersection.distance(p) < 0.0 && difference.distance(p) > 0.0);
+      let p = Vector::new(-0.5, 0.0, 0.0);
+      assert!(intersection.distance(p) > 0.0 && difference.distance(p) < 0.0);
+   }
+
+   #[test]
+   fn smooth_minimum() {
+      assert_eq!(smooth_min(1.0, 2.0, 0.0), 1.0);
+      // it only differs from min within k of the shapes meeting, by at most k / 4
+      assert_eq!(smooth_min(1.0, 2.0, 0.5), 1.0);
+      assert!((smooth_min(1.0, 1.0, 0.5) - (1.0 - 0.125)).abs() < 1e-6);
+      let mut last = smooth_min(-1.0, 0.0, 0.5);
+      for i in 1 .. 101 {
+         let a = -1.0 + i as Float * 0.02;
+         let m = smooth_min(a, 0.0, 0.5);
+         assert!(m <= a.min(0.0) && m >= a.min(0.0) - 0.125);
+         // and joins it continuously
+         assert!((m - last).abs() < 0.03);
+         last = m;
+      }
+
+      // smoothing the other operators rounds off the edge where the
+      // surfaces cross, leaving points on it outside
+      let p = Vector::new(0.5, (0.75 as Float).sqrt(), 0.0);
+      assert!(combine(Operation::Intersection, 0.5).distance(p) > combine(Operation::Intersection, 0.0).distance(p));
+      assert!(combine(Operation::Difference, 0.5).distance(p) > combine(Operation::Difference, 0.0).distance(p));
+   }
+
+   #[test]
+   fn blend_interpolates() {
+      let p = Vector::new(0.2, 0.7, -0.4);
+      let blend = |t: Float| Blend { a: sphere(0.0), b: sphere(1.0), t }.distance(p);
+      let (a, b) = (sphere(0.0).distance(p), sphere(1.0).distance(p));
+      assert_eq!(blend(0.0), a);
+      assert_eq!(blend(1.0), b);
+      assert!((blend(0.25) - (a * 0.75 + b * 0.25)).abs() < 1e-6);
+   }
+
+   #[test]
+   fn smooth_union_bounds_cover_the_bulge() {
+      // spheres touching at the origin fill out a neck wider than either
+      let shape = Combine { op: Operation::Union, a: sphere(-1.0), b: sphere(1.0), k: 2.0 };
+      let neck = Vector::new(0.0, 1.1, 0.0);
+      assert!(shape.distance(neck) < 0.0);
+      assert!(neck.y > shape.a.bounds().union(&shape.b.bounds()).max.y);
+      let bounds = shape.bounds();
+      assert!(bounds.max.y >= 1.5 && bounds.min.y <= -1.5 && bounds.max.x >= 2.5);
+   }
+
+   #[test]
+   fn rays_starting_inside_leave_through_the_surface() {
+      let implicit = Implicit::new(sphere(0.0), Color::new(1.0, 1.0, 1.0));
+      let r = Ray { origin: Vector::new(0.0, 0.0, 0.0), direction: Vector::new(1.0, 0.0, 0.0), time: 0.0 };
+      let sr = implicit.intersect(r, 0.0, Float::INFINITY).expect("missed the sphere");
+      assert!((sr.t - 1.0).abs() < 1e-3, "{}", sr.t);
+      // the normal still points out of the solid
+      assert!(sr.normal.x > 0.99);
+   }
+}
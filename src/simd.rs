@@ -0,0 +1,167 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// SIMD registers holding the lanes of ray packets. On x86_64 they are SSE
+// registers of 4 single or 2 double precision floats. Builds enabling AVX,
+// for instance with RUSTFLAGS="-C target-cpu=native", use AVX registers of
+// twice that where packets are wide enough to fill them. Other targets
+// hold one float per register and leave any vectorising to the compiler.
+//
+// Comparisons are false where either side is NaN, and min(a, b) and
+// max(a, b) give b unless a is strictly less or greater, which is what the
+// minps and maxps instructions do. A mask has all bits set in the lanes
+// where it holds.
+
+pub use self::imp::*;
+
+#[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(all(target_feature = "avx", feature = "packet8"))))]
+mod imp {
+   use std::arch::x86_64::*;
+
+   pub type Reg = __m128;
+   pub const WIDTH: usize = 4;
+
+   #[inline] pub fn splat(x: f32) -> Reg { unsafe { _mm_set1_ps(x) } }
+   #[inline] pub fn load(p: &[f32; WIDTH]) -> Reg { unsafe { _mm_loadu_ps(p.as_ptr()) } }
+   #[inline] pub fn add(a: Reg, b: Reg) -> Reg { unsafe { _mm_add_ps(a, b) } }
+   #[inline] pub fn sub(a: Reg, b: Reg) -> Reg { unsafe { _mm_sub_ps(a, b) } }
+   #[inline] pub fn mul(a: Reg, b: Reg) -> Reg { unsafe { _mm_mul_ps(a, b) } }
+   #[inline] pub fn div(a: Reg, b: Reg) -> Reg { unsafe { _mm_div_ps(a, b) } }
+   #[inline] pub fn min(a: Reg, b: Reg) -> Reg { unsafe { _mm_min_ps(a, b) } }
+   #[inline] pub fn max(a: Reg, b: Reg) -> Reg { unsafe { _mm_max_ps(a, b) } }
+   #[inline] pub fn sqrt(a: Reg) -> Reg { unsafe { _mm_sqrt_ps(a) } }
+   #[inline] pub fn lt(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmplt_ps(a, b) } }
+   #[inline] pub fn le(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmple_ps(a, b) } }
+   #[inline] pub fn gt(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmpgt_ps(a, b) } }
+   #[inline] pub fn ge(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmpge_ps(a, b) } }
+   #[inline] pub fn and(a: Reg, b: Reg) -> Reg { unsafe { _mm_and_ps(a, b) } }
+   #[inline] pub fn or(a: Reg, b: Reg) -> Reg { unsafe { _mm_or_ps(a, b) } }
+   #[inline] pub fn not(a: Reg) -> Reg { unsafe { _mm_xor_ps(a, _mm_castsi128_ps(_mm_set1_epi32(-1))) } }
+   // m ? a : b in each lane
+   #[inline] pub fn select(m: Reg, a: Reg, b: Reg) -> Reg { unsafe { _mm_or_ps(_mm_and_ps(m, a), _mm_andnot_ps(m, b)) } }
+   // a bit for each lane of a mask, the first lane lowest
+   #[inline] pub fn bits(m: Reg) -> u32 { unsafe { _mm_movemask_ps(m) as u32 } }
+}
+
+#[cfg(all(target_arch = "x86_64", not(target_feature = "avx"), feature = "f64"))]
+mod imp {
+   use std::arch::x86_64::*;
+
+   pub type Reg = __m128d;
+   pub const WIDTH: usize = 2;
+
+   #[inline] pub fn splat(x: f64) -> Reg { unsafe { _mm_set1_pd(x) } }
+   #[inline] pub fn load(p: &[f64; WIDTH]) -> Reg { unsafe { _mm_loadu_pd(p.as_ptr()) } }
+   #[inline] pub fn add(a: Reg, b: Reg) -> Reg { unsafe { _mm_add_pd(a, b) } }
+   #[inline] pub fn sub(a: Reg, b: Reg) -> Reg { unsafe { _mm_sub_pd(a, b) } }
+   #[inline] pub fn mul(a: Reg, b: Reg) -> Reg { unsafe { _mm_mul_pd(a, b) } }
+   #[inline] pub fn div(a: Reg, b: Reg) -> Reg { unsafe { _mm_div_pd(a, b) } }
+   #[inline] pub fn min(a: Reg, b: Reg) -> Reg { unsafe { _mm_min_pd(a, b) } }
+   #[inline] pub fn max(a: Reg, b: Reg) -> Reg { unsafe { _mm_max_pd(a, b) } }
+   #[inline] pub fn sqrt(a: Reg) -> Reg { unsafe { _mm_sqrt_pd(a) } }
+   #[inline] pub fn lt(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmplt_pd(a, b) } }
+   #[inline] pub fn le(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmple_pd(a, b) } }
+   #[inline] pub fn gt(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmpgt_pd(a, b) } }
+   #[inline] pub fn ge(a: Reg, b: Reg) -> Reg { unsafe { _mm_cmpge_pd(a, b) } }
+   #[inline] pub fn and(a: Reg, b: Reg) -> Reg { unsafe { _mm_and_pd(a, b) } }
+   #[inline] pub fn or(a: Reg, b: Reg) -> Reg { unsafe { _mm_or_pd(a, b) } }
+   #[inline] pub fn not(a: Reg) -> Reg { unsafe { _mm_xor_pd(a, _mm_castsi128_pd(_mm_set1_epi32(-1))) } }
+   #[inline] pub fn select(m: Reg, a: Reg, b: Reg) -> Reg { unsafe { _mm_or_pd(_mm_and_pd(m, a), _mm_andnot_pd(m, b)) } }
+   #[inline] pub fn bits(m: Reg) -> u32 { unsafe { _mm_movemask_pd(m) as u32 } }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "f64"), target_feature = "avx", feature = "packet8"))]
+mod imp {
+   use std::arch::x86_64::*;
+
+   pub type Reg = __m256;
+   pub const WIDTH: usize = 8;
+
+   #[inline] pub fn splat(x: f32) -> Reg { unsafe { _mm256_set1_ps(x) } }
+   #[inline] pub fn load(p: &[f32; WIDTH]) -> Reg { unsafe { _mm256_loadu_ps(p.as_ptr()) } }
+   #[inline] pub fn add(a: Reg, b: Reg) -> Reg { unsafe { _mm256_add_ps(a, b) } }
+   #[inline] pub fn sub(a: Reg, b: Reg) -> Reg { unsafe { _mm256_sub_ps(a, b) } }
+   #[inline] pub fn mul(a: Reg, b: Reg) -> Reg { unsafe { _mm256_mul_ps(a, b) } }
+   #[inline] pub fn div(a: Reg, b: Reg) -> Reg { unsafe { _mm256_div_ps(a, b) } }
+   #[inline] pub fn min(a: Reg, b: Reg) -> Reg { unsafe { _mm256_min_ps(a, b) } }
+   #[inline] pub fn max(a: Reg, b: Reg) -> Reg { unsafe { _mm256_max_ps(a, b) } }
+   #[inline] pub fn sqrt(a: Reg) -> Reg { unsafe { _mm256_sqrt_ps(a) } }
+   #[inline] pub fn lt(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_ps::<_CMP_LT_OQ>(a, b) } }
+   #[inline] pub fn le(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_ps::<_CMP_LE_OQ>(a, b) } }
+   #[inline] pub fn gt(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_ps::<_CMP_GT_OQ>(a, b) } }
+   #[inline] pub fn ge(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_ps::<_CMP_GE_OQ>(a, b) } }
+   #[inline] pub fn and(a: Reg, b: Reg) -> Reg { unsafe { _mm256_and_ps(a, b) } }
+   #[inline] pub fn or(a: Reg, b: Reg) -> Reg { unsafe { _mm256_or_ps(a, b) } }
+   #[inline] pub fn not(a: Reg) -> Reg { unsafe { _mm256_xor_ps(a, _mm256_castsi256_ps(_mm256_set1_epi32(-1))) } }
+   #[inline] pub fn select(m: Reg, a: Reg, b: Reg) -> Reg { unsafe { _mm256_blendv_ps(b, a, m) } }
+   #[inline] pub fn bits(m: Reg) -> u32 { unsafe { _mm256_movemask_ps(m) as u32 } }
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "avx", feature = "f64"))]
+mod imp {
+   use std::arch::x86_64::*;
+
+   pub type Reg = __m256d;
+   pub const WIDTH: usize = 4;
+
+   #[inline] pub fn splat(x: f64) -> Reg { unsafe { _mm256_set1_pd(x) } }
+   #[inline] pub fn load(p: &[f64; WIDTH]) -> Reg { unsafe { _mm256_loadu_pd(p.as_ptr()) } }
+   #[inline] pub fn add(a: Reg, b: Reg) -> Reg { unsafe { _mm256_add_pd(a, b) } }
+   #[inline] pub fn sub(a: Reg, b: Reg) -> Reg { unsafe { _mm256_sub_pd(a, b) } }
+   #[inline] pub fn mul(a: Reg, b: Reg) -> Reg { unsafe { _mm256_mul_pd(a, b) } }
+   #[inline] pub fn div(a: Reg, b: Reg) -> Reg { unsafe { _mm256_div_pd(a, b) } }
+   #[inline] pub fn min(a: Reg, b: Reg) -> Reg { unsafe { _mm256_min_pd(a, b) } }
+   #[inline] pub fn max(a: Reg, b: Reg) -> Reg { unsafe { _mm256_max_pd(a, b) } }
+   #[inline] pub fn sqrt(a: Reg) -> Reg { unsafe { _mm256_sqrt_pd(a) } }
+   #[inline] pub fn lt(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_pd::<_CMP_LT_OQ>(a, b) } }
+   #[inline] pub fn le(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_pd::<_CMP_LE_OQ>(a, b) } }
+   #[inline] pub fn gt(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_pd::<_CMP_GT_OQ>(a, b) } }
+   #[inline] pub fn ge(a: Reg, b: Reg) -> Reg { unsafe { _mm256_cmp_pd::<_CMP_GE_OQ>(a, b) } }
+   #[inline] pub fn and(a: Reg, b: Reg) -> Reg { unsafe { _mm256_and_pd(a, b) } }
+   #[inline] pub fn or(a: Reg, b: Reg) -> Reg { unsafe { _mm256_or_pd(a, b) } }
+   #[inline] pub fn not(a: Reg) -> Reg { unsafe { _mm256_xor_pd(a, _mm256_castsi256_pd(_mm256_set1_epi32(-1))) } }
+   #[inline] pub fn select(m: Reg, a: Reg, b: Reg) -> Reg { unsafe { _mm256_blendv_pd(b, a, m) } }
+   #[inline] pub fn bits(m: Reg) -> u32 { unsafe { _mm256_movemask_pd(m) as u32 } }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+   use vector::Float;
+
+   pub type Reg = Float;
+   pub const WIDTH: usize = 1;
+
+   // a mask lane is a float with every bit set or none
+   fn from_bool(b: bool) -> Reg { Float::from_bits(if b { !0 } else { 0 }) }
+
+   #[inline] pub fn splat(x: Float) -> Reg { x }
+   #[inline] pub fn load(p: &[Float; WIDTH]) -> Reg { p[0] }
+   #[inline] pub fn add(a: Reg, b: Reg) -> Reg { a + b }
+   #[inline] pub fn sub(a: Reg, b: Reg) -> Reg { a - b }
+   #[inline] pub fn mul(a: Reg, b: Reg) -> Reg { a * b }
+   #[inline] pub fn div(a: Reg, b: Reg) -> Reg { a / b }
+   #[inline] pub fn min(a: Reg, b: Reg) -> Reg { if a < b { a } else { b } }
+   #[inline] pub fn max(a: Reg, b: Reg) -> Reg { if a > b { a } else { b } }
+   #[inline] pub fn sqrt(a: Reg) -> Reg { a.sqrt() }
+   #[inline] pub fn lt(a: Reg, b: Reg) -> Reg { from_bool(a < b) }
+   #[inline] pub fn le(a: Reg, b: Reg) -> Reg { from_bool(a <= b) }
+   #[inline] pub fn gt(a: Reg, b: Reg) -> Reg { from_bool(a > b) }
+   #[inline] pub fn ge(a: Reg, b: Reg) -> Reg { from_bool(a >= b) }
+   #[inline] pub fn and(a: Reg, b: Reg) -> Reg { Float::from_bits(a.to_bits() & b.to_bits()) }
+   #[inline] pub fn or(a: Reg, b: Reg) -> Reg { Float::from_bits(a.to_bits() | b.to_bits()) }
+   #[inline] pub fn not(a: Reg) -> Reg { Float::from_bits(!a.to_bits()) }
+   #[inline] pub fn select(m: Reg, a: Reg, b: Reg) -> Reg { if m.to_bits() != 0 { a } else { b } }
+   #[inline] pub fn bits(m: Reg) -> u32 { if m.to_bits() != 0 { 1 } else { 0 } }
+}
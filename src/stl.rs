@@ -0,0 +1,151 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// STL reader for ascii and binary files. STL stores every triangle with
+// its own copy of the vertices, so identical positions are merged to give
+// an indexed mesh. Facet normals are ignored in favour of the winding.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::io::{Cursor, Read};
+use vector::{Vector, Float};
+use color::Color;
+use mesh::TriangleMesh;
+use byteorder::{LittleEndian, ReadBytesExt};
+
+pub fn load(path: &Path, color: Color) -> Result<TriangleMesh, String> {
+   let mut fin = File::open(path).map_err(|e| format!("{} - {}", path.display(), e))?;
+   let mut data = Vec::new();
+   fin.read_to_end(&mut data).map_err(|e| format!("{} - {}", path.display(), e))?;
+
+   // Binary files may also start with "solid", so trust the size first.
+   let binary = data.len() >= 84 && {
+      let n = Cursor::new(&data[80 .. 84]).read_u32::<LittleEndian>().unwrap() as usize;
+      data.len() == 84 + n * 50
+   };
+
+   let triangles = if binary {
+      read_binary(&data)?
+   } else if data.starts_with(b"solid") {
+      read_ascii(&String::from_utf8_lossy(&data))?
+   } else {
+      return Err("Not an STL file.".to_string());
+   };
+
+   let mut mesh = TriangleMesh::new(color);
+   let mut index = HashMap::new();
+   for tri in triangles {
+      let mut f = [0; 3];
+      for (i, v) in tri.iter().enumerate() {
+         let key = (v.x.to_bits(), v.y.to_bits(), v.z.to_bits());
+         let positions = &mut mesh.positions;
+         f[i] = *index.entry(key).or_insert_with(|| {
+            positions.push(*v);
+            (positions.len() - 1) as u32
+         });
+      }
+      mesh.push_face(f, None, None, 0);
+   }
+
+   Ok(mesh)
+}
+
+fn read_binary(data: &[u8]) -> Result<Vec<[Vector; 3]>, String> {
+   let mut c = Cursor::new(&data[80 ..]);
+   let n = c.read_u32::<LittleEndian>().map_err(|e| e.to_string())?;
+   let mut triangles = Vec::with_capacity(n as usize);
+
+   for _ in 0 .. n {
+      let mut v = [0.0; 12];
+      for x in v.iter_mut() {
+         *x = c.read_f32::<LittleEndian>().map_err(|e| e.to_string())? as Float;
+      }
+      // attribute byte count, unused
+      c.read_u16::<LittleEndian>().map_err(|e| e.to_string())?;
+
+      triangles.push([Vector::new(v[3], v[4], v[5]),
+                      Vector::new(v[6], v[7], v[8]),
+                      Vector::new(v[9], v[10], v[11])]);
+   }
+
+   Ok(triangles)
+}
+
+fn read_ascii(data: &str) -> Result<Vec<[Vector; 3]>, String> {
+   let mut triangles = Vec::new();
+   let mut vertices = Vec::new();
+
+   for line in data.lines() {
+      let t: Vec<&str> = line.split_whitespace().collect();
+      match t.first().copied() {
+         Some("vertex") if t.len() >= 4 => {
+            let mut v = [0.0; 3];
+            for i in 0 .. 3 {
+               v[i] = t[i + 1].parse::<Float>().map_err(|_| format!("Invalid number {}.", t[i + 1]))?;
+            }
+            vertices.push(Vector::new(v[0], v[1], v[2]));
+         }
+         Some("endloop") => {
+            // polygons with more than three vertices are split as fans
+            for i in 1 .. vertices.len().saturating_sub(1) {
+               triangles.push([vertices[0], vertices[i], vertices[i + 1]]);
+            }
+            vertices.clear();
+         }
+         _ => {},
+      }
+   }
+
+   Ok(triangles)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::load;
+   use color::Color;
+   use byteorder::{LittleEndian, WriteBytesExt};
+   use testfile::write;
+
+   #[test]
+   fn reads_ascii_and_merges_vertices() {
+      let path = write("ascii_test.stl", b"solid quad\n\
+                        facet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 1 1 0\nendloop\nendfacet\n\
+                        facet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 1 0\nvertex 0 1 0\nendloop\nendfacet\n\
+                        endsolid quad\n");
+      let mesh = load(&path, Color::new(1.0, 1.0, 1.0)).unwrap();
+      assert_eq!(mesh.positions.len(), 4);
+      assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+   }
+
+   #[test]
+   fn reads_binary_starting_with_solid() {
+      let mut data = b"solid but binary".to_vec();
+      data.resize(80, 0);
+      data.write_u32::<LittleEndian>(1).unwrap();
+      for v in [0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 3.0, 0.0f32].iter() {
+         data.write_f32::<LittleEndian>(*v).unwrap();
+      }
+      data.write_u16::<LittleEndian>(0).unwrap();
+      let mesh = load(&write("binary_test.stl", &data), Color::new(1.0, 1.0, 1.0)).unwrap();
+      assert_eq!(mesh.indices, vec![[0, 1, 2]]);
+      assert_eq!(mesh.positions[1].x, 3.0);
+   }
+
+   #[test]
+   fn rejects_other_files() {
+      assert!(load(&write("not.stl", b"ply\n"), Color::new(1.0, 1.0, 1.0)).is_err());
+   }
+}
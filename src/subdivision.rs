@@ -0,0 +1,361 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Catmull-Clark subdivision of polygon meshes. Each step replaces every
+// n sided face with n quads, so after the first step the mesh is all quads.
+// Boundary edges and vertices use the usual cubic B-spline curve rules.
+// Texture coordinates are face varying and interpolated linearly within each
+// face rather than smoothed, so seams stay where the artist put them, and
+// every new face keeps the material of the face it was split from.
+
+use std::collections::HashMap;
+use std::path::Path;
+use vector::{Vector, cross, Float};
+use color::Color;
+use mesh::{TriangleMesh, Group, read_file, parse_floats, parse_corner, load_mtllib, use_material};
+
+pub struct PolyMesh {
+   pub positions: Vec<Vector>,
+   pub faces: Vec<Vec<u32>>,
+   pub uvs: Vec<(Float, Float)>,
+   // uv indices of each face's corners, empty for faces without uvs
+   pub face_uvs: Vec<Vec<u32>>,
+   pub groups: Vec<Group>,
+   pub face_groups: Vec<u16>,
+}
+
+impl PolyMesh {
+   // Faces of an OBJ file with their uvs and materials, not triangulated as
+   // the subdivision rules depend on their shape. Normals are only range
+   // checked since subdivision replaces them.
+   pub fn load_obj(path: &Path, color: Color) -> Result<PolyMesh, String> {
+      let data = read_file(path)?;
+      let dir = path.parent().unwrap_or(Path::new(""));
+      let mut mesh = PolyMesh {
+         positions: Vec::new(),
+         faces: Vec::new(),
+         uvs: Vec::new(),
+         face_uvs: Vec::new(),
+         groups: vec![Group { name: String::new(), color }],
+         face_groups: Vec::new(),
+      };
+      let mut materials = HashMap::new();
+      let mut group = 0;
+      let mut nn = 0;
+
+      for (lineno, line) in data.lines().enumerate() {
+         let mut tokens = line.split_whitespace();
+         let err = |e: &str| format!("{}:{}: {}", path.display(), lineno + 1, e);
+
+         match tokens.next() {
+            Some("v") => {
+               let v = parse_floats(tokens, 3).map_err(|e| err(&e))?;
+               mesh.positions.push(Vector::new(v[0], v[1], v[2]));
+            }
+            Some("vt") => {
+               let v = parse_floats(tokens, 2).map_err(|e| err(&e))?;
+               mesh.uvs.push((v[0], v[1]));
+            }
+            Some("vn") => nn += 1,
+            Some("f") => {
+               let mut face = Vec::new();
+               let mut uvs = Vec::new();
+               for t in tokens {
+                  let c = parse_corner(t, mesh.positions.len(), mesh.uvs.len(), nn).map_err(|e| err(&e))?;
+                  face.push(c.0);
+                  uvs.push(c.1);
+               }
+               if face.len() < 3 {
+                  return Err(err("Face with fewer than 3 vertices."));
+               }
+               mesh.faces.push(face);
+               mesh.face_uvs.push(uvs.into_iter().collect::<Option<Vec<u32>>>().unwrap_or(Vec::new()));
+               mesh.face_groups.push(group);
+            }
+            Some("mtllib") => materials.extend(load_mtllib(dir, tokens)),
+            Some("usemtl") => {
+               let name = tokens.collect::<Vec<&str>>().join(" ");
+               group = use_material(&mut mesh.groups, &materials, name, color).map_err(|e| err(&e))?;
+            }
+            _ => {},
+         }
+      }
+
+      Ok(mesh)
+   }
+
+   pub fn subdivide(&self) -> PolyMesh {
+      let nv = self.positions.len();
+
+      let face_points: Vec<Vector> = self.faces.iter().map(|f| {
+         f.iter().fold(Vector::zero(), |s, &i| s + self.positions[i as usize]) * (1.0 / f.len() as Float)
+      }).collect();
+
+      // edges by their sorted end points, with the faces either side
+      let mut edge_index = HashMap::new();
+      let mut edges: Vec<(u32, u32, Vec<usize>)> = Vec::new();
+      for (fi, f) in self.faces.iter().enumerate() {
+         for k in 0 .. f.len() {
+            let (a, b) = (f[k], f[(k + 1) % f.len()]);
+            let key = (a.min(b), a.max(b));
+            let e = *edge_index.entry(key).or_insert_with(|| {
+               edges.push((key.0, key.1, Vec::new()));
+               edges.len() - 1
+            });
+            edges[e].2.push(fi);
+         }
+      }
+
+      let edge_points: Vec<Vector> = edges.iter().map(|&(a, b, ref faces)| {
+         let mid = self.positions[a as usize] + self.positions[b as usize];
+         if faces.len() == 2 {
+            (mid + face_points[faces[0]] + face_points[faces[1]]) * 0.25
+         } else {
+            mid * 0.5
+         }
+      }).collect();
+
+      // per vertex sums of adjacent face points and edge midpoints, and
+      // the neighbours along boundary edges
+      let mut face_sum = vec![Vector::zero(); nv];
+      let mut face_count = vec![0; nv];
+      for (fi, f) in self.faces.iter().enumerate() {
+         for &i in f.iter() {
+            face_sum[i as usize] = face_sum[i as usize] + face_points[fi];
+            face_count[i as usize] += 1;
+         }
+      }
+      let mut edge_sum = vec![Vector::zero(); nv];
+      let mut edge_count = vec![0; nv];
+      let mut boundary: Vec<Vec<u32>> = vec![Vec::new(); nv];
+      for &(a, b, ref faces) in edges.iter() {
+         let mid = (self.positions[a as usize] + self.positions[b as usize]) * 0.5;
+         for &(v, other) in [(a, b), (b, a)].iter() {
+            edge_sum[v as usize] = edge_sum[v as usize] + mid;
+            edge_count[v as usize] += 1;
+            if faces.len() != 2 {
+               boundary[v as usize].push(other);
+            }
+         }
+      }
+
+      let mut positions: Vec<Vector> = (0 .. nv).map(|i| {
+         let p = self.positions[i];
+         if boundary[i].len() == 2 {
+            let (a, b) = (self.positions[boundary[i][0] as usize], self.positions[boundary[i][1] as usize]);
+            (a + b) * 0.125 + p * 0.75
+         } else if !boundary[i].is_empty() || face_count[i] == 0 {
+            // corners and non-manifold vertices stay put
+            p
+         } else {
+            let n = face_count[i] as Float;
+            let f = face_sum[i] * (1.0 / n);
+            let r = edge_sum[i] * (1.0 / edge_count[i] as Float);
+            (f + r * 2.0 + p * (n - 3.0)) * (1.0 / n)
+         }
+      }).collect();
+
+      let face_base = positions.len();
+      positions.extend(face_points);
+      let edge_base = positions.len();
+      positions.extend(edge_points);
+
+      // the corner uvs are kept, with the face centres and edge midpoints
+      // added after them, midpoints shared by faces using the same pair
+      let mut uvs = self.uvs.clone();
+      let mut uv_midpoints = HashMap::new();
+
+      let mut faces = Vec::new();
+      let mut face_uvs = Vec::new();
+      let mut face_groups = Vec::new();
+      for (fi, f) in self.faces.iter().enumerate() {
+         let edge = |a: u32, b: u32| (edge_base + edge_index[&(a.min(b), a.max(b))]) as u32;
+         let fuv = &self.face_uvs[fi];
+         let centre = if fuv.is_empty() {
+            None
+         } else {
+            let s = fuv.iter().fold((0.0, 0.0), |s, &i| (s.0 + self.uvs[i as usize].0, s.1 + self.uvs[i as usize].1));
+            uvs.push((s.0 / fuv.len() as Float, s.1 / fuv.len() as Float));
+            Some((uvs.len() - 1) as u32)
+         };
+
+         for k in 0 .. f.len() {
+            let (prev, next) = ((k + f.len() - 1) % f.len(), (k + 1) % f.len());
+            faces.push(vec![f[k], edge(f[k], f[next]), (face_base + fi) as u32, edge(f[prev], f[k])]);
+            face_groups.push(self.face_groups[fi]);
+
+            face_uvs.push(match centre {
+               Some(c) => {
+                  let mut midpoint = |a: u32, b: u32| *uv_midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                     let (ua, ub) = (self.uvs[a as usize], self.uvs[b as usize]);
+                     uvs.push(((ua.0 + ub.0) * 0.5, (ua.1 + ub.1) * 0.5));
+                     (uvs.len() - 1) as u32
+                  });
+                  let (after, before) = (midpoint(fuv[k], fuv[next]), midpoint(fuv[prev], fuv[k]));
+                  vec![fuv[k], after, c, before]
+               }
+               None => Vec::new(),
+            });
+         }
+      }
+
+      PolyMesh {
+         positions,
+         faces,
+         uvs,
+         face_uvs,
+         groups: self.groups.clone(),
+         face_groups,
+      }
+   }
+
+   // Triangulates the faces as fans, with vertex normals averaged from the
+   // faces around each vertex so the result shades smoothly.
+   pub fn to_triangles(&self) -> TriangleMesh {
+      let mut mesh = TriangleMesh::new(self.groups[0].color);
+      mesh.groups = self.groups.clone();
+      mesh.positions = self.positions.clone();
+      mesh.normals = vec![Vector::zero(); self.positions.len()];
+
+      for (fi, f) in self.faces.iter().enumerate() {
+         let fuv = &self.face_uvs[fi];
+         for k in 1 .. f.len() - 1 {
+            let v = [f[0], f[k], f[k + 1]];
+            let (p0, p1, p2) = (self.positions[v[0] as usize], self.positions[v[1] as usize], self.positions[v[2] as usize]);
+            // area weighted, as the cross product isn't normalised
+            let n = cross(p1 - p0, p2 - p0);
+            for &i in v.iter() {
+               mesh.normals[i as usize] = mesh.normals[i as usize] + n;
+            }
+            let uv = if fuv.is_empty() { None } else { Some([fuv[0], fuv[k], fuv[k + 1]]) };
+            mesh.push_face(v, None, uv, self.face_groups[fi]);
+         }
+      }
+
+      // uvs that no face refers to must not be read as per vertex ones
+      if !mesh.uv_indices.is_empty() {
+         mesh.uvs = self.uvs.clone();
+      }
+      for n in mesh.normals.iter_mut() {
+         if n.magnitude_sq() > 0.0 {
+            *n = n.normalize();
+         }
+      }
+      mesh
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::PolyMesh;
+   use color::Color;
+   use mesh::Group;
+   use vector::{Vector, Float};
+   use testfile::write;
+
+   fn load(name: &str, obj: &str) -> Result<PolyMesh, String> {
+      PolyMesh::load_obj(&write(name, obj.as_bytes()), Color::new(1.0, 1.0, 1.0))
+   }
+
+   fn near(a: Vector, b: Vector) -> bool {
+      (a - b).magnitude() < 1e-6
+   }
+
+   fn unit_cube() -> PolyMesh {
+      let positions = (0 .. 8).map(|i| Vector::new((i & 1) as Float, (i >> 1 & 1) as Float, (i >> 2) as Float)).collect();
+      let faces = vec![vec![0, 2, 3, 1], vec![4, 5, 7, 6], vec![0, 1, 5, 4],
+                       vec![2, 6, 7, 3], vec![0, 4, 6, 2], vec![1, 3, 7, 5]];
+      PolyMesh {
+         positions,
+         face_uvs: vec![Vec::new(); 6],
+         face_groups: vec![0; 6],
+         faces,
+         uvs: Vec::new(),
+         groups: vec![Group { name: String::new(), color: Color::new(1.0, 1.0, 1.0) }],
+      }
+   }
+
+   #[test]
+   fn loads_faces_with_texture_and_normal_indices() {
+      let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+                 vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n\
+                 f 1/1/1 2/2/1 3/3/1 4/4/1\nf 1//1 3//1 4//-1\nf 1/1 2/2 3/-1\n";
+      let mesh = load("vt_vn.obj", obj).unwrap();
+      assert_eq!(mesh.faces, vec![vec![0, 1, 2, 3], vec![0, 2, 3], vec![0, 1, 2]]);
+      assert_eq!(mesh.face_uvs, vec![vec![0, 1, 2, 3], vec![], vec![0, 1, 3]]);
+
+      // a quad becomes 4 quads around its face point
+      let step = mesh.subdivide();
+      assert_eq!(step.faces.len(), 10);
+      assert!(step.faces.iter().all(|f| f.len() == 4));
+   }
+
+   #[test]
+   fn rejects_texture_and_normal_indices_out_of_range() {
+      let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nvt 0 0\nvn 0 0 1\n";
+      assert!(load("bad_vt.obj", &format!("{}f 1/2 2/1 3/1\n", obj)).is_err());
+      assert!(load("bad_vn.obj", &format!("{}f 1//1 2//2 3//1\n", obj)).is_err());
+   }
+
+   #[test]
+   fn cube_positions_after_one_step() {
+      let step = unit_cube().subdivide();
+      assert_eq!(step.positions.len(), 8 + 6 + 12);
+      assert_eq!(step.faces.len(), 24);
+
+      // a valence 3 corner moves to (F + 2R)/3 of the face and edge midpoint
+      // averages, 2/9 of the way along the diagonal
+      let (a, b) = (2.0 / 9.0, 7.0 / 9.0);
+      assert!(near(step.positions[0], Vector::new(a, a, a)));
+      assert!(near(step.positions[7], Vector::new(b, b, b)));
+      assert!(near(step.positions[5], Vector::new(b, a, b)));
+      // face points are the face centres
+      assert!(near(step.positions[8], Vector::new(0.5, 0.5, 0.0)));
+      assert!(near(step.positions[13], Vector::new(1.0, 0.5, 0.5)));
+      // an edge point averages its end points and the two face points
+      let edge = step.positions[14 ..].iter().any(|&p| near(p, Vector::new(0.5, 0.125, 0.125)));
+      assert!(edge);
+      // and every new vertex is inside the cube, symmetric about its centre
+      let centre = step.positions.iter().fold(Vector::zero(), |s, &p| s + p) * (1.0 / 26.0);
+      assert!(near(centre, Vector::new(0.5, 0.5, 0.5)));
+   }
+
+   #[test]
+   fn uvs_and_materials_follow_the_faces() {
+      let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 2 0 0\n\
+                 vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+                 f 1/1 2/2 3/3 4/4\nusemtl red\nf 2 5 3\n";
+      let mesh = load("uv_mtl.obj", obj).unwrap();
+      assert_eq!(mesh.groups.len(), 2);
+      assert_eq!(mesh.face_groups, vec![0, 1]);
+
+      let step = mesh.subdivide();
+      assert_eq!(step.face_groups, vec![0, 0, 0, 0, 1, 1, 1]);
+      // the first quad's corner keeps its uv, the rest are linear in the face
+      let uv: Vec<(Float, Float)> = step.face_uvs[0].iter().map(|&i| step.uvs[i as usize]).collect();
+      assert_eq!(uv, vec![(0.0, 0.0), (0.5, 0.0), (0.5, 0.5), (0.0, 0.5)]);
+      let uv: Vec<(Float, Float)> = step.face_uvs[2].iter().map(|&i| step.uvs[i as usize]).collect();
+      assert_eq!(uv, vec![(1.0, 1.0), (0.5, 1.0), (0.5, 0.5), (1.0, 0.5)]);
+      // midpoints are shared between the children of a face
+      assert_eq!(step.face_uvs[0][1], step.face_uvs[1][3]);
+      assert!(step.face_uvs[4 ..].iter().all(|f| f.is_empty()));
+
+      let tris = step.to_triangles();
+      assert_eq!(tris.len(), 14);
+      assert_eq!(tris.uvs(0), Some([(0.0, 0.0), (0.5, 0.0), (0.5, 0.5)]));
+      assert_eq!(tris.uvs(8), None);
+      assert_eq!(tris.face_groups[8], 1);
+   }
+}
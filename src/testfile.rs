@@ -0,0 +1,70 @@
+/*
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Files the loader tests read back. Names are made unique to the process
+// and the call, so tests running at the same time don't share them, and the
+// file is removed when the returned value goes out of scope.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestFile {
+   path: PathBuf,
+}
+
+// Writes data to a new file ending in name, which keeps its extension.
+pub fn write(name: &str, data: &[u8]) -> TestFile {
+   let n = COUNT.fetch_add(1, Ordering::SeqCst);
+   let path = env::temp_dir().join(format!("rrt_{}_{}_{}", process::id(), n, name));
+   File::create(&path).unwrap().write_all(data).unwrap();
+   TestFile { path }
+}
+
+impl Deref for TestFile {
+   type Target = Path;
+
+   fn deref(&self) -> &Path {
+      &self.path
+   }
+}
+
+impl Drop for TestFile {
+   fn drop(&mut self) {
+      let _ = fs::remove_file(&self.path);
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::write;
+   use std::fs;
+
+   #[test]
+   fn removed_when_dropped() {
+      let (a, b) = (write("same.txt", b"a"), write("same.txt", b"b"));
+      assert!(a.to_str().unwrap().ends_with("same.txt") && *a != *b);
+      assert_eq!(fs::read(&*b).unwrap(), b"b");
+      let path = a.to_path_buf();
+      drop(a);
+      assert!(!path.exists());
+   }
+}
@@ -15,18 +15,32 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::ops::{Add, Sub, Neg, Mul};
 
+// Scalar used throughout the renderer. Building with the f64 feature
+// switches to double precision, for scenes so large that single precision
+// can't place things accurately; float is the matching std module, for
+// its constants.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+#[cfg(not(feature = "f64"))]
+pub use std::f32 as float;
+#[cfg(feature = "f64")]
+pub use std::f64 as float;
+
 #[derive(Debug,Copy,Clone)]
 pub struct Vector {
-   pub x: f32,
-   pub y: f32,
-   pub z: f32,
+   pub x: Float,
+   pub y: Float,
+   pub z: Float,
 }
 
 impl Vector {
 
    // Constructors
-   pub fn new(x: f32, y: f32, z: f32) -> Vector {
-      Vector {x: x, y: y, z: z }
+   pub fn new(x: Float, y: Float, z: Float) -> Vector {
+      Vector {x, y, z }
    }
 
    pub fn zero() -> Vector {
@@ -34,11 +48,11 @@ impl Vector {
    }
 
    // Magnitude related
-   pub fn magnitude_sq(self) -> f32 {
+   pub fn magnitude_sq(self) -> Float {
       dot(self, self)
    }
 
-   pub fn magnitude(self) -> f32 {
+   pub fn magnitude(self) -> Float {
       self.magnitude_sq().sqrt()
    }
 
@@ -50,11 +64,15 @@ impl Vector {
          z: self.z * inv_magnitude,
       }
    }
+
+   pub fn abs(self) -> Vector {
+      Vector::new(self.x.abs(), self.y.abs(), self.z.abs())
+   }
 }
 
 // Dot and cross products.
 
-pub fn dot(a: Vector, b: Vector) -> f32 {
+pub fn dot(a: Vector, b: Vector) -> Float {
    a.x * b.x + a.y * b.y + a.z * b.z
 }
 
@@ -66,12 +84,41 @@ pub fn cross(a: Vector, b: Vector) -> Vector {
    }
 }
 
+// Two unit vectors perpendicular to the unit vector d and each other.
+pub fn basis(d: Vector) -> (Vector, Vector) {
+   let a = if d.x.abs() > 0.9 { Vector::new(0.0, 1.0, 0.0) } else { Vector::new(1.0, 0.0, 0.0) };
+   let x = cross(a, d).normalize();
+   (x, cross(d, x))
+}
+
+// Rounding error. gamma(n) bounds the relative error of n floating point
+// operations in a row, and the next floats either side of v are used to
+// round a result away from a surface.
+
+pub fn gamma(n: i32) -> Float {
+   let e = Float::EPSILON * 0.5 * n as Float;
+   e / (1.0 - e)
+}
+
+pub fn next_float_up(v: Float) -> Float {
+   if v.is_infinite() && v > 0.0 {
+      return v;
+   }
+   let v = if v == -0.0 { 0.0 } else { v };
+   let bits = v.to_bits();
+   Float::from_bits(if v >= 0.0 { bits + 1 } else { bits - 1 })
+}
+
+pub fn next_float_down(v: Float) -> Float {
+   -next_float_up(-v)
+}
+
 // Operator overloads
 
-impl Mul<f32> for Vector {
+impl Mul<Float> for Vector {
     type Output = Vector;
 
-    fn mul(self, rhs: f32) -> Vector {
+    fn mul(self, rhs: Float) -> Vector {
         Vector {
             x: self.x * rhs,
             y: self.y * rhs,
@@ -116,7 +163,35 @@ impl Neg for Vector {
     }
 }
 
+#[cfg(test)]
+mod tests {
+   use super::{gamma, next_float_up, next_float_down, Float};
+
+   #[test]
+   fn next_floats() {
+      assert_eq!(next_float_up(1.0), 1.0 + Float::EPSILON);
+      assert_eq!(next_float_down(1.0), 1.0 - Float::EPSILON * 0.5);
+      assert_eq!(next_float_up(-1.0), -1.0 + Float::EPSILON * 0.5);
+      assert_eq!(next_float_down(-1.0), -1.0 - Float::EPSILON);
+
+      // both zeros step to the smallest subnormal either side
+      let tiny = Float::from_bits(1);
+      assert_eq!(next_float_up(0.0), tiny);
+      assert_eq!(next_float_up(-0.0), tiny);
+      assert_eq!(next_float_down(0.0), -tiny);
+      assert_eq!(next_float_down(-0.0), -tiny);
+
+      assert_eq!(next_float_up(Float::INFINITY), Float::INFINITY);
+      assert_eq!(next_float_down(Float::NEG_INFINITY), Float::NEG_INFINITY);
+      assert_eq!(next_float_up(Float::MAX), Float::INFINITY);
+      assert_eq!(next_float_up(Float::NEG_INFINITY), -Float::MAX);
+   }
 
-
-
-
+   #[test]
+   fn gamma_bounds_rounding() {
+      let u = Float::EPSILON * 0.5;
+      assert_eq!(gamma(0), 0.0);
+      assert!(gamma(1) > u && gamma(1) < u * 1.001);
+      assert!(gamma(5) > 5.0 * u && gamma(5) < gamma(6));
+   }
+}